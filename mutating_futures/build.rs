@@ -0,0 +1,17 @@
+use std::process::Command;
+
+/// Records the compiler version, which plugin libraries must have been built with.
+fn main() {
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let version = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .unwrap_or_default();
+
+    println!(
+        "cargo:rustc-env=MUTATING_FUTURES_RUSTC_VERSION={}",
+        version.trim()
+    );
+}
@@ -0,0 +1,497 @@
+use crate::wrapped::{AsyncResult, AsyncWorker, Compensation, WorkIO};
+use failure::Error;
+use futures::future::Either;
+use futures::sync::oneshot;
+use futures::{Future, Sink, Stream};
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use tokio::runtime::TaskExecutor;
+use tokio::sync::mpsc;
+
+#[derive(Debug, Fail)]
+pub enum ActorError {
+    #[fail(display = "actor is stopped")]
+    Stopped,
+    #[fail(display = "actor panicked: {}", _0)]
+    Panicked(String),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ActorStats {
+    /// Messages sent, or waiting for room in the mailbox, which the actor hasn't
+    /// taken yet
+    pub mailbox_depth: usize,
+    pub peak_mailbox_depth: usize,
+    pub handled: u64,
+    pub panics: u64,
+    /// Workers replaced after a panic, see `ActorBuilder::with_max_restarts`
+    pub restarts: u64,
+    /// Workers replaced through `ActorWorker::restart`
+    pub manual_restarts: u64,
+}
+
+type Job<T> = Box<dyn FnOnce(&mut dyn AsyncWorker<T>) -> AsyncResult<()> + Send>;
+type WorkerFactory<T> = Box<dyn Fn() -> Box<dyn AsyncWorker<T>> + Send + Sync>;
+
+/// Message in a mailbox, which leaves the mailbox depth once it's taken or dropped
+struct Envelope<T> {
+    job: Option<Job<T>>,
+    actor: Weak<Actor<T>>,
+}
+
+impl<T> Drop for Envelope<T> {
+    fn drop(&mut self) {
+        if let Some(actor) = self.actor.upgrade() {
+            actor.state().stats.mailbox_depth -= 1;
+        }
+    }
+}
+
+struct ActorState<T> {
+    /// None once the actor is stopped
+    mailbox: Option<mpsc::Sender<Envelope<T>>>,
+    /// Counts the workers started, so a task can tell whether its mailbox was replaced
+    generation: u64,
+    /// Config of the last init, which is replayed on every replacement worker
+    config: Option<serde_json::Value>,
+    /// Whether the worker was warmed up, so every replacement worker is, too
+    warmed_up: bool,
+    stats: ActorStats,
+}
+
+struct Actor<T> {
+    state: Mutex<ActorState<T>>,
+    factory: WorkerFactory<T>,
+    executor: TaskExecutor,
+    capacity: usize,
+    max_restarts: u64,
+}
+
+impl<T> Actor<T> {
+    /// Locks the state, also after a panic while it was locked, which leaves it
+    /// consistent.
+    fn state(self: &Self) -> MutexGuard<'_, ActorState<T>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<T> Actor<T>
+where
+    T: Sync + Send + Clone + 'static,
+{
+    /// Spawns a task with `worker` and an empty mailbox, which replaces the current
+    /// mailbox. The current task stops once it has handled its mailbox.
+    ///
+    /// The worker is initialized and warmed up like the one it replaces before it
+    /// takes any message. If that fails, the actor is stopped.
+    fn start(self: &Arc<Self>, state: &mut ActorState<T>, mut worker: Box<dyn AsyncWorker<T>>) {
+        let (mailbox, receiver) = mpsc::channel::<Envelope<T>>(self.capacity);
+        state.mailbox = Some(mailbox);
+        state.generation += 1;
+        let generation = state.generation;
+        let config = state.config.clone();
+        let warmed_up = state.warmed_up;
+        let actor = Arc::downgrade(self);
+
+        let prepared = futures::future::lazy(move || {
+            let init: AsyncResult<()> = match config {
+                Some(ref config) => worker.init(config),
+                None => Box::new(futures::future::ok(())),
+            };
+            init.and_then(move |_| {
+                let warmup: AsyncResult<()> = if warmed_up {
+                    worker.warmup()
+                } else {
+                    Box::new(futures::future::ok(()))
+                };
+                warmup.map(move |_| worker)
+            })
+        });
+
+        self.executor.spawn(
+            prepared
+                .map_err(move |e| {
+                    if let Some(actor) = actor.upgrade() {
+                        let mut state = actor.state();
+                        if state.generation == generation {
+                            state.mailbox = None;
+                        }
+                    }
+                    println!("[] actor could not prepare its worker: {}", e);
+                })
+                .and_then(|mut worker| {
+                    receiver
+                        .map_err(Error::from)
+                        .for_each(move |mut envelope| {
+                            let job = envelope.job.take().expect("envelopes are opened once");
+                            drop(envelope);
+                            job(&mut *worker)
+                        })
+                        .map_err(|e| println!("[] actor stopped: {}", e))
+                }),
+        );
+    }
+
+    /// Restarts the actor after its worker panicked, unless it ran out of restarts.
+    fn crashed(self: &Arc<Self>, message: &str) {
+        {
+            let mut state = self.state();
+            state.stats.panics += 1;
+
+            if state.stats.restarts >= self.max_restarts {
+                println!("[] actor panicked: {}, stopping", message);
+                state.mailbox = None;
+                return;
+            }
+            state.stats.restarts += 1;
+        }
+
+        println!("[] actor panicked: {}, restarting", message);
+        let worker = (self.factory)();
+        self.start(&mut self.state(), worker);
+    }
+}
+
+pub(crate) fn panic_message(panic: &(dyn Any + Send)) -> String {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => match panic.downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => "unknown panic".to_string(),
+        },
+    }
+}
+
+/// Handle to a worker which runs as its own task and handles one message of its
+/// bounded mailbox at a time.
+///
+/// The task owns the worker, so unlike a `Work` no lock is handed between the
+/// callers. Sends wait while the mailbox is full. Clones share the actor.
+///
+/// If the worker panics, the task is restarted with a fresh worker from the factory,
+/// up to `max_restarts` times, and otherwise stopped. Fresh workers are initialized
+/// and warmed up like the worker they replace. The messages left in the mailbox of a
+/// panicked worker fail with `ActorError::Stopped`.
+pub struct ActorWorker<T>(Arc<Actor<T>>);
+
+impl<T> Clone for ActorWorker<T> {
+    fn clone(&self) -> Self {
+        ActorWorker(self.0.clone())
+    }
+}
+
+impl<T> ActorWorker<T>
+where
+    T: Sync + Send + Clone + 'static,
+{
+    pub fn builder<F>(factory: F) -> ActorBuilder<T>
+    where
+        F: Fn() -> Box<dyn AsyncWorker<T>> + Send + Sync + 'static,
+    {
+        ActorBuilder {
+            factory: Box::new(factory),
+            capacity: 16,
+            max_restarts: 0,
+        }
+    }
+
+    pub fn stats(self: &Self) -> ActorStats {
+        self.0.state().stats
+    }
+
+    pub fn is_running(self: &Self) -> bool {
+        self.0.state().mailbox.is_some()
+    }
+
+    /// Replaces the worker with a fresh one, also if the actor was stopped. The old
+    /// worker still handles the messages already in its mailbox.
+    ///
+    /// Manual restarts don't count against the restarts after panics.
+    pub fn restart(self: &Self) {
+        let worker = (self.0.factory)();
+        let mut state = self.0.state();
+        state.stats.manual_restarts += 1;
+        self.0.start(&mut state, worker);
+    }
+
+    /// Refuses new messages. The messages already in the mailbox are still handled.
+    pub fn stop(self: &Self) {
+        self.0.state().mailbox = None;
+    }
+
+    /// Sends `input` to the worker and waits for its output.
+    pub fn send(self: &Self, input: T) -> AsyncResult<T> {
+        self.call(move |worker| worker.run(input))
+    }
+
+    /// Sends a message which calls `f` with the worker and waits for the reply.
+    fn call<F, R>(self: &Self, f: F) -> AsyncResult<R>
+    where
+        F: FnOnce(&mut dyn AsyncWorker<T>) -> AsyncResult<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let actor = self.0.clone();
+
+        Box::new(futures::future::lazy(move || {
+            let mailbox = {
+                let mut state = actor.state();
+                let mailbox = match state.mailbox {
+                    Some(ref mailbox) => mailbox.clone(),
+                    None => return Either::A(futures::future::err(ActorError::Stopped.into())),
+                };
+                state.stats.mailbox_depth += 1;
+                state.stats.peak_mailbox_depth = state
+                    .stats
+                    .peak_mailbox_depth
+                    .max(state.stats.mailbox_depth);
+                mailbox
+            };
+
+            let (reply, response) = oneshot::channel();
+            let envelope = Envelope {
+                job: Some(Self::job(Arc::downgrade(&actor), f, reply)),
+                actor: Arc::downgrade(&actor),
+            };
+
+            Either::B(
+                mailbox
+                    .send(envelope)
+                    .map_err(|_| ActorError::Stopped.into())
+                    .and_then(|_| response.map_err(|_| ActorError::Stopped.into()))
+                    .and_then(|result| result),
+            )
+        }))
+    }
+
+    /// Wraps `f` so it replies to the sender, and stops the task if the worker panics.
+    fn job<F, R>(actor: Weak<Actor<T>>, f: F, reply: oneshot::Sender<Result<R, Error>>) -> Job<T>
+    where
+        F: FnOnce(&mut dyn AsyncWorker<T>) -> AsyncResult<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        Box::new(move |worker| {
+            let caught =
+                futures::future::result(panic::catch_unwind(AssertUnwindSafe(|| f(worker))))
+                    .and_then(|run| AssertUnwindSafe(run).catch_unwind());
+
+            Box::new(caught.then(move |outcome| {
+                let (result, panicked) = match outcome {
+                    Ok(result) => (result, None),
+                    Err(panic) => {
+                        let message = panic_message(&*panic);
+                        (
+                            Err(ActorError::Panicked(message.clone()).into()),
+                            Some(message),
+                        )
+                    }
+                };
+
+                // the replacement is in place before the sender hears of the panic
+                if let Some(actor) = actor.upgrade() {
+                    match panicked {
+                        Some(ref message) => actor.crashed(message),
+                        None => actor.state().stats.handled += 1,
+                    }
+                }
+                let _ = reply.send(result);
+
+                match panicked {
+                    Some(message) => Err(ActorError::Panicked(message).into()),
+                    None => Ok(()),
+                }
+            }))
+        })
+    }
+}
+
+impl<T> AsyncWorker<T> for ActorWorker<T>
+where
+    T: Sync + Send + Clone + 'static,
+{
+    /// The config is also used to initialize every replacement worker.
+    fn init(self: &mut Self, config: &serde_json::Value) -> AsyncResult<()> {
+        self.0.state().config = Some(config.clone());
+        let config = config.clone();
+        self.call(move |worker| worker.init(&config))
+    }
+
+    fn warmup(self: &mut Self) -> AsyncResult<()> {
+        self.0.state().warmed_up = true;
+        self.call(|worker| worker.warmup())
+    }
+
+    fn health(self: &mut Self) -> AsyncResult<()> {
+        self.call(|worker| worker.health())
+    }
+
+    fn shutdown(self: &mut Self) -> AsyncResult<()> {
+        self.call(|worker| worker.shutdown())
+    }
+
+    fn compensation(self: &mut Self, input: &T, output: &T) -> AsyncResult<Option<Compensation>> {
+        let (input, output) = (input.clone(), output.clone());
+        self.call(move |worker| worker.compensation(&input, &output))
+    }
+
+    fn run(self: &mut Self, input: T) -> AsyncResult<T> {
+        self.send(input)
+    }
+}
+
+pub struct ActorBuilder<T> {
+    factory: WorkerFactory<T>,
+    capacity: usize,
+    max_restarts: u64,
+}
+
+impl<T> ActorBuilder<T>
+where
+    T: Sync + Send + Clone + 'static,
+{
+    /// Sets how many messages fit into the mailbox, at least one.
+    pub fn with_mailbox_capacity(mut self: Self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// Sets how often the worker is replaced after a panic before the actor stops.
+    pub fn with_max_restarts(mut self: Self, max_restarts: u64) -> Self {
+        self.max_restarts = max_restarts;
+        self
+    }
+
+    /// Starts the actor task on `executor`.
+    pub fn spawn(self: Self, executor: TaskExecutor) -> ActorWorker<T> {
+        let worker = (self.factory)();
+        let actor = Arc::new(Actor {
+            state: Mutex::new(ActorState {
+                mailbox: None,
+                generation: 0,
+                config: None,
+                warmed_up: false,
+                stats: ActorStats::default(),
+            }),
+            factory: self.factory,
+            executor,
+            capacity: self.capacity,
+            max_restarts: self.max_restarts,
+        });
+        actor.start(&mut actor.state(), worker);
+
+        ActorWorker(actor)
+    }
+}
+
+/// Like `wrapped::process`, but with actors as stages, so the runs don't lock the
+/// stages.
+pub fn process_with_actors<I>(actors: I) -> AsyncResult<WorkIO>
+where
+    I: IntoIterator<Item = ActorWorker<WorkIO>>,
+    I::IntoIter: Send + 'static,
+{
+    Box::new(
+        futures::stream::iter_ok::<_, Error>(actors)
+            .fold("".to_string(), |input, actor| actor.send(input)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{ExternalCountingForwarder, InternalSlowCounter};
+    use crate::wrapped::{ExternalWorkWrapper, InternalWorkWrapper};
+    use failure::Fallible;
+    use std::time::{Duration, Instant};
+
+    fn counter(delay: u64) -> ActorBuilder<WorkIO> {
+        ActorWorker::builder(move || -> Box<dyn AsyncWorker<WorkIO>> {
+            Box::new(InternalWorkWrapper(InternalSlowCounter::new(
+                Duration::from_millis(delay),
+            )))
+        })
+    }
+
+    /// Waits until `depth` messages are waiting for the actor.
+    fn waiting(actor: &ActorWorker<WorkIO>, depth: usize) {
+        for _ in 0..100 {
+            if actor.stats().mailbox_depth >= depth {
+                assert_eq!(actor.stats().mailbox_depth, depth);
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("messages are not waiting: {:?}", actor.stats());
+    }
+
+    #[test]
+    fn test_process_with_actors() -> Fallible<()> {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let actors = vec![
+            counter(0).spawn(runtime.executor()),
+            counter(0).spawn(runtime.executor()),
+        ];
+        for run in 1..3 {
+            assert_eq!(
+                runtime.block_on(process_with_actors(actors.clone()))?,
+                format!("{}{}", run, run)
+            );
+        }
+
+        // a full mailbox holds back the senders, which still count as waiting
+        let slow = counter(100)
+            .with_mailbox_capacity(1)
+            .spawn(runtime.executor());
+        let replies: Vec<_> = (0..4)
+            .map(|_| oneshot::spawn(slow.send("x".to_string()), &runtime.executor()))
+            .collect();
+        waiting(&slow, 3);
+        assert_eq!(
+            runtime.block_on(futures::future::join_all(replies))?,
+            vec!["x1", "x2", "x3", "x4"]
+        );
+        let stats = slow.stats();
+        assert_eq!((stats.mailbox_depth, stats.handled), (0, 4));
+        assert!(stats.peak_mailbox_depth >= 3);
+
+        // a panicked worker is replaced once, then the actor stops
+        let supervised = counter(0).with_max_restarts(1).spawn(runtime.executor());
+        let mut send = |input: &str| runtime.block_on(supervised.send(input.to_string()));
+        assert_eq!(send("a")?, "a1");
+        // manual restarts don't count against the restarts after panics
+        supervised.restart();
+        assert_eq!(send("a")?, "a1");
+        match send("panic").map_err(|e| e.downcast::<ActorError>()) {
+            Err(Ok(ActorError::Panicked(message))) => assert_eq!(message, "counter got panic"),
+            other => panic!("expected a panic, got {:?}", other.map(|_| ())),
+        }
+        assert_eq!(send("b")?, "b1");
+        assert!(send("panic").is_err());
+        match send("c").map_err(|e| e.downcast::<ActorError>()) {
+            Err(Ok(ActorError::Stopped)) => (),
+            other => panic!("expected a stopped actor, got {:?}", other.map(|_| ())),
+        }
+        let stats = supervised.stats();
+        assert_eq!(
+            (stats.panics, stats.restarts, stats.manual_restarts),
+            (2, 1, 1)
+        );
+        assert!(!supervised.is_running());
+
+        supervised.restart();
+        assert_eq!(runtime.block_on(supervised.send("d".to_string()))?, "d1");
+
+        // a replacement worker is initialized like the worker it replaces
+        let mut configured = ActorWorker::builder(|| -> Box<dyn AsyncWorker<WorkIO>> {
+            Box::new(ExternalWorkWrapper(ExternalCountingForwarder(0)))
+        })
+        .spawn(runtime.executor());
+        runtime.block_on(configured.init(&serde_json::json!({ "start": 4 })))?;
+        assert_eq!(runtime.block_on(configured.send("a".to_string()))?, "a5");
+        configured.restart();
+        assert_eq!(runtime.block_on(configured.send("b".to_string()))?, "b5");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,412 @@
+use crate::plugins::SharedError;
+use crate::wrapped::{with_locked, AsyncResult, AsyncWorker, Compensation};
+use failure::Error;
+use futures::future::Shared;
+use futures::sync::oneshot;
+use futures::Future;
+use futures_locks::Mutex as FuturesMutex;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    /// Runs which had to be computed
+    pub misses: u64,
+    /// Runs which waited for the computation of a concurrent miss of the same input
+    pub joined: u64,
+    /// Entries dropped for the size bound or because they expired
+    pub evictions: u64,
+    pub entries: usize,
+}
+
+struct CacheEntry<T> {
+    output: T,
+    stored: Instant,
+    /// Position in `CacheState::recency`
+    used: u64,
+}
+
+/// Computation of a missed input, which concurrent runs with the same input join
+struct InFlight<T> {
+    output: Shared<oneshot::Receiver<Result<T, SharedError>>>,
+    joined: usize,
+}
+
+struct CacheState<T> {
+    entries: HashMap<T, CacheEntry<T>>,
+    /// Inputs by the time of their last use, the least recently used first
+    recency: BTreeMap<u64, T>,
+    next_use: u64,
+    in_flight: HashMap<T, InFlight<T>>,
+    stats: CacheStats,
+}
+
+impl<T> CacheState<T>
+where
+    T: Eq + Hash + Clone,
+{
+    fn touch(self: &mut Self, key: &T) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.recency.remove(&entry.used);
+            self.next_use += 1;
+            entry.used = self.next_use;
+            self.recency.insert(entry.used, key.clone());
+        }
+    }
+
+    fn remove(self: &mut Self, key: &T) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.used);
+            self.stats.evictions += 1;
+        }
+    }
+
+    fn insert(self: &mut Self, key: T, output: T, capacity: usize) {
+        self.remove(&key);
+        self.next_use += 1;
+        self.recency.insert(self.next_use, key.clone());
+        self.entries.insert(
+            key,
+            CacheEntry {
+                output,
+                stored: Instant::now(),
+                used: self.next_use,
+            },
+        );
+
+        while self.entries.len() > capacity {
+            let oldest = match self.recency.values().next() {
+                Some(oldest) => oldest.clone(),
+                None => break,
+            };
+            self.remove(&oldest);
+        }
+    }
+}
+
+/// Takes a computation out of the in-flight ones if the run which missed is dropped
+/// before it finishes, so the next run with its input computes it again.
+struct Computing<T>
+where
+    T: Eq + Hash,
+{
+    state: Arc<Mutex<CacheState<T>>>,
+    key: Option<T>,
+}
+
+impl<T> Drop for Computing<T>
+where
+    T: Eq + Hash,
+{
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.state.lock().unwrap().in_flight.remove(&key);
+        }
+    }
+}
+
+/// AsyncWorker which remembers the outputs of the wrapped worker by their inputs and
+/// answers repeated inputs without running it.
+///
+/// At most `capacity` outputs are kept, the least recently used ones are evicted
+/// first, and outputs older than the TTL are computed again. Concurrent runs with the
+/// same input share one computation. Failures are not cached.
+///
+/// A failed computation fails the run which missed with the error of the wrapped
+/// worker. If other runs joined it, they all get a SharedError instead, whose cause
+/// is that error.
+///
+/// Clones share their entries and stats, so a clone can report the stats of a worker
+/// which has been moved into a pipeline.
+pub struct CachingWorker<T> {
+    worker: Arc<FuturesMutex<Box<dyn AsyncWorker<T>>>>,
+    state: Arc<Mutex<CacheState<T>>>,
+    capacity: usize,
+    ttl: Option<Duration>,
+}
+
+impl<T> Clone for CachingWorker<T> {
+    fn clone(&self) -> Self {
+        CachingWorker {
+            worker: self.worker.clone(),
+            state: self.state.clone(),
+            capacity: self.capacity,
+            ttl: self.ttl,
+        }
+    }
+}
+
+impl<T> CachingWorker<T>
+where
+    T: Eq + Hash + Sync + Send + Clone + 'static,
+{
+    pub fn new(worker: Box<dyn AsyncWorker<T>>) -> Self {
+        CachingWorker {
+            worker: Arc::new(FuturesMutex::new(worker)),
+            state: Arc::new(Mutex::new(CacheState {
+                entries: HashMap::new(),
+                recency: BTreeMap::new(),
+                next_use: 0,
+                in_flight: HashMap::new(),
+                stats: CacheStats::default(),
+            })),
+            capacity: 1024,
+            ttl: None,
+        }
+    }
+
+    /// Sets how many outputs are kept at most.
+    pub fn with_capacity(mut self: Self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Sets how long an output is used before it is computed again.
+    pub fn with_ttl(mut self: Self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    pub fn stats(self: &Self) -> CacheStats {
+        let state = self.state.lock().unwrap();
+        CacheStats {
+            entries: state.entries.len(),
+            ..state.stats
+        }
+    }
+
+    /// Drops all stored outputs.
+    pub fn clear(self: &Self) {
+        let mut state = self.state.lock().unwrap();
+        state.entries.clear();
+        state.recency.clear();
+    }
+}
+
+impl<T> AsyncWorker<T> for CachingWorker<T>
+where
+    T: Eq + Hash + Sync + Send + Clone + 'static,
+{
+    fn init(self: &mut Self, config: &serde_json::Value) -> AsyncResult<()> {
+        let config = config.clone();
+        with_locked(&self.worker, move |worker| worker.init(&config))
+    }
+
+    fn warmup(self: &mut Self) -> AsyncResult<()> {
+        with_locked(&self.worker, |worker| worker.warmup())
+    }
+
+    fn health(self: &mut Self) -> AsyncResult<()> {
+        with_locked(&self.worker, |worker| worker.health())
+    }
+
+    fn shutdown(self: &mut Self) -> AsyncResult<()> {
+        with_locked(&self.worker, |worker| worker.shutdown())
+    }
+
+    fn compensation(self: &mut Self, input: &T, output: &T) -> AsyncResult<Option<Compensation>> {
+        let (input, output) = (input.clone(), output.clone());
+        with_locked(&self.worker, move |worker| {
+            worker.compensation(&input, &output)
+        })
+    }
+
+    fn run(self: &mut Self, input: T) -> AsyncResult<T> {
+        let mut state = self.state.lock().unwrap();
+
+        let expired = match (state.entries.get(&input), self.ttl) {
+            (Some(entry), Some(ttl)) => entry.stored.elapsed() > ttl,
+            _ => false,
+        };
+        if expired {
+            state.remove(&input);
+        }
+
+        if let Some(output) = state.entries.get(&input).map(|entry| entry.output.clone()) {
+            state.touch(&input);
+            state.stats.hits += 1;
+            return Box::new(futures::future::ok(output));
+        }
+
+        if let Some(computation) = state.in_flight.get_mut(&input) {
+            computation.joined += 1;
+            let output = computation.output.clone();
+            state.stats.joined += 1;
+            return Box::new(output.then(|result| match result {
+                Ok(result) => (*result).clone().map_err(Error::from),
+                Err(_) => Err(failure::err_msg("the run computing the output was dropped")),
+            }));
+        }
+
+        state.stats.misses += 1;
+        let (computed, output) = oneshot::channel();
+        state.in_flight.insert(
+            input.clone(),
+            InFlight {
+                output: output.shared(),
+                joined: 0,
+            },
+        );
+        drop(state);
+
+        let mut computing = Computing {
+            state: self.state.clone(),
+            key: Some(input.clone()),
+        };
+        let capacity = self.capacity;
+        Box::new(
+            with_locked(&self.worker, move |worker| worker.run(input)).then(move |result| {
+                let key = computing.key.take().expect("computation finished twice");
+                let mut state = computing.state.lock().unwrap();
+                let joined = state.in_flight.remove(&key).map_or(0, |c| c.joined);
+                match result {
+                    Ok(output) => {
+                        state.insert(key, output.clone(), capacity);
+                        let _ = computed.send(Ok(output.clone()));
+                        Ok(output)
+                    }
+                    Err(e) if joined == 0 => Err(e),
+                    Err(e) => {
+                        let e = SharedError::from(e);
+                        let _ = computed.send(Err(e.clone()));
+                        Err(e.into())
+                    }
+                }
+            }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::InternalSlowCounter;
+    use crate::wrapped::{AsyncWorkerInternal, InternalWorkWrapper, WorkIO};
+    use failure::{Error, Fallible};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_cache_outputs() -> Fallible<()> {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let cache = CachingWorker::new(Box::new(InternalWorkWrapper(InternalSlowCounter {
+            runs: runs.clone(),
+            delay: Duration::from_millis(20),
+        })))
+        .with_capacity(2)
+        .with_ttl(Duration::from_millis(200));
+        let mut worker = cache.clone();
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        // concurrent misses share one run
+        let concurrent = worker
+            .run("a".to_string())
+            .join(worker.run("a".to_string()));
+        assert_eq!(
+            runtime.block_on(concurrent)?,
+            ("a1".to_string(), "a1".to_string())
+        );
+        assert_eq!(runtime.block_on(worker.run("a".to_string()))?, "a1");
+        assert_eq!(runtime.block_on(worker.run("b".to_string()))?, "b2");
+
+        // "b" is the least recently used once "a" was used again
+        assert_eq!(runtime.block_on(worker.run("a".to_string()))?, "a1");
+        assert_eq!(runtime.block_on(worker.run("c".to_string()))?, "c3");
+        assert_eq!(runtime.block_on(worker.run("a".to_string()))?, "a1");
+        assert_eq!(runtime.block_on(worker.run("b".to_string()))?, "b4");
+
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 3,
+                misses: 4,
+                joined: 1,
+                evictions: 2,
+                entries: 2,
+            }
+        );
+
+        std::thread::sleep(Duration::from_millis(250));
+        assert_eq!(runtime.block_on(worker.run("b".to_string()))?, "b5");
+        assert_eq!(runs.load(Ordering::SeqCst), 5);
+
+        Ok(())
+    }
+
+    /// Input whose hash is the same for every value
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Colliding(&'static str);
+    impl Hash for Colliding {
+        fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+            0.hash(state)
+        }
+    }
+
+    #[derive(Debug, Fail)]
+    #[fail(display = "refused {}", _0)]
+    struct Refused(&'static str);
+
+    /// Echoes its input after a short delay, or refuses inputs starting with "!"
+    struct SlowEcho;
+    impl AsyncWorker<Colliding> for SlowEcho {
+        fn run(self: &mut Self, input: Colliding) -> AsyncResult<Colliding> {
+            Box::new(
+                tokio::timer::Delay::new(Instant::now() + Duration::from_millis(20))
+                    .map_err(Error::from)
+                    .and_then(move |_| {
+                        if input.0.starts_with('!') {
+                            Err(Refused(input.0).into())
+                        } else {
+                            Ok(input)
+                        }
+                    }),
+            )
+        }
+    }
+
+    #[test]
+    fn test_cache_by_input_and_keep_errors() -> Fallible<()> {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut worker = CachingWorker::new(Box::new(SlowEcho));
+
+        // inputs with the same hash are still told apart
+        assert_eq!(
+            runtime.block_on(worker.run(Colliding("a")))?,
+            Colliding("a")
+        );
+        assert_eq!(
+            runtime.block_on(worker.run(Colliding("b")))?,
+            Colliding("b")
+        );
+        assert_eq!(
+            runtime.block_on(worker.run(Colliding("a")))?,
+            Colliding("a")
+        );
+
+        // the run which missed gets the worker's error as it is
+        let error = runtime
+            .block_on(worker.run(Colliding("!c")))
+            .expect_err("input should be refused");
+        assert!(error.downcast_ref::<Refused>().is_some());
+
+        // runs which joined it can still find it
+        let (missed, joined) = runtime
+            .block_on(
+                worker
+                    .run(Colliding("!d"))
+                    .then(Ok::<_, Error>)
+                    .join(worker.run(Colliding("!d")).then(Ok::<_, Error>)),
+            )
+            .unwrap();
+        for result in [missed, joined] {
+            let error = result.expect_err("input should be refused");
+            assert_eq!(error.to_string(), "refused !d");
+            assert!(error.find_root_cause().downcast_ref::<Refused>().is_some());
+        }
+        assert_eq!(worker.stats().joined, 1);
+
+        Ok(())
+    }
+}
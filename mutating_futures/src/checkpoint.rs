@@ -0,0 +1,293 @@
+use crate::plugins::{FutureIO, PluginProcessor};
+use failure::Error;
+use futures::future::Either;
+use futures::{Future, Stream};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::timer::Interval;
+
+/// Writes the snapshots of a processor's checkpointable plugins to a directory and
+/// restores them from there.
+///
+/// Every plugin gets its own `<name>.checkpoint` file, which is replaced atomically:
+/// the snapshot is written to a temporary file and synced before it is renamed, and the
+/// directory is synced after the renames.
+/// Characters of the name other than ASCII letters, digits, `-` and `_` are percent
+/// encoded, so names like `../x` stay inside the directory.
+#[derive(Debug, Clone)]
+pub struct Checkpointer {
+    processor: PluginProcessor,
+    directory: PathBuf,
+    period: Option<Duration>,
+}
+
+/// Writes `contents` to `path` and waits until they are on disk.
+fn write_synced(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut file = std::fs::File::create(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+impl Checkpointer {
+    pub fn new<P: Into<PathBuf>>(processor: &PluginProcessor, directory: P) -> Self {
+        Checkpointer {
+            processor: processor.clone(),
+            directory: directory.into(),
+            period: None,
+        }
+    }
+
+    /// Makes `run` checkpoint every `period` instead of only at shutdown.
+    pub fn with_period(mut self: Self, period: Duration) -> Self {
+        self.period = Some(period);
+        self
+    }
+
+    fn path(self: &Self, name: &str) -> PathBuf {
+        let mut file_name = String::with_capacity(name.len() + ".checkpoint".len());
+        for byte in name.bytes() {
+            match byte {
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => {
+                    file_name.push(byte as char)
+                }
+                _ => file_name.push_str(&format!("%{:02X}", byte)),
+            }
+        }
+        file_name.push_str(".checkpoint");
+        self.directory.join(file_name)
+    }
+
+    /// Starts the processor like `PluginProcessor::start`, restoring the plugins from
+    /// their checkpoints after they are initialized and before they are warmed up.
+    pub fn start(self: &Self) -> FutureIO<'static, ()> {
+        let checkpointer = self.clone();
+        self.processor
+            .start_with(move || Box::new(checkpointer.restore().map(|_| ())))
+    }
+
+    /// Restores all plugins which have a checkpoint file and returns their number.
+    pub fn restore(self: &Self) -> FutureIO<'static, usize> {
+        let restores: Vec<FutureIO<'static, bool>> = self
+            .processor
+            .nodes()
+            .iter()
+            .map(|node| -> FutureIO<'static, bool> {
+                let snapshot = match std::fs::read(self.path(&node.name)) {
+                    Ok(snapshot) => snapshot,
+                    Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        return Box::new(futures::future::ok(false))
+                    }
+                    Err(e) => return Box::new(futures::future::err(e.into())),
+                };
+
+                let name = node.name.clone();
+                Box::new(node.plugin.restore(snapshot).then(move |result| {
+                    result.map(|_| true).map_err(|e| {
+                        e.context(format!("could not restore plugin '{}'", name))
+                            .into()
+                    })
+                }))
+            })
+            .collect();
+
+        Box::new(futures::future::join_all(restores).map(|restored| {
+            let restored = restored.into_iter().filter(|restored| *restored).count();
+            println!("[] restored {} plugins", restored);
+            restored
+        }))
+    }
+
+    /// Writes the snapshots of all checkpointable plugins and returns their number.
+    pub fn checkpoint(self: &Self) -> FutureIO<'static, usize> {
+        let snapshots: Vec<_> = self
+            .processor
+            .nodes()
+            .iter()
+            .map(|node| {
+                let path = self.path(&node.name);
+                node.plugin
+                    .snapshot()
+                    .map(move |snapshot| snapshot.map(|snapshot| (path, snapshot)))
+            })
+            .collect();
+        let directory = self.directory.clone();
+
+        Box::new(
+            futures::future::join_all(snapshots).and_then(move |snapshots| {
+                std::fs::create_dir_all(&directory)?;
+
+                let mut written = 0;
+                for (path, snapshot) in snapshots.into_iter().flatten() {
+                    let temporary = path.with_extension("checkpoint.tmp");
+                    write_synced(&temporary, &snapshot)?;
+                    std::fs::rename(&temporary, &path)?;
+                    written += 1;
+                }
+                // makes the renames durable
+                std::fs::File::open(&directory)?.sync_all()?;
+
+                println!("[] checkpointed {} plugins", written);
+                Ok(written)
+            }),
+        )
+    }
+
+    /// Checkpoints periodically, if a period is set, until `shutdown` resolves and
+    /// then one last time.
+    ///
+    /// A failed periodic checkpoint is logged and retried at the next period.
+    pub fn run<F>(self: &Self, shutdown: F) -> FutureIO<'static, ()>
+    where
+        F: Future<Item = (), Error = ()> + Send + 'static,
+    {
+        let periodic: FutureIO<'static, ()> = match self.period {
+            Some(period) => {
+                let checkpointer = self.clone();
+                Box::new(
+                    Interval::new(Instant::now() + period, period)
+                        .map_err(Error::from)
+                        .for_each(move |_| {
+                            checkpointer.checkpoint().then(|result| {
+                                if let Err(e) = result {
+                                    println!("[] periodic checkpoint failed: {}", e);
+                                }
+                                Ok(())
+                            })
+                        }),
+                )
+            }
+            None => Box::new(futures::future::empty()),
+        };
+
+        let checkpointer = self.clone();
+        Box::new(periodic.select2(shutdown).then(move |result| match result {
+            Err(Either::A((e, _))) => Either::A(futures::future::err(e)),
+            _ => Either::B(checkpointer.checkpoint().map(|_| ())),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugins::{
+        Checkpoint, InternalPlugin, InternalPluginWrapper, PluginNode, PluginReference,
+    };
+    use failure::Fallible;
+    use futures::sync::oneshot;
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashSet;
+
+    /// Counts its runs and drops inputs it has seen before
+    #[derive(Debug, Default, Serialize, Deserialize)]
+    struct DedupPlugin {
+        counter: usize,
+        seen: HashSet<String>,
+    }
+
+    impl InternalPlugin for DedupPlugin {
+        fn run_internal(self: &mut Self, io: String) -> FutureIO<'static, String> {
+            self.counter += 1;
+            let output = if self.seen.insert(io.clone()) {
+                format!("{}{}", io, self.counter)
+            } else {
+                String::new()
+            };
+
+            Box::new(futures::future::ok(output))
+        }
+
+        fn as_checkpoint(self: &mut Self) -> Option<&mut dyn Checkpoint> {
+            Some(self)
+        }
+    }
+
+    impl Checkpoint for DedupPlugin {
+        fn snapshot(self: &mut Self) -> Fallible<Vec<u8>> {
+            Ok(serde_json::to_vec(self)?)
+        }
+
+        fn restore(self: &mut Self, snapshot: &[u8]) -> Fallible<()> {
+            *self = serde_json::from_slice(snapshot)?;
+            Ok(())
+        }
+    }
+
+    #[derive(Debug)]
+    struct StatelessPlugin;
+
+    impl InternalPlugin for StatelessPlugin {
+        fn run_internal(self: &mut Self, io: String) -> FutureIO<'static, String> {
+            Box::new(futures::future::ok(io))
+        }
+    }
+
+    fn processor() -> PluginProcessor {
+        let dedup: PluginReference = Box::new(InternalPluginWrapper::new(DedupPlugin::default()));
+        let stateless: PluginReference = Box::new(InternalPluginWrapper::new(StatelessPlugin));
+
+        PluginProcessor::new(vec![
+            PluginNode::new("dedup", dedup, &[]),
+            PluginNode::new("stateless", stateless, &["dedup"]),
+        ])
+        .expect("invalid plugin graph")
+    }
+
+    #[test]
+    fn checkpoint_and_restore_plugins() -> Fallible<()> {
+        let directory = std::env::temp_dir().join(format!(
+            "mutating_futures_checkpoint_{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&directory);
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let before_restart = processor();
+        runtime.block_on(before_restart.start())?;
+        assert_eq!(
+            runtime.block_on(before_restart.process("a".to_string()))?,
+            "a1"
+        );
+
+        let (shutdown, shutdown_receiver) = oneshot::channel();
+        let checkpointer =
+            Checkpointer::new(&before_restart, &directory).with_period(Duration::from_secs(60));
+        let running = checkpointer.run(shutdown_receiver.map_err(|_| ()));
+        shutdown.send(()).unwrap();
+        runtime.block_on(running)?;
+        assert!(directory.join("dedup.checkpoint").exists());
+        assert!(!directory.join("stateless.checkpoint").exists());
+
+        // the counter and the seen inputs survive the restart
+        let after_restart = processor();
+        runtime.block_on(Checkpointer::new(&after_restart, &directory).start())?;
+        assert_eq!(
+            runtime.block_on(after_restart.process("a".to_string()))?,
+            ""
+        );
+        assert_eq!(
+            runtime.block_on(after_restart.process("b".to_string()))?,
+            "b3"
+        );
+
+        // a broken checkpoint keeps the plugin's error as the cause
+        std::fs::write(directory.join("dedup.checkpoint"), "not a snapshot")?;
+        let error = runtime
+            .block_on(Checkpointer::new(&processor(), &directory).restore())
+            .expect_err("the snapshot should not parse");
+        assert_eq!(error.to_string(), "could not restore plugin 'dedup'");
+        assert!(error
+            .iter_causes()
+            .any(|cause| cause.downcast_ref::<serde_json::Error>().is_some()));
+
+        std::fs::remove_dir_all(&directory)?;
+
+        // names can't leave the directory
+        assert_eq!(
+            checkpointer.path("../dedup/a.b"),
+            directory.join("%2E%2E%2Fdedup%2Fa%2Eb.checkpoint")
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,1040 @@
+use crate::introspection::StageKind;
+use crate::lock_monitor::locking;
+use crate::plugins::{FutureIO, Plugin, PluginConfig};
+use core::fmt::Debug;
+use core::fmt::Formatter;
+use failure::Error;
+use failure::Fallible;
+use failure::ResultExt;
+use futures::future::{Either, Loop};
+use futures::sync::oneshot;
+use futures::{Future, Sink, Stream};
+use futures_locks::Mutex as FuturesMutex;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+#[cfg(target_os = "linux")]
+use std::os::unix::process::{CommandExt as UnixCommandExt, ExitStatusExt};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::codec::{FramedRead, FramedWrite, LinesCodec};
+use tokio::executor::{DefaultExecutor, Executor};
+use tokio_process::{Child, ChildStdin, ChildStdout, CommandExt};
+
+/// Lines at the end of the process' stderr which are kept to tell how it ended
+const STDERR_TAIL: usize = 16;
+
+/// Parts of the messages of failed allocations, from `strerror(ENOMEM)`, Rust, C
+/// libraries and Python
+const OUT_OF_MEMORY: &[&str] = &[
+    "Cannot allocate memory",
+    "memory allocation of",
+    "out of memory",
+    "MemoryError",
+];
+
+/// Part of the messages of `strerror(EMFILE)` and `strerror(ENFILE)`
+const TOO_MANY_OPEN_FILES: &str = "Too many open files";
+
+/// Messages sent to the external process, one JSON object per line
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum ExternalRequest {
+    Init { config: PluginConfig },
+    Warmup,
+    Run { payload: String },
+    Health,
+    Shutdown,
+}
+
+/// Reply to an `init`, `run` or `health` request, one JSON object per line
+#[derive(Debug, Deserialize)]
+struct ExternalResponse {
+    #[serde(default)]
+    payload: Option<String>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+/// How an external plugin failed.
+///
+/// A process which runs out of memory or file descriptors only sees its allocations
+/// or `open` calls fail, so when a process with such a limit ends, its exit is taken
+/// as a breach of it if:
+///
+/// - `MemoryExceeded`: it was killed by SIGSEGV or SIGABRT, which failed allocations
+///   and stacks which can't grow end in, or its stderr reports a failed allocation.
+/// - `OpenFilesExceeded`: its stderr reports `EMFILE`.
+///
+/// With a CPU time limit, SIGXCPU and SIGKILL are taken as a breach of it. Any other
+/// exit is `Crashed`.
+#[derive(Debug, Fail)]
+pub enum ExternalProcessError {
+    #[fail(
+        display = "external plugin '{}' exceeded its CPU time limit of {:?}",
+        program, limit
+    )]
+    CpuTimeExceeded { program: String, limit: Duration },
+    #[fail(
+        display = "external plugin '{}' ran out of its memory limit of {} bytes",
+        program, limit
+    )]
+    MemoryExceeded { program: String, limit: u64 },
+    #[fail(
+        display = "external plugin '{}' ran out of its limit of {} open files",
+        program, limit
+    )]
+    OpenFilesExceeded { program: String, limit: u64 },
+    #[fail(
+        display = "external plugin '{}' took longer than {:?} and was killed",
+        program, limit
+    )]
+    WallClockExceeded { program: String, limit: Duration },
+    #[fail(
+        display = "external plugin '{}' did not answer within {:?} and was killed",
+        program, timeout
+    )]
+    TimedOut { program: String, timeout: Duration },
+    #[fail(display = "external plugin '{}' crashed with {}", program, status)]
+    Crashed { program: String, status: ExitStatus },
+    #[fail(
+        display = "external plugin '{}' could not be set up again after a restart: {}",
+        program, error
+    )]
+    SetupFailed { program: String, error: String },
+    #[fail(
+        display = "external plugin '{}' has resource limits, which are only supported on Linux",
+        program
+    )]
+    LimitsUnsupported { program: String },
+}
+
+impl ExternalProcessError {
+    /// Explains how the process of `command` ended, given the last lines of its stderr.
+    fn from_status(command: &ExternalCommand, status: ExitStatus, stderr: &[String]) -> Self {
+        let program = command.program.clone();
+        let limits = &command.limits;
+        let reported = |messages: &[&str]| {
+            stderr
+                .iter()
+                .any(|line| messages.iter().any(|message| line.contains(message)))
+        };
+
+        #[cfg(target_os = "linux")]
+        {
+            let signal = status.signal();
+            if let Some(limit) = limits.cpu_time {
+                // SIGXCPU at the soft limit, SIGKILL at the hard one
+                if signal == Some(libc::SIGXCPU) || signal == Some(libc::SIGKILL) {
+                    return ExternalProcessError::CpuTimeExceeded { program, limit };
+                }
+            }
+            if let Some(limit) = limits.memory_bytes {
+                if signal == Some(libc::SIGSEGV) || signal == Some(libc::SIGABRT) {
+                    return ExternalProcessError::MemoryExceeded { program, limit };
+                }
+            }
+        }
+        if let Some(limit) = limits.memory_bytes {
+            if reported(OUT_OF_MEMORY) {
+                return ExternalProcessError::MemoryExceeded { program, limit };
+            }
+        }
+        if let Some(limit) = limits.open_files {
+            if reported(&[TOO_MANY_OPEN_FILES]) {
+                return ExternalProcessError::OpenFilesExceeded { program, limit };
+            }
+        }
+        ExternalProcessError::Crashed { program, status }
+    }
+
+    /// Whether the process was killed because it took too long.
+    fn is_deadline(error: &Error) -> bool {
+        matches!(
+            error.downcast_ref::<ExternalProcessError>(),
+            Some(ExternalProcessError::WallClockExceeded { .. })
+                | Some(ExternalProcessError::TimedOut { .. })
+        )
+    }
+
+    /// Whether `error` is a breached limit or a failed setup, which a restart wouldn't
+    /// help with.
+    fn is_permanent(error: &Error) -> bool {
+        matches!(
+            error.downcast_ref::<ExternalProcessError>(),
+            Some(ExternalProcessError::SetupFailed { .. })
+                | Some(ExternalProcessError::CpuTimeExceeded { .. })
+                | Some(ExternalProcessError::MemoryExceeded { .. })
+                | Some(ExternalProcessError::OpenFilesExceeded { .. })
+                | Some(ExternalProcessError::WallClockExceeded { .. })
+        )
+    }
+}
+
+/// Linux limits which the process of an ExternalPlugin runs with, none by default
+///
+/// On other platforms, only the wall clock limit is available; a plugin with any of
+/// the other limits fails to start with `ExternalProcessError::LimitsUnsupported`.
+/// See ExternalProcessError for which breaches are reported as such.
+#[derive(Debug, Clone, Default)]
+pub struct ResourceLimits {
+    memory_bytes: Option<u64>,
+    cpu_time: Option<Duration>,
+    open_files: Option<u64>,
+    isolate_network: bool,
+    wall_clock: Option<Duration>,
+}
+
+impl ResourceLimits {
+    /// Limits the address space of the process.
+    ///
+    /// Allocations beyond it fail within the process, which usually makes it crash,
+    /// see ExternalProcessError::MemoryExceeded.
+    pub fn with_memory_limit(mut self: Self, bytes: u64) -> Self {
+        self.memory_bytes = Some(bytes);
+        self
+    }
+
+    /// Limits the CPU time of the process, rounded up to whole seconds.
+    pub fn with_cpu_time_limit(mut self: Self, cpu_time: Duration) -> Self {
+        self.cpu_time = Some(cpu_time);
+        self
+    }
+
+    /// Limits the number of file descriptors the process may have open.
+    pub fn with_open_files_limit(mut self: Self, open_files: u64) -> Self {
+        self.open_files = Some(open_files);
+        self
+    }
+
+    /// Runs the process in a network namespace of its own, which has no interfaces
+    /// besides a loopback one which is down.
+    ///
+    /// Without the privileges for a network namespace, a user namespace is created
+    /// along with it, in which the process runs as an unmapped user.
+    pub fn with_isolated_network(mut self: Self) -> Self {
+        self.isolate_network = true;
+        self
+    }
+
+    /// Kills the process if it takes longer than `wall_clock` to answer a request.
+    pub fn with_wall_clock_limit(mut self: Self, wall_clock: Duration) -> Self {
+        self.wall_clock = Some(wall_clock);
+        self
+    }
+
+    /// Whether there are limits which are applied to the process when it starts.
+    fn applies_at_start(self: &Self) -> bool {
+        self.memory_bytes.is_some()
+            || self.cpu_time.is_some()
+            || self.open_files.is_some()
+            || self.isolate_network
+    }
+
+    /// Makes `command` apply the limits to its process right before it executes
+    /// `program`.
+    #[cfg(target_os = "linux")]
+    fn apply(self: &Self, _program: &str, command: &mut Command) -> Fallible<()> {
+        if !self.applies_at_start() {
+            return Ok(());
+        }
+        let limits = self.clone();
+        // the closure runs in the forked child, where it only makes system calls
+        unsafe {
+            command.pre_exec(move || limits.enforce());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn apply(self: &Self, program: &str, _command: &mut Command) -> Fallible<()> {
+        if !self.applies_at_start() {
+            return Ok(());
+        }
+        Err(ExternalProcessError::LimitsUnsupported {
+            program: program.to_string(),
+        }
+        .into())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn enforce(self: &Self) -> std::io::Result<()> {
+        let check = |result: libc::c_int| {
+            if result == 0 {
+                Ok(())
+            } else {
+                Err(std::io::Error::last_os_error())
+            }
+        };
+        let limit = |soft: u64, hard: u64| libc::rlimit {
+            rlim_cur: soft as libc::rlim_t,
+            rlim_max: hard as libc::rlim_t,
+        };
+
+        if let Some(bytes) = self.memory_bytes {
+            check(unsafe { libc::setrlimit(libc::RLIMIT_AS, &limit(bytes, bytes)) })?;
+        }
+        if let Some(cpu_time) = self.cpu_time {
+            // SIGXCPU at the soft limit, SIGKILL a second later if it is ignored
+            let seconds = cpu_time.as_secs() + u64::from(cpu_time.subsec_nanos() > 0);
+            let seconds = seconds.max(1);
+            check(unsafe { libc::setrlimit(libc::RLIMIT_CPU, &limit(seconds, seconds + 1)) })?;
+        }
+        if let Some(open_files) = self.open_files {
+            check(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit(open_files, open_files)) })?;
+        }
+        if self.isolate_network && unsafe { libc::unshare(libc::CLONE_NEWNET) } != 0 {
+            check(unsafe { libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNET) })?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ExternalCommand {
+    program: String,
+    args: Vec<String>,
+    limits: ResourceLimits,
+    request_timeout: Option<Duration>,
+}
+
+struct ExternalProcess {
+    command: ExternalCommand,
+    child: Child,
+    requests: FramedWrite<ChildStdin, LinesCodec>,
+    responses: FramedRead<ChildStdout, LinesCodec>,
+    /// The last lines of stderr, once the process closed it
+    stderr: oneshot::Receiver<Vec<String>>,
+}
+
+impl Debug for ExternalProcess {
+    fn fmt(self: &Self, formatter: &mut Formatter) -> Result<(), std::fmt::Error> {
+        write!(formatter, "ExternalProcess {{ pid: {} }}", self.child.id())
+    }
+}
+
+impl ExternalProcess {
+    fn spawn(command: &ExternalCommand) -> Fallible<Self> {
+        let mut process = Command::new(&command.program);
+        process
+            .args(&command.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        command.limits.apply(&command.program, &mut process)?;
+        let mut child = process
+            .spawn_async()
+            .with_context(|_| format!("could not spawn external plugin '{}'", command.program))?;
+
+        let stdin = child
+            .stdin()
+            .take()
+            .ok_or_else(|| failure::err_msg("external plugin has no stdin"))?;
+        let stdout = child
+            .stdout()
+            .take()
+            .ok_or_else(|| failure::err_msg("external plugin has no stdout"))?;
+        let stderr = Self::forward_stderr(&mut child);
+
+        Ok(ExternalProcess {
+            command: command.clone(),
+            child,
+            requests: FramedWrite::new(stdin, LinesCodec::new()),
+            responses: FramedRead::new(stdout, LinesCodec::new()),
+            stderr,
+        })
+    }
+
+    /// Copies the stderr of `child` to ours and sends its last lines once it closes.
+    fn forward_stderr(child: &mut Child) -> oneshot::Receiver<Vec<String>> {
+        let (tail, receiver) = oneshot::channel();
+        let stderr = match child.stderr().take() {
+            Some(stderr) => stderr,
+            None => return receiver,
+        };
+
+        let forward = FramedRead::new(stderr, LinesCodec::new())
+            .fold(VecDeque::new(), |mut lines, line| {
+                eprintln!("{}", line);
+                if lines.len() == STDERR_TAIL {
+                    lines.pop_front();
+                }
+                lines.push_back(line);
+                Ok::<_, std::io::Error>(lines)
+            })
+            .then(move |lines| {
+                let _ = tail.send(lines.map(Vec::from).unwrap_or_default());
+                Ok(())
+            });
+
+        let mut executor = DefaultExecutor::current();
+        if executor.status().is_ok() {
+            let _ = executor.spawn(Box::new(forward));
+        } else {
+            std::thread::spawn(move || forward.wait());
+        }
+        receiver
+    }
+
+    /// Writes one request line and reads back one response line.
+    ///
+    /// If the process ends instead of answering, the error tells how it ended. The
+    /// error comes with whether the request was written, i.e. whether the process may
+    /// have handled it.
+    fn exchange(
+        self: Self,
+        line: String,
+    ) -> impl Future<Item = (Self, String), Error = (Error, bool)> {
+        let ExternalProcess {
+            command,
+            child,
+            requests,
+            responses,
+            stderr,
+        } = self;
+
+        let exchange = requests
+            .send(line)
+            .map_err(|e| (Error::from(e), false))
+            .and_then(move |requests| {
+                responses
+                    .into_future()
+                    .map_err(|(e, _)| (Error::from(e), true))
+                    .map(move |(response, responses)| (requests, response, responses))
+            });
+
+        // the earlier of the wall clock limit and the request timeout applies
+        let deadline = match (command.limits.wall_clock, command.request_timeout) {
+            (Some(limit), Some(timeout)) if timeout < limit => Some((timeout, false)),
+            (Some(limit), _) => Some((limit, true)),
+            (None, Some(timeout)) => Some((timeout, false)),
+            (None, None) => None,
+        };
+        let exchange = match deadline {
+            None => Either::A(exchange),
+            Some((limit, is_limit)) => {
+                let program = command.program.clone();
+                Either::B(
+                    tokio::timer::Timeout::new(exchange, limit).map_err(move |e| {
+                        if !e.is_elapsed() {
+                            return e.into_inner().unwrap_or_else(|| {
+                                (
+                                    failure::err_msg("the external plugin's kill timer failed"),
+                                    true,
+                                )
+                            });
+                        }
+                        let error = if is_limit {
+                            ExternalProcessError::WallClockExceeded { program, limit }
+                        } else {
+                            ExternalProcessError::TimedOut {
+                                program,
+                                timeout: limit,
+                            }
+                        };
+                        (error.into(), true)
+                    }),
+                )
+            }
+        };
+
+        exchange.then(move |result| {
+            let (fallback, sent) = match result {
+                Ok((requests, Some(response), responses)) => {
+                    return Either::A(futures::future::ok((
+                        ExternalProcess {
+                            command,
+                            child,
+                            requests,
+                            responses,
+                            stderr,
+                        },
+                        response,
+                    )))
+                }
+                // dropping the child kills the process
+                Err((e, sent)) if ExternalProcessError::is_deadline(&e) => {
+                    return Either::A(futures::future::err((e, sent)))
+                }
+                Ok((_, None, _)) => (failure::err_msg("external plugin closed its stdout"), true),
+                Err((e, sent)) => (e, sent),
+            };
+
+            Either::B(
+                Self::exit_error(command, child, stderr, fallback).map_err(move |e| (e, sent)),
+            )
+        })
+    }
+
+    /// Waits briefly for the process to end and fails with how it ended, or with
+    /// `fallback` if it doesn't end.
+    fn exit_error(
+        command: ExternalCommand,
+        child: Child,
+        stderr: oneshot::Receiver<Vec<String>>,
+        fallback: Error,
+    ) -> impl Future<Item = (Self, String), Error = Error> {
+        tokio::timer::Timeout::new(child, Duration::from_secs(1)).then(move |result| {
+            let status = match result {
+                Ok(status) => status,
+                Err(_) => return Either::A(futures::future::err(fallback)),
+            };
+
+            // the rest of stderr may still be on its way, unless another process
+            // keeps it open
+            Either::B(
+                tokio::timer::Timeout::new(stderr, Duration::from_millis(100)).then(
+                    move |stderr| {
+                        let stderr = stderr.unwrap_or_default();
+                        Err(ExternalProcessError::from_status(&command, status, &stderr).into())
+                    },
+                ),
+            )
+        })
+    }
+
+    /// Sends the `setup` requests to a restarted process, failing with `SetupFailed`
+    /// if it rejects one of them. The errors come with whether a request of the
+    /// caller was sent, which it never is here.
+    fn replay(
+        self: Self,
+        setup: Vec<ExternalRequest>,
+    ) -> impl Future<Item = Self, Error = (Error, bool)> {
+        let program = self.command.program.clone();
+
+        futures::stream::iter_ok::<_, (Error, bool)>(setup).fold(self, move |process, request| {
+            let line = serde_json::to_string(&request).expect("setup request was sent before");
+            let program = program.clone();
+
+            process
+                .exchange(line)
+                .map_err(|(e, _)| (e, false))
+                .and_then(move |(process, response)| {
+                    let error = match serde_json::from_str::<ExternalResponse>(&response) {
+                        Ok(ExternalResponse { error: None, .. }) => return Ok(process),
+                        Ok(ExternalResponse {
+                            error: Some(error), ..
+                        }) => error,
+                        Err(e) => format!("invalid response {}: {}", response, e),
+                    };
+                    Err((
+                        ExternalProcessError::SetupFailed { program, error }.into(),
+                        false,
+                    ))
+                })
+        })
+    }
+}
+
+/// Plugin which runs a local executable and exchanges newline-delimited JSON
+/// messages with it over stdin and stdout.
+///
+/// The process is spawned on first use and restarted if it crashes. A restarted
+/// process is sent the last `init` request and, if the plugin was warmed up, a
+/// `warmup` request before anything else. A request is only sent again to the
+/// restarted process if the crashed one never got it, unless the plugin is marked
+/// idempotent.
+#[derive(Debug, Clone)]
+pub struct ExternalPlugin {
+    command: ExternalCommand,
+    max_restarts: usize,
+    idempotent: bool,
+    shutdown_timeout: Duration,
+    process: Arc<FuturesMutex<Option<ExternalProcess>>>,
+    /// The init and warmup requests the process answered, for its restarts
+    setup: Arc<std::sync::Mutex<Vec<ExternalRequest>>>,
+}
+
+impl ExternalPlugin {
+    pub fn new(program: &str, args: &[&str]) -> Self {
+        ExternalPlugin {
+            command: ExternalCommand {
+                program: program.to_string(),
+                args: args.iter().map(|arg| arg.to_string()).collect(),
+                limits: ResourceLimits::default(),
+                request_timeout: Some(Duration::from_secs(30)),
+            },
+            max_restarts: 3,
+            idempotent: false,
+            shutdown_timeout: Duration::from_secs(5),
+            process: Arc::new(FuturesMutex::new(None)),
+            setup: Default::default(),
+        }
+    }
+
+    /// Sets how often a crashed process is restarted for a single request.
+    pub fn with_max_restarts(mut self: Self, max_restarts: usize) -> Self {
+        self.max_restarts = max_restarts;
+        self
+    }
+
+    /// Also sends requests again which a crashed process may already have handled.
+    ///
+    /// Only for plugins whose requests have no side effects, or whose side effects
+    /// may happen more than once.
+    pub fn with_idempotent_requests(mut self: Self) -> Self {
+        self.idempotent = true;
+        self
+    }
+
+    /// Sets how long the process may take to answer a request before it is killed,
+    /// 30 seconds by default. `None` waits forever.
+    pub fn with_request_timeout(mut self: Self, request_timeout: Option<Duration>) -> Self {
+        self.command.request_timeout = request_timeout;
+        self
+    }
+
+    /// Sets the limits the process runs with from its next start on.
+    pub fn with_limits(mut self: Self, limits: ResourceLimits) -> Self {
+        self.command.limits = limits;
+        self
+    }
+
+    /// Sets how long `shutdown` waits for the process to exit before killing it.
+    pub fn with_shutdown_timeout(mut self: Self, shutdown_timeout: Duration) -> Self {
+        self.shutdown_timeout = shutdown_timeout;
+        self
+    }
+
+    /// Starts the process unless it is already running, setting it up again if it
+    /// ran before.
+    pub fn spawn(self: &Self) -> FutureIO<'static, ()> {
+        let command = self.command.clone();
+        let setup = self.setup.clone();
+
+        Box::new(
+            locking(&self.process)
+                .map_err(|_| failure::err_msg("could not acquire the external plugin mutex"))
+                .and_then(move |mut guard| {
+                    if guard.is_some() {
+                        return Either::A(futures::future::ok(()));
+                    }
+                    let process = match ExternalProcess::spawn(&command) {
+                        Ok(process) => process,
+                        Err(e) => return Either::A(futures::future::err(e)),
+                    };
+                    let setup = setup.lock().unwrap().clone();
+
+                    Either::B(
+                        process
+                            .replay(setup)
+                            .map_err(|(e, _)| e)
+                            .map(move |process| {
+                                *guard = Some(process);
+                            }),
+                    )
+                }),
+        )
+    }
+
+    /// Asks the process whether it is able to handle requests.
+    pub fn health_check(self: &Self) -> FutureIO<'static, ()> {
+        Box::new(
+            self.exchange(ExternalRequest::Health)
+                .and_then(|response| match response.error {
+                    Some(error) => Err(format_err!("external plugin is unhealthy: {}", error)),
+                    None => Ok(()),
+                }),
+        )
+    }
+
+    /// Asks the process to exit and closes its stdin, killing it if it doesn't
+    /// exit within the shutdown timeout.
+    pub fn shutdown(self: &Self) -> FutureIO<'static, ()> {
+        let shutdown_timeout = self.shutdown_timeout;
+        self.setup.lock().unwrap().clear();
+
+        Box::new(
+            locking(&self.process)
+                .map_err(|_| failure::err_msg("could not acquire the external plugin mutex"))
+                .and_then(move |mut guard| match guard.take() {
+                    None => Either::A(futures::future::ok(())),
+                    Some(process) => {
+                        let ExternalProcess {
+                            child, requests, ..
+                        } = process;
+
+                        let line = serde_json::to_string(&ExternalRequest::Shutdown)
+                            .expect("shutdown request serializes");
+
+                        Either::B(
+                            requests
+                                .send(line)
+                                .then(move |_| {
+                                    // dropping the sink closes the process' stdin
+                                    tokio::timer::Timeout::new_at(
+                                        child,
+                                        Instant::now() + shutdown_timeout,
+                                    )
+                                })
+                                .then(|result| match result {
+                                    Ok(_) => Ok(()),
+                                    // dropping the child kills the process
+                                    Err(ref e) if e.is_elapsed() => Ok(()),
+                                    Err(e) => Err(format_err!(
+                                        "could not wait for external plugin: {}",
+                                        e
+                                    )),
+                                }),
+                        )
+                    }
+                }),
+        )
+    }
+
+    fn exchange(self: &Self, request: ExternalRequest) -> FutureIO<'static, ExternalResponse> {
+        let line = match serde_json::to_string(&request) {
+            Ok(line) => line,
+            Err(e) => return Box::new(futures::future::err(e.into())),
+        };
+        let command = self.command.clone();
+        let max_restarts = self.max_restarts;
+        let idempotent = self.idempotent;
+        let setup = self.setup.clone();
+
+        Box::new(
+            locking(&self.process)
+                .map_err(|_| failure::err_msg("could not acquire the external plugin mutex"))
+                .and_then(move |guard| {
+                    futures::future::loop_fn((guard, 0), move |(mut guard, restarts)| {
+                        let (process, setup) = match guard.take() {
+                            Some(process) => (process, Vec::new()),
+                            None => match ExternalProcess::spawn(&command) {
+                                Ok(process) => (process, setup.lock().unwrap().clone()),
+                                Err(e) => return Either::A(futures::future::err(e)),
+                            },
+                        };
+                        let line = line.clone();
+
+                        Either::B(
+                            process
+                                .replay(setup)
+                                .and_then(move |process| process.exchange(line))
+                                .then(move |result| match result {
+                                    Ok((process, response)) => {
+                                        *guard = Some(process);
+                                        Ok(Loop::Break(response))
+                                    }
+                                    Err((ref e, sent))
+                                        if restarts < max_restarts
+                                            && (idempotent || !sent)
+                                            && !ExternalProcessError::is_permanent(e) =>
+                                    {
+                                        println!("[] external plugin failed: {}, restarting", e);
+                                        Ok(Loop::Continue((guard, restarts + 1)))
+                                    }
+                                    Err((e, _)) => Err(e),
+                                }),
+                        )
+                    })
+                })
+                .and_then(|line| {
+                    serde_json::from_str::<ExternalResponse>(&line)
+                        .with_context(|_| {
+                            format!("invalid response from external plugin: {}", line)
+                        })
+                        .map_err(Error::from)
+                }),
+        )
+    }
+}
+
+impl Plugin<String> for ExternalPlugin {
+    fn run(self: &Self, plugin_io: String) -> FutureIO<'static, String> {
+        Box::new(
+            self.exchange(ExternalRequest::Run { payload: plugin_io })
+                .and_then(|response| match (response.payload, response.error) {
+                    (_, Some(error)) => Err(format_err!("external plugin failed: {}", error)),
+                    (Some(payload), None) => Ok(payload),
+                    (None, None) => Err(failure::err_msg("external plugin returned no payload")),
+                }),
+        )
+    }
+
+    fn kind(self: &Self) -> StageKind {
+        StageKind::External
+    }
+
+    /// Spawns the process and sends it the config.
+    fn init(self: &Self, config: &PluginConfig) -> FutureIO<'static, ()> {
+        let request = ExternalRequest::Init {
+            config: config.clone(),
+        };
+        let setup = self.setup.clone();
+
+        Box::new(
+            self.exchange(request.clone())
+                .and_then(move |response| match response.error {
+                    Some(error) => Err(format_err!(
+                        "external plugin failed to initialize: {}",
+                        error
+                    )),
+                    None => {
+                        *setup.lock().unwrap() = vec![request];
+                        Ok(())
+                    }
+                }),
+        )
+    }
+
+    fn warmup(self: &Self) -> FutureIO<'static, ()> {
+        let setup = self.setup.clone();
+
+        Box::new(
+            self.exchange(ExternalRequest::Warmup)
+                .and_then(move |response| match response.error {
+                    Some(error) => Err(format_err!("external plugin failed to warm up: {}", error)),
+                    None => {
+                        let mut setup = setup.lock().unwrap();
+                        if !setup
+                            .iter()
+                            .any(|request| matches!(request, ExternalRequest::Warmup))
+                        {
+                            setup.push(ExternalRequest::Warmup);
+                        }
+                        Ok(())
+                    }
+                }),
+        )
+    }
+
+    fn health(self: &Self) -> FutureIO<'static, ()> {
+        self.health_check()
+    }
+
+    fn shutdown(self: &Self) -> FutureIO<'static, ()> {
+        ExternalPlugin::shutdown(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::AppendingPlugin;
+    use crate::plugins::{InternalPluginWrapper, PluginNode, PluginProcessor};
+
+    #[test]
+    fn process_internal_and_external_plugins() {
+        // `cat` echoes every request, which is a valid response carrying the same payload
+        let external = ExternalPlugin::new("cat", &[]);
+
+        let plugin_processor = PluginProcessor::new(vec![
+            PluginNode::new(
+                "internal",
+                Box::new(InternalPluginWrapper::new(AppendingPlugin::new("a"))),
+                &[],
+            ),
+            PluginNode::new("external", Box::new(external), &["internal"]),
+        ])
+        .expect("invalid plugin graph");
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        // init and health requests are echoed back without an error, too
+        runtime
+            .block_on(plugin_processor.start())
+            .expect("plugins failed to start");
+
+        for _ in 0..3 {
+            let result = runtime
+                .block_on(plugin_processor.process("_".to_string()))
+                .expect("plugin processing failed");
+            assert_eq!(result, "_a");
+        }
+
+        runtime
+            .block_on(plugin_processor.shutdown())
+            .expect("shutdown failed");
+    }
+
+    #[test]
+    fn restart_crashed_external_plugin() {
+        // answers a single request and exits
+        let external = ExternalPlugin::new("sh", &["-c", "read line; echo \"$line\""])
+            .with_idempotent_requests();
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        for input in &["first", "second", "third"] {
+            let result = runtime
+                .block_on(external.run(input.to_string()))
+                .expect("external plugin was not restarted");
+            assert_eq!(&result, input);
+        }
+
+        let never_restarted = ExternalPlugin::new("sh", &["-c", "read line; echo \"$line\""])
+            .with_idempotent_requests()
+            .with_max_restarts(0);
+        runtime
+            .block_on(never_restarted.run("first".to_string()))
+            .expect("first run failed");
+        assert!(runtime
+            .block_on(never_restarted.run("second".to_string()))
+            .is_err());
+
+        // a request which reached the crashed process is not sent again
+        let log = std::env::temp_dir().join(format!(
+            "mutating_futures_external_{}.log",
+            std::process::id()
+        ));
+        let script = format!("read line; echo \"$line\" >> {}; exit 3", log.display());
+        let crashing = ExternalPlugin::new("sh", &["-c", &script]);
+        assert!(runtime.block_on(crashing.run("once".to_string())).is_err());
+        let handled = std::fs::read_to_string(&log).expect("request was not handled");
+        std::fs::remove_file(&log).expect("could not remove the log");
+        assert_eq!(handled.lines().count(), 1);
+
+        // keeps the suffix of its config and whether it was warmed up, and exits after
+        // each run
+        let script = r#"while read line; do
+            case "$line" in
+                *'"kind":"init"'*)
+                    suffix=$(echo "$line" | sed 's/.*"suffix":"\([^"]*\)".*/\1/')
+                    echo '{}' ;;
+                *'"kind":"warmup"'*) warm=" warm"; echo '{}' ;;
+                *'"kind":"run"'*)
+                    payload=$(echo "$line" | sed 's/.*"payload":"\([^"]*\)".*/\1/')
+                    echo "{\"payload\": \"$payload$suffix$warm\"}"
+                    exit 3 ;;
+            esac
+        done"#;
+        let configured = ExternalPlugin::new("sh", &["-c", script]).with_idempotent_requests();
+        runtime
+            .block_on(configured.init(&serde_json::json!({ "suffix": "!" })))
+            .expect("init failed");
+        assert_eq!(
+            runtime
+                .block_on(configured.run("first".to_string()))
+                .expect("first run failed"),
+            "first!"
+        );
+        runtime
+            .block_on(Plugin::warmup(&configured))
+            .expect("warmup failed");
+        for input in &["second", "third"] {
+            assert_eq!(
+                runtime
+                    .block_on(configured.run(input.to_string()))
+                    .expect("restarted plugin was not set up again"),
+                format!("{}! warm", input)
+            );
+        }
+
+        let hanging = ExternalPlugin::new("sleep", &["10"])
+            .with_request_timeout(Some(Duration::from_millis(100)));
+        match runtime
+            .block_on(hanging.run("".to_string()))
+            .map_err(|e| e.downcast::<ExternalProcessError>())
+        {
+            Err(Ok(ExternalProcessError::TimedOut { .. })) => (),
+            other => panic!("expected a timeout, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn contain_external_plugins() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let limits = ResourceLimits::default()
+            .with_memory_limit(512 << 20)
+            .with_open_files_limit(16);
+
+        // reports its limits
+        let reporting = ExternalPlugin::new(
+            "sh",
+            &[
+                "-c",
+                "read line; echo \"{\\\"payload\\\": \\\"$(ulimit -n) $(ulimit -v)\\\"}\"",
+            ],
+        )
+        .with_limits(limits);
+        assert_eq!(
+            runtime
+                .block_on(reporting.run("".to_string()))
+                .expect("limits were not applied"),
+            "16 524288"
+        );
+
+        // reports the names of its network interfaces
+        let isolated = ExternalPlugin::new(
+            "sh",
+            &[
+                "-c",
+                "read line; echo \"{\\\"payload\\\": \\\"$(tail -n +3 /proc/net/dev | cut -d: -f1 | tr -d ' \\n')\\\"}\"",
+            ],
+        )
+        .with_max_restarts(0)
+        .with_limits(ResourceLimits::default().with_isolated_network());
+        match runtime.block_on(isolated.run("".to_string())) {
+            Ok(interfaces) => assert_eq!(interfaces, "lo"),
+            // without unprivileged user namespaces there is no isolation to check
+            Err(e)
+                if e.iter_chain().any(|cause| {
+                    cause
+                        .downcast_ref::<std::io::Error>()
+                        .map(std::io::Error::kind)
+                        == Some(std::io::ErrorKind::PermissionDenied)
+                }) =>
+            {
+                println!("[] skipped the network isolation check: {}", e)
+            }
+            Err(e) => panic!("network was not isolated: {}", e),
+        }
+
+        let run = |plugin: ExternalPlugin, runtime: &mut tokio::runtime::Runtime| {
+            runtime
+                .block_on(plugin.with_max_restarts(0).run("".to_string()))
+                .expect_err("external plugin should fail")
+                .downcast::<ExternalProcessError>()
+                .expect("error is not an ExternalProcessError")
+        };
+
+        let spinning = ExternalPlugin::new("sh", &["-c", "while :; do :; done"])
+            .with_limits(ResourceLimits::default().with_cpu_time_limit(Duration::from_millis(500)));
+        match run(spinning, &mut runtime) {
+            ExternalProcessError::CpuTimeExceeded { .. } => (),
+            other => panic!("expected an exceeded CPU time, got {}", other),
+        }
+
+        let sleeping = ExternalPlugin::new("sleep", &["10"]).with_limits(
+            ResourceLimits::default().with_wall_clock_limit(Duration::from_millis(100)),
+        );
+        match run(sleeping, &mut runtime) {
+            ExternalProcessError::WallClockExceeded { .. } => (),
+            other => panic!("expected an exceeded wall clock, got {}", other),
+        }
+
+        // dash segfaults when it can't allocate
+        let allocating = ExternalPlugin::new(
+            "sh",
+            &[
+                "-c",
+                "read line; x=$(head -c 200000000 /dev/zero | tr '\\0' a)",
+            ],
+        )
+        .with_limits(ResourceLimits::default().with_memory_limit(64 << 20));
+        match run(allocating, &mut runtime) {
+            ExternalProcessError::MemoryExceeded { limit, .. } => assert_eq!(limit, 64 << 20),
+            other => panic!("expected an exceeded memory limit, got {}", other),
+        }
+
+        let opening = ExternalPlugin::new(
+            "sh",
+            &[
+                "-c",
+                "read line; exec 3</dev/null 4</dev/null 5</dev/null 6</dev/null 7</dev/null 8</dev/null",
+            ],
+        )
+        .with_limits(ResourceLimits::default().with_open_files_limit(8));
+        match run(opening, &mut runtime) {
+            ExternalProcessError::OpenFilesExceeded { limit, .. } => assert_eq!(limit, 8),
+            other => panic!("expected an exceeded open files limit, got {}", other),
+        }
+
+        let crashing = ExternalPlugin::new("sh", &["-c", "read line; exit 3"]);
+        match run(crashing, &mut runtime) {
+            ExternalProcessError::Crashed { status, .. } => assert_eq!(status.code(), Some(3)),
+            other => panic!("expected a crash, got {}", other),
+        }
+    }
+}
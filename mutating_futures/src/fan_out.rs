@@ -0,0 +1,341 @@
+use crate::wrapped::{
+    with_each_locked, with_locked, AsyncResult, AsyncWorker, Compensation, ServedRuns, Work,
+    WorkCollection, WorkIO,
+};
+use failure::{Error, Fallible};
+use futures::Future;
+use futures_locks::Mutex as FuturesMutex;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Combines the results of all workers, in the order of the workers
+pub type ReduceFn = Arc<dyn Fn(Vec<Result<WorkIO, Error>>) -> Fallible<WorkIO> + Send + Sync>;
+
+/// How a FanOutWorker combines the outputs of its workers
+#[derive(Clone)]
+pub enum Reducer {
+    /// Joins all outputs with the separator, fails as soon as any worker fails
+    Concatenate {
+        separator: String,
+    },
+    /// Returns the first output to arrive, fails only if every worker fails
+    FirstSuccess,
+    /// Returns the output which more than half of the workers agree on
+    MajorityVote,
+    Custom(ReduceFn),
+}
+
+impl Reducer {
+    pub fn concatenate(separator: &str) -> Self {
+        Reducer::Concatenate {
+            separator: separator.to_string(),
+        }
+    }
+
+    pub fn custom<F>(reduce: F) -> Self
+    where
+        F: Fn(Vec<Result<WorkIO, Error>>) -> Fallible<WorkIO> + Send + Sync + 'static,
+    {
+        Reducer::Custom(Arc::new(reduce))
+    }
+}
+
+#[derive(Debug, Fail)]
+pub enum FanOutError {
+    #[fail(display = "fan-out stage has no workers")]
+    NoWorkers,
+    /// Caused by the error of the worker which failed last
+    #[fail(display = "all {} workers failed", workers)]
+    AllFailed {
+        workers: usize,
+        #[cause]
+        last: Error,
+    },
+    #[fail(
+        display = "no output has a majority, the most common one was returned by {} of {} workers",
+        votes, workers
+    )]
+    NoMajority { votes: usize, workers: usize },
+}
+
+/// AsyncWorker which sends each input to all of its workers at the same time and
+/// reduces their outputs to one.
+pub struct FanOutWorker {
+    workers: WorkCollection,
+    reducer: Reducer,
+    /// The output of each worker, if it had one, for the compensation
+    served: ServedRuns<Vec<Option<WorkIO>>>,
+}
+
+impl FanOutWorker {
+    pub fn new(workers: Vec<Box<dyn AsyncWorker<WorkIO>>>, reducer: Reducer) -> Self {
+        FanOutWorker {
+            workers: workers
+                .into_iter()
+                .map(|worker| Arc::new(FuturesMutex::new(worker)))
+                .collect(),
+            reducer,
+            served: ServedRuns::default(),
+        }
+    }
+
+    /// Creates a fan-out stage from workers which may also be used elsewhere.
+    pub fn from_work(workers: WorkCollection, reducer: Reducer) -> Self {
+        FanOutWorker {
+            workers,
+            reducer,
+            served: ServedRuns::default(),
+        }
+    }
+}
+
+/// Returns the output of more than half of `workers` results, if there is one.
+fn majority(results: Vec<Result<WorkIO, Error>>) -> Fallible<WorkIO> {
+    let workers = results.len();
+    let mut votes: HashMap<WorkIO, usize> = HashMap::new();
+    let mut last = None;
+    for result in results {
+        match result {
+            Ok(output) => *votes.entry(output).or_insert(0) += 1,
+            Err(e) => last = Some(e),
+        }
+    }
+
+    match (votes.into_iter().max_by_key(|(_, votes)| *votes), last) {
+        (Some((output, votes)), _) if votes * 2 > workers => Ok(output),
+        (Some((_, votes)), _) => Err(FanOutError::NoMajority { votes, workers }.into()),
+        (None, Some(last)) => Err(FanOutError::AllFailed { workers, last }.into()),
+        (None, None) => Err(FanOutError::NoWorkers.into()),
+    }
+}
+
+impl AsyncWorker<WorkIO> for FanOutWorker {
+    fn init(self: &mut Self, config: &serde_json::Value) -> AsyncResult<()> {
+        let config = config.clone();
+        with_each_locked(self.workers.iter(), move |worker| worker.init(&config))
+    }
+
+    fn warmup(self: &mut Self) -> AsyncResult<()> {
+        with_each_locked(self.workers.iter(), |worker| worker.warmup())
+    }
+
+    fn health(self: &mut Self) -> AsyncResult<()> {
+        with_each_locked(self.workers.iter(), |worker| worker.health())
+    }
+
+    fn shutdown(self: &mut Self) -> AsyncResult<()> {
+        with_each_locked(self.workers.iter(), |worker| worker.shutdown())
+    }
+
+    /// Undoes the runs of all workers which had an output and have something to undo,
+    /// concurrently. Each worker is asked with its own output.
+    fn compensation(
+        self: &mut Self,
+        input: &WorkIO,
+        output: &WorkIO,
+    ) -> AsyncResult<Option<Compensation>> {
+        let outputs = match self.served.take(input, output) {
+            Ok(outputs) => outputs,
+            Err(e) => return Box::new(futures::future::err(e.into())),
+        };
+        let compensations: Vec<_> = self
+            .workers
+            .iter()
+            .zip(outputs)
+            .filter_map(|(worker, output)| {
+                let input = input.clone();
+                output.map(|output| {
+                    with_locked(worker, move |worker| worker.compensation(&input, &output))
+                })
+            })
+            .collect();
+
+        Box::new(
+            futures::future::join_all(compensations).map(|compensations| {
+                let compensations: Vec<Compensation> =
+                    compensations.into_iter().flatten().collect();
+                if compensations.is_empty() {
+                    return None;
+                }
+
+                let compensate: Compensation = Box::new(move || {
+                    let undone: Vec<_> = compensations
+                        .into_iter()
+                        .map(|compensation| compensation())
+                        .collect();
+                    Box::new(futures::future::join_all(undone).map(|_| ()))
+                });
+                Some(compensate)
+            }),
+        )
+    }
+
+    fn run(self: &mut Self, input: WorkIO) -> AsyncResult<WorkIO> {
+        if self.workers.is_empty() {
+            return Box::new(futures::future::err(FanOutError::NoWorkers.into()));
+        }
+
+        let workers = self.workers.len();
+        let outputs = Arc::new(Mutex::new(vec![None; workers]));
+        let runs: Vec<_> = self
+            .workers
+            .iter()
+            .enumerate()
+            .map(|(index, worker)| {
+                let input = input.clone();
+                let outputs = outputs.clone();
+                with_locked(worker, move |worker| worker.run(input)).then(move |result| {
+                    if let Ok(ref output) = result {
+                        outputs.lock().unwrap()[index] = Some(output.clone());
+                    }
+                    result
+                })
+            })
+            .collect();
+        println!("[] fanning input {} out to {} workers", input, workers);
+
+        let reduced: AsyncResult<WorkIO> = match self.reducer {
+            Reducer::Concatenate { ref separator } => {
+                let separator = separator.clone();
+                Box::new(
+                    futures::future::join_all(runs).map(move |outputs| outputs.join(&separator)),
+                )
+            }
+            Reducer::FirstSuccess => Box::new(
+                futures::future::select_ok(runs)
+                    .map(|(output, _)| output)
+                    .map_err(move |last| FanOutError::AllFailed { workers, last }.into()),
+            ),
+            Reducer::MajorityVote => Box::new(
+                futures::future::join_all(runs.into_iter().map(|run| run.then(Ok::<_, Error>)))
+                    .and_then(majority),
+            ),
+            Reducer::Custom(ref reduce) => {
+                let reduce = reduce.clone();
+                Box::new(
+                    futures::future::join_all(runs.into_iter().map(|run| run.then(Ok::<_, Error>)))
+                        .and_then(move |results| reduce(results)),
+                )
+            }
+        };
+
+        let served = self.served.clone();
+        Box::new(reduced.map(move |output| {
+            let outputs = std::mem::take(&mut *outputs.lock().unwrap());
+            served.record(input, output.clone(), outputs);
+            output
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wrapped::{process, AsyncWorkerInternal, InternalWorkWrapper};
+    use std::time::{Duration, Instant};
+
+    /// Answers with its output after its delay, or fails without an output
+    #[derive(Clone)]
+    struct InternalEnricher {
+        output: Option<&'static str>,
+        delay: Duration,
+    }
+    impl AsyncWorkerInternal<WorkIO> for InternalEnricher {
+        fn run_internal(self: &mut Self, input: WorkIO) -> AsyncResult<WorkIO> {
+            let output = self.output;
+            Box::new(
+                tokio::timer::Delay::new(Instant::now() + self.delay)
+                    .map_err(Error::from)
+                    .and_then(move |_| match output {
+                        Some(output) => Ok(format!("{}{}", input, output)),
+                        None => Err(failure::err_msg("enricher failed")),
+                    }),
+            )
+        }
+    }
+
+    fn enrichers(outputs: &[(Option<&'static str>, u64)]) -> Vec<Box<dyn AsyncWorker<WorkIO>>> {
+        outputs
+            .iter()
+            .map(|(output, delay)| {
+                Box::new(InternalWorkWrapper(InternalEnricher {
+                    output: *output,
+                    delay: Duration::from_millis(*delay),
+                })) as Box<dyn AsyncWorker<WorkIO>>
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_process_with_fan_out() -> Fallible<()> {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut run = |outputs: &[(Option<&'static str>, u64)], reducer| {
+            let stage: Work = Arc::new(FuturesMutex::new(Box::new(FanOutWorker::new(
+                enrichers(outputs),
+                reducer,
+            ))));
+            runtime.block_on(process(vec![stage].into_iter()))
+        };
+
+        assert_eq!(
+            run(
+                &[(Some("a"), 20), (Some("b"), 0)],
+                Reducer::concatenate(",")
+            )?,
+            "a,b"
+        );
+        assert!(run(&[(Some("a"), 0), (None, 0)], Reducer::concatenate(",")).is_err());
+
+        assert_eq!(
+            run(
+                &[(None, 0), (Some("slow"), 50), (Some("fast"), 10)],
+                Reducer::FirstSuccess
+            )?,
+            "fast"
+        );
+        // both reducers fail the same way when every worker fails
+        for reducer in [Reducer::FirstSuccess, Reducer::MajorityVote] {
+            let error = run(&[(None, 0), (None, 0)], reducer).unwrap_err();
+            let chain: Vec<String> = error.iter_chain().map(|e| e.to_string()).collect();
+            assert_eq!(chain, vec!["all 2 workers failed", "enricher failed"]);
+            match error.downcast::<FanOutError>() {
+                Ok(FanOutError::AllFailed { workers: 2, .. }) => (),
+                other => panic!("expected all workers to fail, got {:?}", other),
+            }
+        }
+
+        assert_eq!(
+            run(
+                &[(Some("x"), 0), (Some("y"), 0), (Some("x"), 0)],
+                Reducer::MajorityVote
+            )?,
+            "x"
+        );
+        match run(
+            &[(Some("x"), 0), (Some("y"), 0), (None, 0)],
+            Reducer::MajorityVote,
+        )
+        .map_err(|e| e.downcast::<FanOutError>())
+        {
+            Err(Ok(FanOutError::NoMajority {
+                votes: 1,
+                workers: 3,
+            })) => (),
+            other => panic!("expected no majority, got {:?}", other.map(|_| ())),
+        }
+
+        let successes = Reducer::custom(|results| {
+            Ok(results
+                .iter()
+                .filter(|result| result.is_ok())
+                .count()
+                .to_string())
+        });
+        assert_eq!(
+            run(&[(Some("a"), 0), (None, 0), (Some("c"), 0)], successes)?,
+            "2"
+        );
+
+        Ok(())
+    }
+}
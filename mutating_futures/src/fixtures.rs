@@ -0,0 +1,74 @@
+use crate::plugins::{FutureIO, InternalPlugin};
+use crate::wrapped::{AsyncResult, AsyncWorkerExternal, AsyncWorkerInternal, WorkIO};
+use failure::Error;
+use futures::Future;
+use serde::Deserialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The counting forwarders of the `wrapped` tests
+pub(crate) use crate::wrapped::tests::{ExternalCountingForwarder, InternalCountingForwarder};
+
+/// Appends how often it ran after a delay, and panics on "panic"
+#[derive(Clone)]
+pub struct InternalSlowCounter {
+    pub runs: Arc<AtomicUsize>,
+    pub delay: Duration,
+}
+
+impl InternalSlowCounter {
+    pub fn new(delay: Duration) -> Self {
+        InternalSlowCounter {
+            runs: Arc::new(AtomicUsize::new(0)),
+            delay,
+        }
+    }
+}
+
+impl AsyncWorkerInternal<WorkIO> for InternalSlowCounter {
+    fn run_internal(self: &mut Self, input: WorkIO) -> AsyncResult<WorkIO> {
+        if input == "panic" {
+            panic!("counter got {}", input);
+        }
+        let runs = self.runs.fetch_add(1, Ordering::SeqCst) + 1;
+
+        Box::new(
+            tokio::timer::Delay::new(Instant::now() + self.delay)
+                .map_err(Error::from)
+                .map(move |_| format!("{}{}", input, runs)),
+        )
+    }
+}
+
+/// Appends its suffix, as a plugin and as either kind of worker
+#[derive(Debug, Clone, Deserialize)]
+pub struct AppendingPlugin {
+    pub suffix: String,
+}
+
+impl AppendingPlugin {
+    pub fn new(suffix: &str) -> Self {
+        AppendingPlugin {
+            suffix: suffix.to_string(),
+        }
+    }
+}
+
+impl InternalPlugin for AppendingPlugin {
+    fn run_internal(self: &mut Self, io: String) -> FutureIO<'static, String> {
+        Box::new(futures::future::ok(format!("{}{}", io, self.suffix)))
+    }
+}
+
+impl AsyncWorkerInternal<WorkIO> for AppendingPlugin {
+    fn run_internal(self: &mut Self, input: WorkIO) -> AsyncResult<WorkIO> {
+        Box::new(futures::future::ok(format!("{}{}", input, self.suffix)))
+    }
+}
+
+impl AsyncWorkerExternal<WorkIO> for AppendingPlugin {
+    fn run_external(self: &mut Self, input: WorkIO) -> AsyncResult<WorkIO> {
+        Box::new(futures::future::ok(format!("{}{}", input, self.suffix)))
+    }
+}
@@ -0,0 +1,209 @@
+use crate::plugins::{FutureIO, LifecycleError, LifecycleState, PluginProcessor};
+use actix_web::http::StatusCode;
+use actix_web::{web, App, HttpResponse, HttpServer};
+use failure::Error;
+use futures::Future;
+use futures03::compat::Future01CompatExt;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use tokio::runtime::TaskExecutor;
+
+/// Body of every error response
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    error: String,
+    /// The causes of `error`, outermost first
+    causes: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct HealthBody {
+    state: String,
+    /// `None` for healthy plugins, the reason otherwise
+    plugins: BTreeMap<String, Option<String>>,
+}
+
+fn error_response(error: &Error) -> HttpResponse {
+    let status = match error.downcast_ref::<LifecycleError>() {
+        Some(LifecycleError::NotReady(_)) | Some(LifecycleError::Unhealthy { .. }) => {
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+
+    HttpResponse::build(status).json(ErrorBody {
+        error: error.to_string(),
+        causes: error.iter_causes().map(|cause| cause.to_string()).collect(),
+    })
+}
+
+/// Serves a PluginProcessor over HTTP.
+///
+/// - `POST /process` runs the processor with the request body as input and responds
+///   with its output
+/// - `GET /pipeline` responds with the description of the plugin graph
+/// - `GET /health` health checks every plugin and responds with 503 unless all of
+///   them are healthy and the processor is ready
+///
+/// Failures are responded to with an `ErrorBody` as JSON. The plugins keep running on
+/// the tokio runtime of `executor`, as the server has a runtime of its own.
+#[derive(Clone)]
+pub struct HttpService {
+    processor: PluginProcessor,
+    executor: TaskExecutor,
+}
+
+impl HttpService {
+    pub fn new(processor: PluginProcessor, executor: TaskExecutor) -> Self {
+        HttpService {
+            processor,
+            executor,
+        }
+    }
+
+    /// Runs `future` on the plugins' runtime and waits for it on the server's.
+    async fn run_on_executor<T>(self: &Self, future: FutureIO<'static, T>) -> Result<T, Error>
+    where
+        T: Send + 'static,
+    {
+        futures::sync::oneshot::spawn(future, &self.executor)
+            .compat()
+            .await
+    }
+
+    async fn process(service: web::Data<HttpService>, body: String) -> HttpResponse {
+        match service
+            .run_on_executor(service.processor.process(body))
+            .await
+        {
+            Ok(output) => HttpResponse::Ok().content_type("text/plain").body(output),
+            Err(e) => error_response(&e),
+        }
+    }
+
+    async fn pipeline(service: web::Data<HttpService>) -> HttpResponse {
+        HttpResponse::Ok().json(service.processor.describe())
+    }
+
+    async fn health(service: web::Data<HttpService>) -> HttpResponse {
+        let checks: Vec<_> = service
+            .processor
+            .nodes()
+            .iter()
+            .map(|node| {
+                let name = node.name.clone();
+                node.plugin
+                    .health()
+                    .then(move |result| Ok::<_, Error>((name, result.err().map(|e| e.to_string()))))
+            })
+            .collect();
+
+        let plugins = match service
+            .run_on_executor(Box::new(futures::future::join_all(checks)))
+            .await
+        {
+            Ok(plugins) => plugins.into_iter().collect::<BTreeMap<_, _>>(),
+            Err(e) => return error_response(&e),
+        };
+
+        let state = service.processor.state();
+        let status = if state == LifecycleState::Ready && plugins.values().all(Option::is_none) {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+
+        HttpResponse::build(status).json(HealthBody {
+            state: format!("{:?}", state).to_lowercase(),
+            plugins,
+        })
+    }
+
+    /// Adds the routes to an actix-web App, e.g. to serve them next to others.
+    pub fn configure(self: &Self, config: &mut web::ServiceConfig) {
+        config
+            .app_data(web::Data::new(self.clone()))
+            .route("/process", web::post().to(Self::process))
+            .route("/pipeline", web::get().to(Self::pipeline))
+            .route("/health", web::get().to(Self::health));
+    }
+
+    /// Serves the routes on `address` until the server is stopped.
+    pub fn run(self: Self, address: &str) -> std::io::Result<()> {
+        println!("[] serving plugin processor on {}", address);
+        let server = HttpServer::new(move || {
+            let service = self.clone();
+            App::new().configure(move |config| service.configure(config))
+        })
+        .bind(address)?;
+
+        actix_web::rt::System::new().block_on(server.run())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugins::{InternalPlugin, InternalPluginWrapper, PluginNode};
+    use actix_web::test;
+
+    #[derive(Debug)]
+    struct Appender(&'static str);
+
+    impl InternalPlugin for Appender {
+        fn run_internal(self: &mut Self, io: String) -> FutureIO<'static, String> {
+            Box::new(futures::future::ok(format!("{}{}", io, self.0)))
+        }
+    }
+
+    #[test]
+    fn serve_plugin_processor() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let processor = PluginProcessor::new(vec![
+            PluginNode::new(
+                "a",
+                Box::new(InternalPluginWrapper::new(Appender("a"))),
+                &[],
+            ),
+            PluginNode::new(
+                "b",
+                Box::new(InternalPluginWrapper::new(Appender("b"))),
+                &["a"],
+            ),
+        ])
+        .expect("invalid plugin graph");
+        let service = HttpService::new(processor.clone(), runtime.executor());
+
+        actix_web::rt::System::new().block_on(async move {
+            let app =
+                test::init_service(App::new().configure(|config| service.configure(config))).await;
+            let process = || test::TestRequest::post().uri("/process").set_payload("x");
+
+            // runs are refused until the processor is started
+            let response = test::call_service(&app, process().to_request()).await;
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+            let body: serde_json::Value = test::read_body_json(response).await;
+            assert_eq!(
+                body["error"],
+                "plugin processor is not ready, it is Created"
+            );
+
+            runtime.block_on(processor.start()).expect("start failed");
+            let response = test::call_service(&app, process().to_request()).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(test::read_body(response).await, "xab");
+
+            let request = test::TestRequest::get().uri("/health").to_request();
+            let body: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+            assert_eq!(
+                body,
+                serde_json::json!({"state": "ready", "plugins": {"a": null, "b": null}})
+            );
+
+            let request = test::TestRequest::get().uri("/pipeline").to_request();
+            let body: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+            assert_eq!(body["stages"][1]["kind"], "internal");
+            assert_eq!(body["edges"][0]["from"], "a");
+        });
+    }
+}
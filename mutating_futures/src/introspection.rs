@@ -0,0 +1,286 @@
+use crate::plugins::PluginProcessor;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StageKind {
+    Internal,
+    External,
+    /// Sends items to one of several sub-pipelines
+    Routing,
+}
+
+impl StageKind {
+    fn as_str(self: &Self) -> &'static str {
+        match self {
+            StageKind::Internal => "internal",
+            StageKind::External => "external",
+            StageKind::Routing => "routing",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StageDescription {
+    pub name: String,
+    pub kind: StageKind,
+    /// IO type the stage consumes
+    pub input: String,
+    /// IO type the stage produces
+    pub output: String,
+}
+
+/// Flow of items from one stage to another
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EdgeDescription {
+    pub from: String,
+    pub to: String,
+    /// What is done to the items on their way, if anything
+    pub conversion: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PipelineDescription {
+    pub stages: Vec<StageDescription>,
+    pub edges: Vec<EdgeDescription>,
+}
+
+impl PipelineDescription {
+    /// Describes stages which run one after another, inserting conversions where the
+    /// output of a stage doesn't match the input of the next one.
+    pub fn chain(stages: Vec<StageDescription>) -> Self {
+        let edges = stages
+            .windows(2)
+            .map(|pair| EdgeDescription {
+                from: pair[0].name.clone(),
+                to: pair[1].name.clone(),
+                conversion: if pair[0].output == pair[1].input {
+                    None
+                } else {
+                    Some(format!("{} -> {}", pair[0].output, pair[1].input))
+                },
+            })
+            .collect();
+
+        PipelineDescription { stages, edges }
+    }
+
+    fn stage_label(stage: &StageDescription, line_break: &str) -> String {
+        let io = if stage.input == stage.output {
+            stage.input.clone()
+        } else {
+            format!("{} -> {}", stage.input, stage.output)
+        };
+        format!(
+            "{}{}{}: {}",
+            stage.name,
+            line_break,
+            stage.kind.as_str(),
+            io
+        )
+    }
+
+    /// Renders the pipeline as a Graphviz digraph.
+    pub fn to_dot(self: &Self) -> String {
+        let escape = |text: &str| text.replace('\\', "\\\\").replace('"', "\\\"");
+        let quote = |text: &str| format!("\"{}\"", escape(text));
+
+        let mut dot = String::from("digraph pipeline {\n    rankdir=LR;\n");
+        for stage in &self.stages {
+            // the parts are escaped on their own, so the line break stays one
+            let escaped = StageDescription {
+                name: escape(&stage.name),
+                kind: stage.kind,
+                input: escape(&stage.input),
+                output: escape(&stage.output),
+            };
+            dot.push_str(&format!(
+                "    {} [label=\"{}\", shape={}];\n",
+                quote(&stage.name),
+                Self::stage_label(&escaped, "\\n"),
+                if stage.kind == StageKind::Routing {
+                    "diamond"
+                } else {
+                    "box"
+                }
+            ));
+        }
+        for edge in &self.edges {
+            dot.push_str(&format!("    {} -> {}", quote(&edge.from), quote(&edge.to)));
+            if let Some(ref conversion) = edge.conversion {
+                dot.push_str(&format!(" [label={}]", quote(conversion)));
+            }
+            dot.push_str(";\n");
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Renders the pipeline as a Mermaid flowchart.
+    pub fn to_mermaid(self: &Self) -> String {
+        let id = |name: &str| {
+            self.stages
+                .iter()
+                .position(|stage| stage.name == name)
+                .map_or_else(|| name.to_string(), |index| format!("s{}", index))
+        };
+        let escape = |text: &str| text.replace('"', "#quot;");
+
+        let mut mermaid = String::from("flowchart LR\n");
+        for (index, stage) in self.stages.iter().enumerate() {
+            let label = escape(&Self::stage_label(stage, "<br/>"));
+            mermaid.push_str(&match stage.kind {
+                StageKind::Routing => format!("    s{}{{\"{}\"}}\n", index, label),
+                _ => format!("    s{}[\"{}\"]\n", index, label),
+            });
+        }
+        for edge in &self.edges {
+            match edge.conversion {
+                Some(ref conversion) => mermaid.push_str(&format!(
+                    "    {} -->|\"{}\"| {}\n",
+                    id(&edge.from),
+                    escape(conversion),
+                    id(&edge.to)
+                )),
+                None => mermaid.push_str(&format!("    {} --> {}\n", id(&edge.from), id(&edge.to))),
+            }
+        }
+        mermaid
+    }
+}
+
+/// Returns the name of `T` without module paths, also within generic arguments.
+pub fn short_type_name<T: ?Sized>() -> String {
+    let mut short = String::new();
+    let mut segment = String::new();
+    for c in std::any::type_name::<T>().chars() {
+        if c.is_alphanumeric() || c == '_' {
+            segment.push(c);
+        } else if c == ':' {
+            segment.clear();
+        } else {
+            short.push_str(&segment);
+            segment.clear();
+            short.push(c);
+        }
+    }
+    short.push_str(&segment);
+    short
+}
+
+impl PluginProcessor {
+    /// Describes the plugin DAG; join nodes merge the outputs of their dependencies.
+    pub fn describe(self: &Self) -> PipelineDescription {
+        let nodes = self.nodes();
+
+        PipelineDescription {
+            stages: nodes
+                .iter()
+                .map(|node| StageDescription {
+                    name: node.name.clone(),
+                    kind: node.plugin.kind(),
+                    input: "String".to_string(),
+                    output: "String".to_string(),
+                })
+                .collect(),
+            edges: nodes
+                .iter()
+                .flat_map(|node| {
+                    let conversion = if node.dependencies.len() > 1 {
+                        Some("merge".to_string())
+                    } else {
+                        None
+                    };
+                    node.dependencies
+                        .iter()
+                        .map(move |dependency| EdgeDescription {
+                            from: dependency.clone(),
+                            to: node.name.clone(),
+                            conversion: conversion.clone(),
+                        })
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::external_plugin::ExternalPlugin;
+    use crate::plugins::{FutureIO, InternalPlugin, InternalPluginWrapper, PluginNode};
+
+    #[derive(Debug)]
+    struct Forwarder;
+
+    impl InternalPlugin for Forwarder {
+        fn run_internal(self: &mut Self, io: String) -> FutureIO<'static, String> {
+            Box::new(futures::future::ok(io))
+        }
+    }
+
+    #[test]
+    fn describe_plugin_processor() {
+        let plugin_processor = PluginProcessor::new(vec![
+            PluginNode::new("a", Box::new(InternalPluginWrapper::new(Forwarder)), &[]),
+            PluginNode::new("b", Box::new(ExternalPlugin::new("cat", &[])), &["a"]),
+            PluginNode::new(
+                "c",
+                Box::new(InternalPluginWrapper::new(Forwarder)),
+                &["a", "b"],
+            ),
+        ])
+        .expect("invalid plugin graph");
+
+        let description = plugin_processor.describe();
+        let kinds: Vec<_> = description.stages.iter().map(|stage| stage.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                StageKind::Internal,
+                StageKind::External,
+                StageKind::Internal
+            ]
+        );
+
+        assert_eq!(
+            description.to_dot(),
+            "digraph pipeline {
+    rankdir=LR;
+    \"a\" [label=\"a\\ninternal: String\", shape=box];
+    \"b\" [label=\"b\\nexternal: String\", shape=box];
+    \"c\" [label=\"c\\ninternal: String\", shape=box];
+    \"a\" -> \"b\";
+    \"a\" -> \"c\" [label=\"merge\"];
+    \"b\" -> \"c\" [label=\"merge\"];
+}
+"
+        );
+        assert_eq!(
+            description.to_mermaid(),
+            "flowchart LR
+    s0[\"a<br/>internal: String\"]
+    s1[\"b<br/>external: String\"]
+    s2[\"c<br/>internal: String\"]
+    s0 --> s1
+    s0 -->|\"merge\"| s2
+    s1 -->|\"merge\"| s2
+"
+        );
+
+        let quoted = PipelineDescription::chain(vec![StageDescription {
+            name: r#"C:\"x""#.to_string(),
+            kind: StageKind::Internal,
+            input: "String".to_string(),
+            output: "String".to_string(),
+        }]);
+        assert!(quoted
+            .to_dot()
+            .contains(r#""C:\\\"x\"" [label="C:\\\"x\"\ninternal: String""#));
+
+        assert_eq!(
+            short_type_name::<Option<crate::plugins::PluginNode>>(),
+            "Option<PluginNode>"
+        );
+    }
+}
@@ -6,7 +6,30 @@ extern crate lazy_static;
 #[macro_use]
 extern crate failure;
 
-// mod plugins;
+pub mod plugins;
+pub mod external_plugin;
+pub mod plugin_loader;
+pub mod checkpoint;
+pub mod introspection;
+pub mod stage_control;
+pub mod lock_monitor;
 mod minimal;
-mod wrapped;
-mod wrapped_enum;
+pub mod wrapped;
+pub mod wrapped_enum;
+pub mod retry;
+pub mod pipeline_config;
+pub mod streaming;
+pub mod worker_pool;
+pub mod live_collection;
+pub mod pipeline;
+pub mod fan_out;
+pub mod recording;
+#[cfg(feature = "http-server")]
+pub mod http_server;
+pub mod typed;
+pub mod cache;
+pub mod saga;
+pub mod actor;
+pub mod shadow;
+#[cfg(test)]
+mod fixtures;
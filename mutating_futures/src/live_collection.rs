@@ -0,0 +1,471 @@
+use crate::stage_control::ProcessControl;
+use crate::wrapped::{
+    process_controlled, with_each_locked, with_locked, AsyncResult, AsyncWorker, Work,
+    WorkCollection, WorkIO,
+};
+use failure::{Error, Fallible};
+use futures::future::Either;
+use futures::Future;
+use futures_locks::Mutex as FuturesMutex;
+use std::sync::{Arc, RwLock};
+use tokio::executor::{DefaultExecutor, Executor};
+
+#[derive(Debug, Fail)]
+pub enum LiveWorkError {
+    #[fail(display = "stage {} is out of range for {} stages", stage, len)]
+    OutOfRange { stage: usize, len: usize },
+    #[fail(display = "version {} has no previous version to roll back to", _0)]
+    NoPreviousVersion(u64),
+}
+
+/// One version of the stages of a LiveWorkCollection
+#[derive(Clone)]
+struct Version {
+    version: u64,
+    stages: Arc<WorkCollection>,
+}
+
+struct LiveState {
+    current: Version,
+    /// Earlier versions, the most recent last
+    history: Vec<Version>,
+    next_version: u64,
+    max_history: usize,
+    /// Workers which no kept version has anymore, until their runs are done
+    retiring: Vec<Work>,
+}
+
+impl LiveState {
+    /// Moves the workers of `discarded` which no kept version has to `retiring`.
+    fn retire(self: &mut Self, discarded: Vec<Version>) {
+        let kept: Vec<&Work> = std::iter::once(&self.current)
+            .chain(self.history.iter())
+            .flat_map(|version| version.stages.iter())
+            .collect();
+        let mut retired: Vec<Work> = Vec::new();
+        for work in discarded.iter().flat_map(|version| version.stages.iter()) {
+            let known = |other: &&Work| Arc::ptr_eq(other, work);
+            if !kept.iter().any(known) && !retired.iter().any(|other| known(&other)) {
+                retired.push(work.clone());
+            }
+        }
+        drop(discarded);
+        self.retiring.extend(retired);
+    }
+
+    /// Takes the retiring workers which no run holds anymore.
+    fn idle(self: &mut Self) -> Vec<Work> {
+        let (idle, busy) = self
+            .retiring
+            .drain(..)
+            .partition(|work| Arc::strong_count(work) == 1);
+        self.retiring = busy;
+        idle
+    }
+}
+
+/// Initializes and warms up a worker before it is published.
+fn prepare(work: &Work, config: &serde_json::Value) -> AsyncResult<()> {
+    let config = config.clone();
+    let warmed_up = work.clone();
+
+    Box::new(
+        with_locked(work, move |worker| worker.init(&config))
+            .and_then(move |_| with_locked(&warmed_up, |worker| worker.warmup())),
+    )
+}
+
+/// Shuts down retired workers. Their stages have moved on already, so failures are
+/// only logged.
+fn shut_down(workers: Vec<Work>) -> AsyncResult<()> {
+    if workers.is_empty() {
+        return Box::new(futures::future::ok(()));
+    }
+
+    Box::new(
+        with_each_locked(workers.iter(), |worker| worker.shutdown()).or_else(|e| {
+            println!("[] could not shut down a retired worker: {}", e);
+            Ok(())
+        }),
+    )
+}
+
+/// Holds the stages of a run until it is done, then shuts down the retired workers
+/// which were only waiting for it
+struct RunGuard {
+    stages: Vec<Work>,
+    live: LiveWorkCollection,
+}
+
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        self.stages.clear();
+        let mut state = self.live.state.write().unwrap();
+        let idle = state.idle();
+        if idle.is_empty() {
+            return;
+        }
+
+        let mut executor = DefaultExecutor::current();
+        if executor.status().is_ok() {
+            let _ = executor.spawn(Box::new(shut_down(idle).map_err(drop)));
+        } else {
+            // outside of a runtime, the next change shuts them down
+            state.retiring.extend(idle);
+        }
+    }
+}
+
+/// WorkCollection whose stages can be replaced and inserted while runs are in progress.
+///
+/// Every run works on the version which was current when it started, so runs in
+/// progress finish on the old workers while new runs pick up the change. Every change
+/// creates a new version number, which is never reused.
+///
+/// Incoming workers are initialized and warmed up before their version is published.
+/// Workers which no kept version has anymore, because they were rolled back or their
+/// version dropped out of the history, are shut down once their runs are done.
+#[derive(Clone)]
+pub struct LiveWorkCollection {
+    state: Arc<RwLock<LiveState>>,
+}
+
+impl LiveWorkCollection {
+    pub fn new(work_collection: WorkCollection) -> Self {
+        LiveWorkCollection {
+            state: Arc::new(RwLock::new(LiveState {
+                current: Version {
+                    version: 0,
+                    stages: Arc::new(work_collection),
+                },
+                history: Vec::new(),
+                next_version: 1,
+                max_history: 8,
+                retiring: Vec::new(),
+            })),
+        }
+    }
+
+    /// Limits how many earlier versions are kept for rollbacks.
+    pub fn with_max_history(self: Self, max_history: usize) -> Self {
+        self.state.write().unwrap().max_history = max_history;
+        self
+    }
+
+    /// Returns the current version together with its stages.
+    pub fn snapshot(self: &Self) -> (u64, Arc<WorkCollection>) {
+        let state = self.state.read().unwrap();
+        (state.current.version, state.current.stages.clone())
+    }
+
+    pub fn version(self: &Self) -> u64 {
+        self.state.read().unwrap().current.version
+    }
+
+    /// Applies `change` to a copy of the current stages and makes it the new version.
+    ///
+    /// Returns the new version and the retired workers which can be shut down.
+    fn update<F>(self: &Self, change: F) -> Fallible<(u64, Vec<Work>)>
+    where
+        F: FnOnce(&mut WorkCollection) -> Fallible<()>,
+    {
+        let mut state = self.state.write().unwrap();
+        let mut stages = (*state.current.stages).clone();
+        change(&mut stages)?;
+
+        let version = state.next_version;
+        state.next_version += 1;
+        let previous = std::mem::replace(
+            &mut state.current,
+            Version {
+                version,
+                stages: Arc::new(stages),
+            },
+        );
+        state.history.push(previous);
+        let excess = state.history.len().saturating_sub(state.max_history);
+        let discarded: Vec<Version> = state.history.drain(..excess).collect();
+        state.retire(discarded);
+
+        println!("[] work collection is now at version {}", version);
+        Ok((version, state.idle()))
+    }
+
+    /// Prepares `worker` with `config`, publishes it with `change` and shuts down the
+    /// workers this retires, or `worker` itself if the change fails.
+    fn update_with<F>(
+        self: &Self,
+        worker: Box<dyn AsyncWorker<WorkIO>>,
+        config: &serde_json::Value,
+        change: F,
+    ) -> AsyncResult<u64>
+    where
+        F: FnOnce(&mut WorkCollection, Work) -> Fallible<()> + Send + 'static,
+    {
+        let live = self.clone();
+        let work: Work = Arc::new(FuturesMutex::new(worker));
+
+        Box::new(prepare(&work, config).and_then(move |_| {
+            let published = work.clone();
+            match live.update(move |stages| change(stages, published)) {
+                Ok((version, idle)) => Either::A(shut_down(idle).map(move |_| version)),
+                Err(e) => Either::B(shut_down(vec![work]).then(move |_| Err::<u64, Error>(e))),
+            }
+        }))
+    }
+
+    /// Replaces the worker of `stage` with `worker`, once it is initialized with
+    /// `config` and warmed up, and returns the new version.
+    pub fn replace(
+        self: &Self,
+        stage: usize,
+        worker: Box<dyn AsyncWorker<WorkIO>>,
+        config: &serde_json::Value,
+    ) -> AsyncResult<u64> {
+        self.update_with(worker, config, move |stages, work| {
+            let len = stages.len();
+            let slot = stages
+                .get_mut(stage)
+                .ok_or(LiveWorkError::OutOfRange { stage, len })?;
+            *slot = work;
+            Ok(())
+        })
+    }
+
+    /// Inserts `worker` before `stage`, or at the end if `stage` is the number of
+    /// stages, once it is initialized with `config` and warmed up, and returns the new
+    /// version.
+    pub fn insert(
+        self: &Self,
+        stage: usize,
+        worker: Box<dyn AsyncWorker<WorkIO>>,
+        config: &serde_json::Value,
+    ) -> AsyncResult<u64> {
+        self.update_with(worker, config, move |stages, work| {
+            if stage > stages.len() {
+                return Err(LiveWorkError::OutOfRange {
+                    stage,
+                    len: stages.len(),
+                }
+                .into());
+            }
+            stages.insert(stage, work);
+            Ok(())
+        })
+    }
+
+    /// Makes the version before the current one current again and returns its number.
+    ///
+    /// The workers of that version are restored as they were, including their state.
+    pub fn rollback(self: &Self) -> AsyncResult<u64> {
+        let (version, idle) = {
+            let mut state = self.state.write().unwrap();
+            let previous = match state.history.pop() {
+                Some(previous) => previous,
+                None => {
+                    let error = LiveWorkError::NoPreviousVersion(state.current.version);
+                    return Box::new(futures::future::err(error.into()));
+                }
+            };
+            let discarded = std::mem::replace(&mut state.current, previous);
+            state.retire(vec![discarded]);
+
+            println!(
+                "[] work collection rolled back to version {}",
+                state.current.version
+            );
+            (state.current.version, state.idle())
+        };
+
+        Box::new(shut_down(idle).map(move |_| version))
+    }
+
+    /// Runs the current version of the stages, see `wrapped::process`.
+    pub fn process(self: &Self) -> AsyncResult<WorkIO> {
+        self.process_controlled(ProcessControl::default())
+    }
+
+    pub fn process_controlled(self: &Self, control: ProcessControl) -> AsyncResult<WorkIO> {
+        let (_, stages) = self.snapshot();
+        let stages: Vec<Work> = stages.iter().cloned().collect();
+        let guard = RunGuard {
+            stages: stages.clone(),
+            live: self.clone(),
+        };
+
+        Box::new(
+            process_controlled(stages.into_iter(), control).then(move |result| {
+                drop(guard);
+                result
+            }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wrapped::{AsyncWorkerInternal, InternalWorkWrapper};
+    use failure::Error;
+    use futures::sync::oneshot;
+    use futures::Future;
+    use serde_json::json;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    /// Appends its suffix once `release` has fired, or right away without one, and logs
+    /// its lifecycle hooks
+    #[derive(Clone)]
+    struct InternalGatedAppender {
+        suffix: &'static str,
+        release: Arc<Mutex<Option<oneshot::Receiver<()>>>>,
+        log: Log,
+    }
+    impl InternalGatedAppender {
+        fn logged(self: &Self, hook: String) -> AsyncResult<()> {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", self.suffix, hook));
+            Box::new(futures::future::ok(()))
+        }
+    }
+    impl AsyncWorkerInternal<WorkIO> for InternalGatedAppender {
+        fn run_internal(self: &mut Self, input: WorkIO) -> AsyncResult<WorkIO> {
+            let output = format!("{}{}", input, self.suffix);
+            match self.release.lock().unwrap().take() {
+                Some(release) => Box::new(release.map_err(Error::from).map(move |_| output)),
+                None => Box::new(futures::future::ok(output)),
+            }
+        }
+
+        fn init(self: &mut Self, config: &serde_json::Value) -> AsyncResult<()> {
+            self.logged(format!("init {}", config))
+        }
+
+        fn warmup(self: &mut Self) -> AsyncResult<()> {
+            self.logged("warmup".to_string())
+        }
+
+        fn shutdown(self: &mut Self) -> AsyncResult<()> {
+            self.logged("shutdown".to_string())
+        }
+    }
+
+    fn gated_appender(
+        suffix: &'static str,
+        log: &Log,
+        gate: Option<oneshot::Receiver<()>>,
+    ) -> Box<dyn AsyncWorker<WorkIO>> {
+        Box::new(InternalWorkWrapper(InternalGatedAppender {
+            suffix,
+            release: Arc::new(Mutex::new(gate)),
+            log: log.clone(),
+        }))
+    }
+
+    fn appender(suffix: &'static str) -> Box<dyn AsyncWorker<WorkIO>> {
+        gated_appender(suffix, &Log::default(), None)
+    }
+
+    #[test]
+    fn test_hot_swap_and_rollback() -> Fallible<()> {
+        let (release, gate) = oneshot::channel();
+        let gated = gated_appender("a", &Log::default(), Some(gate));
+        let live = LiveWorkCollection::new(vec![Arc::new(FuturesMutex::new(gated))]);
+        let null = serde_json::Value::Null;
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        // the run in progress finishes on the worker it started with
+        let in_progress = oneshot::spawn(live.process(), &runtime.executor());
+        assert_eq!(runtime.block_on(live.replace(0, appender("b"), &null))?, 1);
+        assert_eq!(runtime.block_on(live.process())?, "b");
+        release.send(()).unwrap();
+        assert_eq!(runtime.block_on(in_progress)?, "a");
+
+        assert_eq!(runtime.block_on(live.insert(1, appender("c"), &null))?, 2);
+        assert_eq!(runtime.block_on(live.process())?, "bc");
+
+        assert_eq!(runtime.block_on(live.rollback())?, 1);
+        assert_eq!(runtime.block_on(live.process())?, "b");
+        assert_eq!(runtime.block_on(live.replace(0, appender("d"), &null))?, 3);
+        assert_eq!(runtime.block_on(live.process())?, "d");
+
+        assert_eq!(runtime.block_on(live.rollback())?, 1);
+        assert_eq!(runtime.block_on(live.rollback())?, 0);
+        assert_eq!(runtime.block_on(live.process())?, "a");
+        match runtime
+            .block_on(live.rollback())
+            .map_err(|e| e.downcast::<LiveWorkError>())
+        {
+            Err(Ok(LiveWorkError::NoPreviousVersion(0))) => (),
+            other => panic!("expected no previous version, got {:?}", other.map(|_| ())),
+        }
+
+        match runtime
+            .block_on(live.replace(5, appender("e"), &null))
+            .map_err(|e| e.downcast::<LiveWorkError>())
+        {
+            Err(Ok(LiveWorkError::OutOfRange { stage: 5, len: 1 })) => (),
+            other => panic!(
+                "expected an out of range stage, got {:?}",
+                other.map(|_| ())
+            ),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lifecycle_of_swapped_workers() -> Fallible<()> {
+        let log = Log::default();
+        let (release, gate) = oneshot::channel();
+        let live = LiveWorkCollection::new(vec![Arc::new(FuturesMutex::new(gated_appender(
+            "a",
+            &log,
+            Some(gate),
+        )))])
+        .with_max_history(0);
+        let logged = || log.lock().unwrap().clone();
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        // incoming workers are ready before any run can reach them, outgoing ones are
+        // shut down once their runs are done
+        let in_progress = oneshot::spawn(live.process(), &runtime.executor());
+        let config = json!({"suffix": "b"});
+        runtime.block_on(live.replace(0, gated_appender("b", &log, None), &config))?;
+        assert_eq!(logged(), vec![r#"b init {"suffix":"b"}"#, "b warmup"]);
+        release.send(()).unwrap();
+        assert_eq!(runtime.block_on(in_progress)?, "a");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while logged().len() < 3 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(logged()[2..], ["a shutdown"]);
+
+        // rolled back workers without runs are shut down right away
+        let live = live.with_max_history(1);
+        runtime.block_on(live.insert(1, gated_appender("c", &log, None), &config))?;
+        runtime.block_on(live.rollback())?;
+        assert_eq!(
+            logged()[3..],
+            [r#"c init {"suffix":"b"}"#, "c warmup", "c shutdown"]
+        );
+
+        // workers which can't be published are shut down too
+        runtime
+            .block_on(live.replace(5, gated_appender("d", &log, None), &config))
+            .expect_err("stage 5 is out of range");
+        assert_eq!(
+            logged()[6..],
+            [r#"d init {"suffix":"b"}"#, "d warmup", "d shutdown"]
+        );
+        assert_eq!(runtime.block_on(live.process())?, "b");
+
+        Ok(())
+    }
+}
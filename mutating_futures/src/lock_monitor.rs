@@ -0,0 +1,615 @@
+use core::fmt::Display;
+use core::fmt::Formatter;
+use failure::Error;
+use futures::future::Either;
+use futures::{Async, Future, Poll, Stream};
+use futures_locks::{Mutex as FuturesMutex, MutexFut, MutexGuard};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::executor::{DefaultExecutor, Executor};
+
+/// Identifies one run of a pipeline, see `ProcessControl::for_run`
+pub type RunId = usize;
+
+/// Key of a monitored mutex, the address it is shared at
+type LockKey = usize;
+
+/// Waiters and holder of a mutex, kept only while there are any
+struct LockRecord {
+    label: String,
+    holder: Option<(RunId, Instant)>,
+    waiters: Vec<(RunId, Instant)>,
+    /// Keeps the mutex, and with it the key, from being reused while it is recorded
+    _mutex: Arc<dyn Send + Sync>,
+}
+
+impl MonitorState {
+    /// Changes the record of `lock` with `f`, then drops it if it is no longer used.
+    fn update<F: FnOnce(&mut LockRecord)>(self: &mut Self, lock: LockKey, f: F) {
+        let unused = match self.locks.get_mut(&lock) {
+            Some(record) => {
+                f(record);
+                record.holder.is_none() && record.waiters.is_empty()
+            }
+            None => false,
+        };
+        if unused {
+            self.locks.remove(&lock);
+        }
+    }
+}
+
+#[derive(Default)]
+struct MonitorState {
+    locks: HashMap<LockKey, LockRecord>,
+    next_run: RunId,
+}
+
+/// Keeps track of which run holds and which runs wait for each monitored mutex.
+///
+/// Besides the stage locks, the locks which workers take through `lock_nested`, as
+/// `with_locked` does, are recorded for the run whose stage is being polled, labelled
+/// e.g. `orders stage 0, nested`. Locks taken on other tasks, e.g. after a
+/// `tokio::spawn`, are not recorded.
+///
+/// Deadlocks can only happen between runs which hold one lock while waiting for
+/// another. The `process` functions and `with_locked` release a lock before they wait
+/// for the next one, so among their runs this shows long waits but no deadlocks; those
+/// are found for workers which hold a guard of `lock_nested` while they wait for
+/// another.
+///
+/// Clones share the same records.
+#[derive(Clone, Default)]
+pub struct LockMonitor {
+    state: Arc<Mutex<MonitorState>>,
+}
+
+/// Monitored run and stage, see `LockMonitor::in_stage`
+#[derive(Clone)]
+struct StageContext {
+    monitor: LockMonitor,
+    run: RunId,
+    label: String,
+}
+
+thread_local! {
+    /// The stage whose future is being polled on this thread, if it is monitored
+    static POLLED_STAGE: RefCell<Option<StageContext>> = const { RefCell::new(None) };
+}
+
+/// Future of a stage, while which is polled `lock_nested` records its locks
+pub struct InStage<F> {
+    future: F,
+    context: StageContext,
+}
+
+/// Puts back the stage polled before, also when the inner future panics
+struct Restore(Option<StageContext>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        POLLED_STAGE.with(|stage| *stage.borrow_mut() = previous);
+    }
+}
+
+impl<F: Future> Future for InStage<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(self: &mut Self) -> Poll<F::Item, F::Error> {
+        let previous = POLLED_STAGE.with(|stage| stage.replace(Some(self.context.clone())));
+        let _restore = Restore(previous);
+        self.future.poll()
+    }
+}
+
+/// Locks `mutex`, recorded as a nested lock of the monitored stage which polls it.
+///
+/// Outside of monitored stages this is a plain lock.
+pub fn lock_nested<T>(
+    mutex: &Arc<FuturesMutex<T>>,
+) -> impl Future<Item = MonitoredGuard<T>, Error = Error>
+where
+    T: Send + 'static,
+{
+    let mutex = mutex.clone();
+
+    // the stage is only known once the future is polled
+    futures::future::lazy(
+        move || match POLLED_STAGE.with(|stage| stage.borrow().clone()) {
+            Some(context) => Either::A(context.monitor.lock(
+                context.run,
+                &format!("{}, nested", context.label),
+                &mutex,
+            )),
+            None => Either::B(
+                locking(&mutex)
+                    .map(MonitoredGuard::unmonitored)
+                    .map_err(|_| failure::err_msg("could not acquire the mutex lock")),
+            ),
+        },
+    )
+}
+
+/// Removes a waiter which gave up, e.g. after a timeout, before it got the lock
+struct Waiting {
+    monitor: LockMonitor,
+    lock: LockKey,
+    run: RunId,
+    acquired: bool,
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        if !self.acquired {
+            let run = self.run;
+            let mut state = self.monitor.state.lock().unwrap();
+            state.update(self.lock, |record| {
+                record.waiters.retain(|(waiter, _)| *waiter != run)
+            });
+        }
+    }
+}
+
+/// Clears the holder of a lock once its guard is dropped
+struct Held {
+    monitor: LockMonitor,
+    lock: LockKey,
+    run: RunId,
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        let run = self.run;
+        let mut state = self.monitor.state.lock().unwrap();
+        state.update(self.lock, |record| {
+            if record.holder.map(|(holder, _)| holder) == Some(run) {
+                record.holder = None;
+            }
+        });
+    }
+}
+
+/// MutexGuard which also releases the lock in its LockMonitor, if there is one
+pub struct MonitoredGuard<T> {
+    // declared first so the mutex is unlocked before the holder is cleared
+    guard: MutexGuard<T>,
+    _held: Option<Held>,
+}
+
+/// Lock future which may be dropped while it waits, e.g. by a timeout or a `select`.
+///
+/// futures-locks 0.3 panics when it hands the lock to a waiter which is gone, so a
+/// wait that was given up goes on in a task of its own, which releases the lock as
+/// soon as it gets it.
+pub struct Locking<T: Send + 'static> {
+    wait: Option<MutexFut<T>>,
+    waiting: bool,
+}
+
+/// Locks `mutex` like `FuturesMutex::lock`, but can be dropped at any time.
+pub fn locking<T: Send + 'static>(mutex: &FuturesMutex<T>) -> Locking<T> {
+    Locking {
+        wait: Some(mutex.lock()),
+        waiting: false,
+    }
+}
+
+impl<T: Send + 'static> Future for Locking<T> {
+    type Item = MutexGuard<T>;
+    type Error = ();
+
+    fn poll(self: &mut Self) -> Poll<MutexGuard<T>, ()> {
+        let wait = self
+            .wait
+            .as_mut()
+            .expect("polled after the lock was acquired");
+        match wait.poll()? {
+            Async::Ready(guard) => {
+                self.wait = None;
+                Ok(Async::Ready(guard))
+            }
+            Async::NotReady => {
+                self.waiting = true;
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+impl<T: Send + 'static> Drop for Locking<T> {
+    fn drop(&mut self) {
+        let wait = match self.wait.take() {
+            Some(wait) if self.waiting => wait,
+            // a wait which was never polled is not queued yet
+            _ => return,
+        };
+        let release = wait.map(drop);
+
+        let mut executor = DefaultExecutor::current();
+        if executor.status().is_ok() {
+            let _ = executor.spawn(Box::new(release));
+        } else {
+            std::thread::spawn(move || release.wait());
+        }
+    }
+}
+
+impl<T> MonitoredGuard<T> {
+    pub fn unmonitored(guard: MutexGuard<T>) -> Self {
+        MonitoredGuard { guard, _held: None }
+    }
+}
+
+impl<T> Deref for MonitoredGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for MonitoredGuard<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl LockMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn next_run(self: &Self) -> RunId {
+        let mut state = self.state.lock().unwrap();
+        state.next_run += 1;
+        state.next_run
+    }
+
+    /// Locks `mutex` on behalf of `run`, recording the wait and the hold under `label`.
+    ///
+    /// Clones of the Arc are the same lock. Its record is dropped once nobody holds or
+    /// waits for it anymore.
+    pub fn lock<T>(
+        self: &Self,
+        run: RunId,
+        label: &str,
+        mutex: &Arc<FuturesMutex<T>>,
+    ) -> impl Future<Item = MonitoredGuard<T>, Error = Error>
+    where
+        T: Send + 'static,
+    {
+        let lock = Arc::as_ptr(mutex) as LockKey;
+        {
+            let mut state = self.state.lock().unwrap();
+            let record = state.locks.entry(lock).or_insert_with(|| LockRecord {
+                label: label.to_string(),
+                holder: None,
+                waiters: Vec::new(),
+                _mutex: mutex.clone(),
+            });
+            record.label = label.to_string();
+            record.waiters.push((run, Instant::now()));
+        }
+
+        let mut waiting = Waiting {
+            monitor: self.clone(),
+            lock,
+            run,
+            acquired: false,
+        };
+        locking(mutex)
+            .map_err(|_| failure::err_msg("could not acquire the mutex lock"))
+            .map(move |guard| {
+                waiting.acquired = true;
+                let monitor = waiting.monitor.clone();
+                {
+                    let mut state = monitor.state.lock().unwrap();
+                    state.update(lock, |record| {
+                        record.waiters.retain(|(waiter, _)| *waiter != run);
+                        record.holder = Some((run, Instant::now()));
+                    });
+                }
+
+                MonitoredGuard {
+                    guard,
+                    _held: Some(Held { monitor, lock, run }),
+                }
+            })
+    }
+
+    /// Records the locks taken through `lock_nested` while `future` is polled for `run`,
+    /// under the stage's `label`.
+    pub fn in_stage<F: Future>(self: &Self, run: RunId, label: &str, future: F) -> InStage<F> {
+        InStage {
+            future,
+            context: StageContext {
+                monitor: self.clone(),
+                run,
+                label: label.to_string(),
+            },
+        }
+    }
+
+    /// Returns the current waiters and holders of all locks and the deadlocks
+    /// among them.
+    pub fn dump(self: &Self) -> LockDump {
+        let state = self.state.lock().unwrap();
+
+        let mut locks: Vec<LockStatus> = state
+            .locks
+            .values()
+            .map(|record| LockStatus {
+                label: record.label.clone(),
+                holder: record.holder.map(|(run, since)| LockHold {
+                    run,
+                    held: since.elapsed(),
+                }),
+                waiters: record
+                    .waiters
+                    .iter()
+                    .map(|(run, since)| LockWait {
+                        run: *run,
+                        waited: since.elapsed(),
+                    })
+                    .collect(),
+            })
+            .collect();
+        locks.sort_by(|a, b| a.label.cmp(&b.label));
+
+        // every waiting run waits for the run holding the lock
+        let mut waits_for: BTreeMap<RunId, BTreeSet<RunId>> = BTreeMap::new();
+        for record in state.locks.values() {
+            if let Some((holder, _)) = record.holder {
+                for (waiter, _) in &record.waiters {
+                    waits_for.entry(*waiter).or_default().insert(holder);
+                }
+            }
+        }
+
+        LockDump {
+            locks,
+            deadlocks: find_cycles(&waits_for),
+        }
+    }
+
+    /// Checks the waits every `period` and passes a dump to `on_dump` whenever a run
+    /// has waited longer than `threshold` or runs are deadlocked.
+    pub fn watch<F>(
+        self: &Self,
+        threshold: Duration,
+        period: Duration,
+        on_dump: F,
+    ) -> impl Future<Item = (), Error = Error>
+    where
+        F: Fn(&LockDump) + Send + 'static,
+    {
+        let monitor = self.clone();
+
+        tokio::timer::Interval::new(Instant::now() + period, period)
+            .map_err(Error::from)
+            .for_each(move |_| {
+                let dump = monitor.dump();
+                if !dump.deadlocks.is_empty() || dump.longest_wait() > Some(threshold) {
+                    on_dump(&dump);
+                }
+                Ok(())
+            })
+    }
+}
+
+/// Returns every cycle in the wait-for graph once, starting at its smallest run.
+fn find_cycles(waits_for: &BTreeMap<RunId, BTreeSet<RunId>>) -> Vec<Vec<RunId>> {
+    fn visit(
+        run: RunId,
+        waits_for: &BTreeMap<RunId, BTreeSet<RunId>>,
+        path: &mut Vec<RunId>,
+        cycles: &mut BTreeSet<Vec<RunId>>,
+    ) {
+        if let Some(start) = path.iter().position(|r| *r == run) {
+            let mut cycle = path[start..].to_vec();
+            let smallest = (0..cycle.len()).min_by_key(|i| cycle[*i]).unwrap_or(0);
+            cycle.rotate_left(smallest);
+            cycles.insert(cycle);
+            return;
+        }
+
+        path.push(run);
+        for next in waits_for.get(&run).into_iter().flatten() {
+            visit(*next, waits_for, path, cycles);
+        }
+        path.pop();
+    }
+
+    let mut cycles = BTreeSet::new();
+    for run in waits_for.keys() {
+        visit(*run, waits_for, &mut Vec::new(), &mut cycles);
+    }
+    cycles.into_iter().collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct LockHold {
+    pub run: RunId,
+    pub held: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct LockWait {
+    pub run: RunId,
+    pub waited: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct LockStatus {
+    pub label: String,
+    pub holder: Option<LockHold>,
+    pub waiters: Vec<LockWait>,
+}
+
+/// Snapshot of all held or awaited locks of a LockMonitor
+#[derive(Debug, Clone)]
+pub struct LockDump {
+    pub locks: Vec<LockStatus>,
+    /// Runs which wait for each other in a cycle
+    pub deadlocks: Vec<Vec<RunId>>,
+}
+
+impl LockDump {
+    pub fn longest_wait(self: &Self) -> Option<Duration> {
+        self.locks
+            .iter()
+            .flat_map(|lock| lock.waiters.iter().map(|waiter| waiter.waited))
+            .max()
+    }
+}
+
+impl Display for LockDump {
+    fn fmt(self: &Self, formatter: &mut Formatter) -> Result<(), std::fmt::Error> {
+        writeln!(formatter, "[] lock dump:")?;
+        for lock in &self.locks {
+            match lock.holder {
+                Some(ref holder) => writeln!(
+                    formatter,
+                    "[]   {}: held by run {} for {:?}",
+                    lock.label, holder.run, holder.held
+                )?,
+                None => writeln!(formatter, "[]   {}: free", lock.label)?,
+            }
+            for waiter in &lock.waiters {
+                writeln!(
+                    formatter,
+                    "[]     run {} waiting for {:?}",
+                    waiter.run, waiter.waited
+                )?;
+            }
+        }
+        for deadlock in &self.deadlocks {
+            writeln!(formatter, "[]   deadlock between runs {:?}", deadlock)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stage_control::ProcessControl;
+    use failure::Fallible;
+    use futures::sync::{mpsc, oneshot};
+
+    #[test]
+    fn detect_lock_waits_and_deadlocks() -> Fallible<()> {
+        let monitor = LockMonitor::new();
+        let control = ProcessControl::default()
+            .with_name("orders")
+            .with_lock_monitor(monitor.clone());
+        let (first, second) = (
+            Arc::new(FuturesMutex::new(1)),
+            Arc::new(FuturesMutex::new(2)),
+        );
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let (dumps, dumped) = mpsc::unbounded();
+        runtime.spawn(
+            monitor
+                .watch(
+                    Duration::from_millis(10),
+                    Duration::from_millis(5),
+                    move |dump| {
+                        let _ = dumps.unbounded_send(dump.clone());
+                    },
+                )
+                .map_err(|_| ()),
+        );
+
+        // each run holds the lock the other one is waiting for
+        let (run_a, run_b) = (control.for_run(), control.for_run());
+        let held_by_a = runtime.block_on(run_a.lock(0, &first))?;
+        let held_by_b = runtime.block_on(run_b.lock(1, &second))?;
+        let a_waiting = run_a.lock(1, &second);
+        // clones of a stage are the same lock
+        let b_waiting = run_b.lock(0, &first.clone());
+
+        let dump = runtime
+            .block_on(dumped.into_future())
+            .map_err(|_| failure::err_msg("no lock dump"))?
+            .0
+            .expect("watch ended");
+        assert_eq!(dump.deadlocks, vec![vec![1, 2]]);
+        assert!(dump.longest_wait().is_some());
+        let text = dump.to_string();
+        assert!(text.contains("orders stage 0: held by run 1"));
+        assert!(text.contains("run 2 waiting for"));
+
+        // giving up on a wait removes the waiter
+        drop(a_waiting);
+        drop(b_waiting);
+        assert!(monitor.dump().deadlocks.is_empty());
+        drop(held_by_a);
+        drop(held_by_b);
+        assert!(monitor.state.lock().unwrap().locks.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn record_nested_locks() -> Fallible<()> {
+        use crate::fan_out::{FanOutWorker, Reducer};
+        use crate::fixtures::InternalCountingForwarder;
+        use crate::wrapped::{process_controlled, InternalWorkWrapper, Work, WorkCollection};
+
+        let monitor = LockMonitor::new();
+        let control = ProcessControl::default()
+            .with_name("orders")
+            .with_lock_monitor(monitor.clone());
+        let wrapped: Work = Arc::new(FuturesMutex::new(Box::new(InternalWorkWrapper(
+            InternalCountingForwarder(0),
+        ))));
+        let stages: WorkCollection = vec![Arc::new(FuturesMutex::new(Box::new(
+            FanOutWorker::from_work(vec![wrapped.clone()], Reducer::concatenate(",")),
+        )))];
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        // the fan-out waits for its wrapped worker while it is held here
+        let held = runtime
+            .block_on(locking(&wrapped))
+            .map_err(|_| failure::err_msg("could not acquire the mutex lock"))?;
+        let (result, finished) = oneshot::channel();
+        runtime.spawn(
+            process_controlled(stages.into_iter(), control).then(move |output| {
+                let _ = result.send(output);
+                Ok(())
+            }),
+        );
+
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let nested = loop {
+            let dump = monitor.dump();
+            if let Some(lock) = dump
+                .locks
+                .into_iter()
+                .find(|lock| lock.label == "orders stage 0, nested" && !lock.waiters.is_empty())
+            {
+                break lock;
+            }
+            assert!(Instant::now() < deadline, "nested lock was not recorded");
+            std::thread::sleep(Duration::from_millis(5));
+        };
+        assert_eq!(nested.waiters[0].run, 1);
+        assert!(nested.holder.is_none());
+
+        drop(held);
+        runtime
+            .block_on(finished)
+            .map_err(|_| failure::err_msg("run was dropped"))??;
+        assert!(monitor.state.lock().unwrap().locks.is_empty());
+
+        Ok(())
+    }
+}
@@ -1,9 +1,11 @@
 #![allow(dead_code)]
 
+use crate::stage_control::{ProcessControl, StagePhase};
 use failure::Error;
 use futures::IntoFuture;
 use futures::{Future, Stream};
 use futures_locks::Mutex as FuturesMutex;
+use std::borrow::Borrow;
 use std::sync::Arc;
 
 type AsyncResult<T> = Box<dyn Future<Item = T, Error = Error> + Send>;
@@ -23,30 +25,44 @@ type WorkCollectionMutex = FuturesMutex<WorkCollection>;
 
 fn process<T>(work_collection: T) -> AsyncResult<String>
 where
-    T: Iterator<Item = &'static WorkMutex>,
-    T: Sync + Send,
-    T: 'static,
+    T: Iterator + Sync + Send + 'static,
+    T::Item: Borrow<WorkMutex> + Send,
+{
+    process_controlled(work_collection, ProcessControl::default())
+}
+
+/// Like `process`, but with the timeouts and cancellation of `control` applied.
+fn process_controlled<T>(work_collection: T, control: ProcessControl) -> AsyncResult<String>
+where
+    T: Iterator + Sync + Send + 'static,
+    T::Item: Borrow<WorkMutex> + Send,
 {
-    let future_work = futures::stream::iter_ok::<_, Error>(work_collection)
+    let control = control.for_run();
+    let stage_control = control.clone();
+    let future_work = futures::stream::iter_ok::<_, Error>(work_collection.enumerate())
         .fold(
             futures::future::Either::A(Box::new(futures::future::ok("".to_string()))),
-            |future_input, next_item_mutex| {
+            move |future_input, (stage, next_item_mutex)| {
                 println!("[] getting work lock...");
-                next_item_mutex
-                    .lock()
-                    .map_err(|_| failure::err_msg("could not acquire the mutex lock"))
+                let run_control = stage_control.clone();
+                stage_control
+                    .lock(stage, next_item_mutex.borrow())
                     .join(future_input)
-                    .map(|(mut next_item, input)| {
+                    .map(move |(mut next_item, input)| {
                         println!("[] got work lock!");
                         println!("[] input: {}", input);
-                        futures::future::Either::B((*next_item).run(input))
+                        futures::future::Either::B(run_control.stage(
+                            stage,
+                            StagePhase::Run,
+                            (*next_item).run(input),
+                        ))
                     })
             },
         )
         .into_future()
         .flatten();
 
-    Box::new(future_work)
+    control.pipeline(future_work)
 }
 
 #[cfg(test)]
@@ -92,4 +108,19 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_process_owned_collection() -> Fallible<()> {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        // collections don't need to be 'static
+        let work_collection: WorkCollection = vec![
+            Arc::new(FuturesMutex::new(Box::new(CountingForwarder(0)))),
+            Arc::new(FuturesMutex::new(Box::new(CountingForwarder(0)))),
+        ];
+        let result = runtime.block_on(process(work_collection.into_iter()))?;
+        assert_eq!(result, "11");
+
+        Ok(())
+    }
 }
@@ -0,0 +1,143 @@
+use crate::stage_control::ProcessControl;
+use crate::wrapped::{
+    process_controlled, AsyncResult, AsyncWorker, AsyncWorkerExternal, AsyncWorkerInternal,
+    ExternalWorkWrapper, InternalWorkWrapper, Work, WorkCollection, WorkIO,
+};
+use futures_locks::Mutex as FuturesMutex;
+use std::sync::Arc;
+
+/// Owned, cheaply cloneable sequence of stages.
+///
+/// Unlike the `process` functions, a Pipeline doesn't need its stages to live in a
+/// `'static` collection, so pipelines can be created and dropped per request. Clones
+/// share their stages, and with them the state of their workers.
+#[derive(Clone)]
+pub struct Pipeline {
+    stages: Arc<[Work]>,
+    control: ProcessControl,
+}
+
+impl Pipeline {
+    pub fn builder() -> PipelineBuilder {
+        PipelineBuilder::default()
+    }
+
+    pub fn stages(self: &Self) -> &[Work] {
+        &self.stages
+    }
+
+    pub fn len(self: &Self) -> usize {
+        self.stages.len()
+    }
+
+    pub fn is_empty(self: &Self) -> bool {
+        self.stages.is_empty()
+    }
+
+    /// Runs all stages one after another, see `wrapped::process_controlled`.
+    pub fn process(self: &Self) -> AsyncResult<WorkIO> {
+        let stages = self.stages.clone();
+
+        process_controlled(
+            (0..stages.len()).map(move |stage| stages[stage].clone()),
+            self.control.clone(),
+        )
+    }
+}
+
+impl From<WorkCollection> for Pipeline {
+    fn from(work_collection: WorkCollection) -> Self {
+        Pipeline {
+            stages: work_collection.into(),
+            control: ProcessControl::default(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct PipelineBuilder {
+    stages: Vec<Work>,
+    control: ProcessControl,
+}
+
+impl PipelineBuilder {
+    pub fn with_stage(mut self: Self, worker: Box<dyn AsyncWorker<WorkIO>>) -> Self {
+        self.stages.push(Arc::new(FuturesMutex::new(worker)));
+        self
+    }
+
+    pub fn with_internal<T>(self: Self, worker: T) -> Self
+    where
+        T: AsyncWorkerInternal<WorkIO> + Sync + Send + Clone + 'static,
+    {
+        self.with_stage(Box::new(InternalWorkWrapper(worker)))
+    }
+
+    pub fn with_external<T>(self: Self, worker: T) -> Self
+    where
+        T: AsyncWorkerExternal<WorkIO> + Sync + Send + Clone + 'static,
+    {
+        self.with_stage(Box::new(ExternalWorkWrapper(worker)))
+    }
+
+    /// Adds a stage which may also be part of other pipelines.
+    pub fn with_work(mut self: Self, work: Work) -> Self {
+        self.stages.push(work);
+        self
+    }
+
+    /// Sets the timeouts and cancellation applied to every run.
+    pub fn with_control(mut self: Self, control: ProcessControl) -> Self {
+        self.control = control;
+        self
+    }
+
+    pub fn build(self: Self) -> Pipeline {
+        Pipeline {
+            stages: self.stages.into(),
+            control: self.control,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::InternalCountingForwarder;
+    use failure::Fallible;
+
+    #[test]
+    fn test_process_owned_pipelines() -> Fallible<()> {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let shared: Work = Arc::new(FuturesMutex::new(Box::new(InternalWorkWrapper(
+            InternalCountingForwarder(0),
+        ))));
+
+        // one short-lived pipeline per tenant, each with its own counter and a stage
+        // which counts the runs of all tenants
+        for tenant in 0..3 {
+            let pipeline = Pipeline::builder()
+                .with_internal(InternalCountingForwarder(tenant))
+                .with_work(shared.clone())
+                .build();
+
+            let copy = pipeline.clone();
+            assert_eq!(
+                runtime.block_on(pipeline.process())?,
+                format!("{}{}", tenant + 1, 2 * tenant + 1)
+            );
+            drop(pipeline);
+            assert_eq!(
+                runtime.block_on(copy.process())?,
+                format!("{}{}", tenant + 2, 2 * tenant + 2)
+            );
+        }
+
+        // only the shared stage outlives its pipelines
+        assert_eq!(Arc::strong_count(&shared), 1);
+        let pipeline = Pipeline::from(vec![shared]);
+        assert_eq!(runtime.block_on(pipeline.process())?, "7");
+
+        Ok(())
+    }
+}
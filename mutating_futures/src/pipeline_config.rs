@@ -0,0 +1,524 @@
+use crate::external_plugin::ExternalPlugin;
+use crate::plugins::{
+    InternalPlugin, InternalPluginWrapper, PluginNode, PluginProcessor, PluginReference,
+};
+use crate::wrapped::{
+    AsyncWorker, AsyncWorkerExternal, AsyncWorkerInternal, ExternalWorkWrapper,
+    InternalWorkWrapper, WorkCollection, WorkIO,
+};
+use core::fmt::{Display, Formatter};
+use failure::{Error, Fail, Fallible};
+use futures_locks::Mutex as FuturesMutex;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use toml::Spanned;
+
+/// Per-stage parameters from the `[stage.params]` table, empty if none are given
+pub type Params = toml::Value;
+
+type PluginFactory = Box<dyn Fn(&Params) -> Fallible<PluginReference> + Send + Sync>;
+type WorkerFactory = Box<dyn Fn(&Params) -> Fallible<Box<dyn AsyncWorker<WorkIO>>> + Send + Sync>;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PipelineConfig {
+    #[serde(rename = "stage", default)]
+    stages: Vec<StageConfig>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum WrapperKind {
+    Internal,
+    External,
+}
+
+impl Display for WrapperKind {
+    fn fmt(self: &Self, formatter: &mut Formatter) -> Result<(), std::fmt::Error> {
+        formatter.write_str(match self {
+            WrapperKind::Internal => "internal",
+            WrapperKind::External => "external",
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StageConfig {
+    name: Spanned<String>,
+    #[serde(default)]
+    wrapper: Option<Spanned<WrapperKind>>,
+    #[serde(default)]
+    worker: Option<Spanned<String>>,
+    #[serde(default)]
+    command: Option<Spanned<String>>,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    depends_on: Vec<Spanned<String>>,
+    #[serde(default)]
+    params: Option<Params>,
+}
+
+impl StageConfig {
+    fn wrapper(self: &Self) -> WrapperKind {
+        self.wrapper
+            .as_ref()
+            .map_or(WrapperKind::Internal, |wrapper| *wrapper.get_ref())
+    }
+
+    fn params(self: &Self) -> Params {
+        self.params
+            .clone()
+            .unwrap_or_else(|| toml::Value::Table(Default::default()))
+    }
+}
+
+/// 1-based line and column in a pipeline config
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Problem with a pipeline config, located where it is known
+#[derive(Debug)]
+pub struct ConfigError {
+    pub source: String,
+    pub location: Option<Location>,
+    pub message: String,
+    /// Error of the TOML parser or of a factory which caused the problem
+    pub cause: Option<Error>,
+}
+
+impl Display for ConfigError {
+    fn fmt(self: &Self, formatter: &mut Formatter) -> Result<(), std::fmt::Error> {
+        match self.location {
+            Some(Location { line, column }) => write!(
+                formatter,
+                "{}:{}:{}: {}",
+                self.source, line, column, self.message
+            ),
+            None => write!(formatter, "{}: {}", self.source, self.message),
+        }
+    }
+}
+
+impl Fail for ConfigError {
+    fn cause(&self) -> Option<&dyn Fail> {
+        self.cause.as_ref().map(Error::as_fail)
+    }
+}
+
+/// Named worker factories from which pipelines are built according to a TOML config.
+///
+/// A config lists the stages in order:
+///
+/// ```toml
+/// [[stage]]
+/// name = "count"
+/// worker = "counter"
+/// wrapper = "internal"
+/// [stage.params]
+/// start = 3
+///
+/// [[stage]]
+/// name = "enrich"
+/// wrapper = "external"
+/// command = "python3"
+/// args = ["enrich.py"]
+/// depends_on = ["count"]
+/// ```
+///
+/// For a `PluginProcessor`, external stages run `command` as an `ExternalPlugin` and
+/// `depends_on` declares the plugin DAG. The params of every stage are also passed to
+/// its plugin's `init` hook. For a `WorkCollection`, `worker` picks a
+/// factory registered for the stage's wrapper and the stages run in the listed order.
+#[derive(Default)]
+pub struct WorkerRegistry {
+    plugins: HashMap<String, PluginFactory>,
+    internal_workers: HashMap<String, WorkerFactory>,
+    external_workers: HashMap<String, WorkerFactory>,
+}
+
+impl WorkerRegistry {
+    pub fn register_plugin<T, F>(self: &mut Self, name: &str, factory: F)
+    where
+        T: InternalPlugin + Sync + Send + 'static,
+        F: Fn(&Params) -> Fallible<T> + Send + Sync + 'static,
+    {
+        self.plugins.insert(
+            name.to_string(),
+            Box::new(move |params| {
+                let plugin: PluginReference =
+                    Box::new(InternalPluginWrapper::new(factory(params)?));
+                Ok(plugin)
+            }),
+        );
+    }
+
+    pub fn register_internal_worker<T, F>(self: &mut Self, name: &str, factory: F)
+    where
+        T: AsyncWorkerInternal<WorkIO> + Sync + Send + Clone + 'static,
+        F: Fn(&Params) -> Fallible<T> + Send + Sync + 'static,
+    {
+        self.internal_workers.insert(
+            name.to_string(),
+            Box::new(move |params| {
+                let worker: Box<dyn AsyncWorker<WorkIO>> =
+                    Box::new(InternalWorkWrapper(factory(params)?));
+                Ok(worker)
+            }),
+        );
+    }
+
+    pub fn register_external_worker<T, F>(self: &mut Self, name: &str, factory: F)
+    where
+        T: AsyncWorkerExternal<WorkIO> + Sync + Send + Clone + 'static,
+        F: Fn(&Params) -> Fallible<T> + Send + Sync + 'static,
+    {
+        self.external_workers.insert(
+            name.to_string(),
+            Box::new(move |params| {
+                let worker: Box<dyn AsyncWorker<WorkIO>> =
+                    Box::new(ExternalWorkWrapper(factory(params)?));
+                Ok(worker)
+            }),
+        );
+    }
+
+    pub fn load_plugin_processor_file(self: &Self, path: &Path) -> Fallible<PluginProcessor> {
+        let config = std::fs::read_to_string(path)?;
+        self.load_plugin_processor(&path.display().to_string(), &config)
+    }
+
+    pub fn load_work_collection_file(self: &Self, path: &Path) -> Fallible<WorkCollection> {
+        let config = std::fs::read_to_string(path)?;
+        self.load_work_collection(&path.display().to_string(), &config)
+    }
+
+    /// Builds a PluginProcessor from the TOML in `config`; `source` names it in errors.
+    pub fn load_plugin_processor(
+        self: &Self,
+        source: &str,
+        config: &str,
+    ) -> Fallible<PluginProcessor> {
+        let locate = |offset: usize, message: String| locate(source, config, offset, message);
+        let pipeline = parse(source, config)?;
+
+        let names: Vec<&str> = pipeline
+            .stages
+            .iter()
+            .map(|stage| stage.name.get_ref().as_str())
+            .collect();
+        let mut nodes = Vec::with_capacity(pipeline.stages.len());
+        for stage in &pipeline.stages {
+            for dependency in &stage.depends_on {
+                if !names.contains(&dependency.get_ref().as_str()) {
+                    return Err(locate(
+                        dependency.start(),
+                        format!("unknown stage '{}'", dependency.get_ref()),
+                    ));
+                }
+            }
+
+            let plugin: PluginReference = match (stage.wrapper(), &stage.worker, &stage.command) {
+                (WrapperKind::Internal, Some(worker), None) => {
+                    let factory = self.plugins.get(worker.get_ref()).ok_or_else(|| {
+                        locate(
+                            worker.start(),
+                            format!("unknown plugin '{}'", worker.get_ref()),
+                        )
+                    })?;
+                    factory(&stage.params()).map_err(|e| {
+                        caused(
+                            source,
+                            config,
+                            Some(stage.name.start()),
+                            "could not create plugin",
+                            e,
+                        )
+                    })?
+                }
+                (WrapperKind::External, None, Some(command)) => {
+                    let args: Vec<&str> = stage.args.iter().map(String::as_str).collect();
+                    Box::new(ExternalPlugin::new(command.get_ref(), &args))
+                }
+                (WrapperKind::Internal, _, _) => {
+                    return Err(locate(
+                        stage.name.start(),
+                        "internal stages need a `worker` and no `command`".to_string(),
+                    ))
+                }
+                (WrapperKind::External, _, _) => {
+                    return Err(locate(
+                        stage.name.start(),
+                        "external stages need a `command` and no `worker`".to_string(),
+                    ))
+                }
+            };
+
+            let config = serde_json::to_value(stage.params()).map_err(|e| {
+                caused(
+                    source,
+                    config,
+                    Some(stage.name.start()),
+                    "could not convert params",
+                    e.into(),
+                )
+            })?;
+
+            nodes.push(PluginNode {
+                name: stage.name.get_ref().clone(),
+                plugin: Arc::new(plugin),
+                dependencies: stage
+                    .depends_on
+                    .iter()
+                    .map(|d| d.get_ref().clone())
+                    .collect(),
+                config,
+            });
+        }
+
+        PluginProcessor::new(nodes).map_err(|e| {
+            let offset = match e.downcast_ref::<crate::plugins::PluginGraphError>() {
+                Some(crate::plugins::PluginGraphError::DuplicateName(name))
+                | Some(crate::plugins::PluginGraphError::UnknownDependency(name, _)) => {
+                    stage_offset(&pipeline, name)
+                }
+                Some(crate::plugins::PluginGraphError::Cycle(names)) => {
+                    stage_offset(&pipeline, &names[0])
+                }
+                None => None,
+            };
+            caused(source, config, offset, "invalid stage dependencies", e)
+        })
+    }
+
+    /// Builds a WorkCollection from the TOML in `config`; `source` names it in errors.
+    pub fn load_work_collection(
+        self: &Self,
+        source: &str,
+        config: &str,
+    ) -> Fallible<WorkCollection> {
+        let locate = |offset: usize, message: String| locate(source, config, offset, message);
+        let pipeline = parse(source, config)?;
+
+        let mut work_collection = WorkCollection::with_capacity(pipeline.stages.len());
+        for stage in &pipeline.stages {
+            if let Some(dependency) = stage.depends_on.first() {
+                return Err(locate(
+                    dependency.start(),
+                    "work collections run their stages in order and don't support `depends_on`"
+                        .to_string(),
+                ));
+            }
+            if let Some(command) = &stage.command {
+                return Err(locate(
+                    command.start(),
+                    "work collections only support registered workers, not `command`".to_string(),
+                ));
+            }
+
+            let worker = stage
+                .worker
+                .as_ref()
+                .ok_or_else(|| locate(stage.name.start(), "stage needs a `worker`".to_string()))?;
+            let factories = match stage.wrapper() {
+                WrapperKind::Internal => &self.internal_workers,
+                WrapperKind::External => &self.external_workers,
+            };
+            let factory = factories.get(worker.get_ref()).ok_or_else(|| {
+                locate(
+                    worker.start(),
+                    format!("unknown {} worker '{}'", stage.wrapper(), worker.get_ref()),
+                )
+            })?;
+            let worker = factory(&stage.params()).map_err(|e| {
+                caused(
+                    source,
+                    config,
+                    Some(stage.name.start()),
+                    "could not create worker",
+                    e,
+                )
+            })?;
+
+            work_collection.push(Arc::new(FuturesMutex::new(worker)));
+        }
+
+        Ok(work_collection)
+    }
+}
+
+fn parse(source: &str, config: &str) -> Fallible<PipelineConfig> {
+    toml::from_str(config).map_err(|e: toml::de::Error| {
+        let location = e.line_col().map(|(line, column)| Location {
+            line: line + 1,
+            column: column + 1,
+        });
+
+        ConfigError {
+            source: source.to_string(),
+            location,
+            message: "invalid TOML".to_string(),
+            cause: Some(e.into()),
+        }
+        .into()
+    })
+}
+
+fn stage_offset(pipeline: &PipelineConfig, name: &str) -> Option<usize> {
+    pipeline
+        .stages
+        .iter()
+        .find(|stage| stage.name.get_ref() == name)
+        .map(|stage| stage.name.start())
+}
+
+fn location(config: &str, offset: usize) -> Location {
+    let before = &config[..offset.min(config.len())];
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+
+    Location {
+        line: before.matches('\n').count() + 1,
+        column: before[line_start..].chars().count() + 1,
+    }
+}
+
+fn locate(source: &str, config: &str, offset: usize, message: String) -> failure::Error {
+    ConfigError {
+        source: source.to_string(),
+        location: Some(location(config, offset)),
+        message,
+        cause: None,
+    }
+    .into()
+}
+
+/// Like `locate`, for problems caused by another error
+fn caused(
+    source: &str,
+    config: &str,
+    offset: Option<usize>,
+    message: &str,
+    cause: Error,
+) -> failure::Error {
+    ConfigError {
+        source: source.to_string(),
+        location: offset.map(|offset| location(config, offset)),
+        message: message.to_string(),
+        cause: Some(cause),
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::AppendingPlugin;
+    use crate::plugins::FutureIO;
+    use crate::wrapped::{process, AsyncResult};
+    use failure::Fallible;
+
+    fn registry() -> WorkerRegistry {
+        let mut registry = WorkerRegistry::default();
+        registry.register_plugin("appending", |params| {
+            Ok(params.clone().try_into::<AppendingPlugin>()?)
+        });
+        registry.register_internal_worker("appending", |params| {
+            Ok(params.clone().try_into::<AppendingPlugin>()?)
+        });
+        registry.register_external_worker("appending", |params| {
+            Ok(params.clone().try_into::<AppendingPlugin>()?)
+        });
+        registry
+    }
+
+    const WORK_COLLECTION_CONFIG: &str = r#"
+[[stage]]
+name = "first"
+worker = "appending"
+params = { suffix = "a" }
+
+[[stage]]
+name = "second"
+worker = "appending"
+wrapper = "external"
+params = { suffix = "b" }
+"#;
+
+    #[test]
+    fn load_pipelines_from_config() -> Fallible<()> {
+        lazy_static! {
+            static ref WORK_COLLECTION: WorkCollection = registry()
+                .load_work_collection("work.toml", WORK_COLLECTION_CONFIG)
+                .expect("invalid config");
+        }
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let result = runtime.block_on(process(WORK_COLLECTION.iter()))?;
+        assert_eq!(result, "ab");
+
+        let plugin_processor = registry().load_plugin_processor(
+            "plugins.toml",
+            r#"
+[[stage]]
+name = "first"
+worker = "appending"
+params = { suffix = "a" }
+
+[[stage]]
+name = "echo"
+wrapper = "external"
+command = "cat"
+depends_on = ["first"]
+"#,
+        )?;
+        runtime.block_on(plugin_processor.start())?;
+        let result = runtime.block_on(plugin_processor.process("_".to_string()))?;
+        assert_eq!(result, "_a");
+        runtime.block_on(plugin_processor.shutdown())?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn report_config_errors_with_location() {
+        let location = |result: Fallible<WorkCollection>| match result
+            .map_err(|e| e.downcast::<ConfigError>())
+        {
+            Err(Ok(e)) => {
+                let location = e.location.expect("config error without a location");
+                let chain: Vec<String> = (&e as &dyn Fail)
+                    .iter_chain()
+                    .map(|e| e.to_string())
+                    .collect();
+                (location.line, location.column, chain.join(": "))
+            }
+            other => panic!("expected a config error, got {:?}", other.map(|_| ())),
+        };
+
+        let (line, column, message) = location(registry().load_work_collection(
+            "work.toml",
+            "[[stage]]\nname = \"first\"\nworker = \"Missing\"\n",
+        ));
+        assert_eq!((line, column), (3, 10));
+        assert_eq!(message, "work.toml:3:10: unknown internal worker 'Missing'");
+
+        let (line, column, message) =
+            location(registry().load_work_collection("work.toml", "[[stage]]\nname = 3\n"));
+        assert_eq!((line, column), (2, 8));
+        assert!(message.starts_with("work.toml:2:8: invalid TOML: invalid type"));
+
+        let (line, column, message) = location(registry().load_work_collection(
+            "work.toml",
+            "[[stage]]\nname = \"first\"\nworker = \"appending\"\n",
+        ));
+        assert_eq!((line, column), (2, 8));
+        assert!(message.contains("could not create worker"));
+    }
+}
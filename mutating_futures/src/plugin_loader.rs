@@ -0,0 +1,514 @@
+use crate::plugins::{
+    Checkpoint, FutureIO, InternalPlugin, InternalPluginWrapper, PluginConfig, PluginProcessor,
+    PluginReference,
+};
+use core::fmt::Debug;
+use core::fmt::{Display, Formatter};
+use failure::{Error, Fail, Fallible};
+use futures::Future;
+use libloading::Library;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Version of the registration ABI between the processor and plugin libraries.
+///
+/// Bump this whenever the layout of `PluginDeclaration` changes. Its `abi_version`
+/// stays the first field, so libraries with another layout can still be told apart.
+pub const PLUGIN_ABI_VERSION: u32 = 1;
+
+/// Name of the static every plugin library exports, see `export_plugins!`
+pub const PLUGIN_DECLARATION_SYMBOL: &str = "mutating_futures_plugin_declaration";
+
+/// Size of the version fields of a PluginDeclaration
+pub const VERSION_FIELD_LEN: usize = 128;
+
+/// Version of the compiler which built this crate
+pub const RUSTC_VERSION: &str = env!("MUTATING_FUTURES_RUSTC_VERSION");
+
+/// Version of this crate
+pub const CRATE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Copies `version` into a zero-padded version field, cut off at its size.
+pub const fn version_field(version: &str) -> [u8; VERSION_FIELD_LEN] {
+    let bytes = version.as_bytes();
+    let mut field = [0; VERSION_FIELD_LEN];
+    let mut index = 0;
+    while index < bytes.len() && index < VERSION_FIELD_LEN {
+        field[index] = bytes[index];
+        index += 1;
+    }
+    field
+}
+
+fn version_field_str(field: &[u8; VERSION_FIELD_LEN]) -> String {
+    let end = field
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// Entry point exported by plugin libraries.
+///
+/// Only the declaration itself has a C layout. The plugins are handed over as Rust
+/// trait objects through a `PluginRegistrar`, whose layouts are only the same for the
+/// same compiler and the same version of this crate. So libraries built with another
+/// compiler or against another version are refused, and `register` is only called
+/// once the ABI version and both build versions match. The versions of the other
+/// crates both share, e.g. `failure` and `futures`, are not checked, so libraries must
+/// be built with the Cargo.lock of the processor.
+#[repr(C)]
+pub struct PluginDeclaration {
+    pub abi_version: u32,
+    /// `RUSTC_VERSION` of the library, see `version_field`
+    pub rustc_version: [u8; VERSION_FIELD_LEN],
+    /// `CRATE_VERSION` of the library, see `version_field`
+    pub crate_version: [u8; VERSION_FIELD_LEN],
+    pub register: unsafe extern "C" fn(registrar: *mut PluginRegistrar),
+}
+
+/// Collects the plugins a library registers from within its `register` function.
+///
+/// It is a Rust type, see `PluginDeclaration` for why that is safe.
+#[derive(Default)]
+pub struct PluginRegistrar {
+    plugins: Vec<(String, Box<dyn InternalPlugin + Sync + Send>)>,
+}
+
+impl PluginRegistrar {
+    pub fn register(self: &mut Self, name: &str, plugin: Box<dyn InternalPlugin + Sync + Send>) {
+        self.plugins.push((name.to_string(), plugin));
+    }
+}
+
+/// Declares the registration entry point of a plugin library.
+///
+/// ```ignore
+/// fn register(registrar: &mut PluginRegistrar) {
+///     registrar.register("upper", Box::new(Upper));
+/// }
+///
+/// export_plugins!(register);
+/// ```
+#[macro_export]
+macro_rules! export_plugins {
+    ($register:expr) => {
+        #[doc(hidden)]
+        #[no_mangle]
+        pub static mutating_futures_plugin_declaration: $crate::plugin_loader::PluginDeclaration =
+            $crate::plugin_loader::PluginDeclaration {
+                abi_version: $crate::plugin_loader::PLUGIN_ABI_VERSION,
+                rustc_version: $crate::plugin_loader::version_field(
+                    $crate::plugin_loader::RUSTC_VERSION,
+                ),
+                crate_version: $crate::plugin_loader::version_field(
+                    $crate::plugin_loader::CRATE_VERSION,
+                ),
+                register: {
+                    // named apart from the function passed in, which it would shadow
+                    unsafe extern "C" fn register_plugins(
+                        registrar: *mut $crate::plugin_loader::PluginRegistrar,
+                    ) {
+                        let register: fn(&mut $crate::plugin_loader::PluginRegistrar) = $register;
+                        register(&mut *registrar)
+                    }
+                    register_plugins
+                },
+            };
+    };
+}
+
+#[derive(Debug, Fail)]
+pub enum PluginLoadError {
+    #[fail(display = "could not load plugin library {:?}: {}", _0, _1)]
+    Library(PathBuf, String),
+    #[fail(
+        display = "{:?} is not a plugin library, it does not export `{}`",
+        _0, _1
+    )]
+    MissingDeclaration(PathBuf, &'static str),
+    #[fail(
+        display = "plugin library {:?} was built for plugin ABI version {}, but version {} is required",
+        _0, _1, _2
+    )]
+    AbiMismatch(PathBuf, u32, u32),
+    #[fail(
+        display = "plugin library {:?} was built with {} '{}', but '{}' is required",
+        path, build, found, required
+    )]
+    BuildMismatch {
+        path: PathBuf,
+        /// "rustc" or "mutating_futures"
+        build: &'static str,
+        found: String,
+        required: String,
+    },
+}
+
+/// Failure of a plugin from a shared library, which keeps the library loaded for as long
+/// as the failure is alive.
+///
+/// Like `SharedError`, it displays like the failure, which is its cause.
+#[derive(Debug)]
+pub struct LibraryError {
+    // declared first so it is dropped before the library
+    error: Error,
+    _library: Arc<Library>,
+}
+
+impl LibraryError {
+    pub fn error(self: &Self) -> &Error {
+        &self.error
+    }
+}
+
+impl Display for LibraryError {
+    fn fmt(self: &Self, formatter: &mut Formatter) -> Result<(), std::fmt::Error> {
+        write!(formatter, "{}", self.error)
+    }
+}
+
+impl Fail for LibraryError {
+    fn cause(&self) -> Option<&dyn Fail> {
+        Some(self.error.as_fail())
+    }
+}
+
+/// InternalPlugin from a shared library, which keeps the library loaded for as long as
+/// the plugin or any of its futures and failures are alive
+struct LoadedPlugin {
+    // declared first so it is dropped before the library
+    plugin: Box<dyn InternalPlugin + Sync + Send>,
+    library: Arc<Library>,
+}
+
+impl LoadedPlugin {
+    /// Keeps the library loaded until `future` and its failure are gone.
+    fn keep_loaded<T: Send + 'static>(
+        self: &Self,
+        future: FutureIO<'static, T>,
+    ) -> FutureIO<'static, T> {
+        let library = self.library.clone();
+
+        Box::new(future.then(move |result| {
+            match result {
+                Ok(item) => Ok(item),
+                Err(error) => Err(LibraryError {
+                    error,
+                    _library: library,
+                }
+                .into()),
+            }
+        }))
+    }
+}
+
+impl Debug for LoadedPlugin {
+    fn fmt(self: &Self, formatter: &mut Formatter) -> Result<(), std::fmt::Error> {
+        write!(formatter, "LoadedPlugin({:?})", self.plugin)
+    }
+}
+
+impl InternalPlugin for LoadedPlugin {
+    fn run_internal(self: &mut Self, input: String) -> FutureIO<'static, String> {
+        let future = self.plugin.run_internal(input);
+        self.keep_loaded(future)
+    }
+
+    fn init(self: &mut Self, config: &PluginConfig) -> FutureIO<'static, ()> {
+        let future = self.plugin.init(config);
+        self.keep_loaded(future)
+    }
+
+    fn warmup(self: &mut Self) -> FutureIO<'static, ()> {
+        let future = self.plugin.warmup();
+        self.keep_loaded(future)
+    }
+
+    fn health(self: &mut Self) -> FutureIO<'static, ()> {
+        let future = self.plugin.health();
+        self.keep_loaded(future)
+    }
+
+    fn shutdown(self: &mut Self) -> FutureIO<'static, ()> {
+        let future = self.plugin.shutdown();
+        self.keep_loaded(future)
+    }
+
+    fn as_checkpoint(self: &mut Self) -> Option<&mut dyn Checkpoint> {
+        if self.plugin.as_checkpoint().is_some() {
+            Some(self)
+        } else {
+            None
+        }
+    }
+}
+
+/// Checkpoint of the loaded plugin, whose failures keep the library loaded as well
+impl Checkpoint for LoadedPlugin {
+    fn snapshot(self: &mut Self) -> Fallible<Vec<u8>> {
+        let library = self.library.clone();
+        self.plugin
+            .as_checkpoint()
+            .expect("checked by as_checkpoint")
+            .snapshot()
+            .map_err(|error| {
+                LibraryError {
+                    error,
+                    _library: library,
+                }
+                .into()
+            })
+    }
+
+    fn restore(self: &mut Self, snapshot: &[u8]) -> Fallible<()> {
+        let library = self.library.clone();
+        self.plugin
+            .as_checkpoint()
+            .expect("checked by as_checkpoint")
+            .restore(snapshot)
+            .map_err(|error| {
+                LibraryError {
+                    error,
+                    _library: library,
+                }
+                .into()
+            })
+    }
+}
+
+fn check_declaration(path: &Path, declaration: &PluginDeclaration) -> Fallible<()> {
+    if declaration.abi_version != PLUGIN_ABI_VERSION {
+        return Err(PluginLoadError::AbiMismatch(
+            path.to_path_buf(),
+            declaration.abi_version,
+            PLUGIN_ABI_VERSION,
+        )
+        .into());
+    }
+
+    for (build, found, required) in &[
+        ("rustc", &declaration.rustc_version, RUSTC_VERSION),
+        (
+            "mutating_futures",
+            &declaration.crate_version,
+            CRATE_VERSION,
+        ),
+    ] {
+        if **found != version_field(required) {
+            return Err(PluginLoadError::BuildMismatch {
+                path: path.to_path_buf(),
+                build,
+                found: version_field_str(found),
+                required: version_field_str(&version_field(required)),
+            }
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Loads all plugins registered by the shared library at `path`.
+pub fn load_plugin_library(path: &Path) -> Fallible<Vec<(String, PluginReference)>> {
+    let library = Library::new(path)
+        .map_err(|e| PluginLoadError::Library(path.to_path_buf(), e.to_string()))?;
+
+    let mut registrar = PluginRegistrar::default();
+    unsafe {
+        let declaration = library
+            .get::<*const PluginDeclaration>(format!("{}\0", PLUGIN_DECLARATION_SYMBOL).as_bytes())
+            .map_err(|_| {
+                PluginLoadError::MissingDeclaration(path.to_path_buf(), PLUGIN_DECLARATION_SYMBOL)
+            })?;
+        let declaration: &PluginDeclaration = &**declaration;
+
+        check_declaration(path, declaration)?;
+        (declaration.register)(&mut registrar);
+    }
+
+    let library = Arc::new(library);
+    Ok(registrar
+        .plugins
+        .into_iter()
+        .map(|(name, plugin)| {
+            let plugin: PluginReference = Box::new(InternalPluginWrapper::new(LoadedPlugin {
+                plugin,
+                library: library.clone(),
+            }));
+            (name, plugin)
+        })
+        .collect())
+}
+
+/// Loads the plugins of all shared libraries in `dir`, in file name order.
+pub fn load_plugin_dir(dir: &Path) -> Fallible<Vec<(String, PluginReference)>> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+    paths.retain(|path| {
+        path.extension().and_then(|extension| extension.to_str())
+            == Some(std::env::consts::DLL_EXTENSION)
+    });
+    paths.sort();
+
+    let mut plugins = Vec::new();
+    for path in paths {
+        plugins.extend(load_plugin_library(&path)?);
+    }
+
+    Ok(plugins)
+}
+
+impl PluginProcessor {
+    /// Builds a processor which runs the plugins from all shared libraries in `dir`
+    /// one after another.
+    pub fn from_plugin_dir(dir: &Path) -> Fallible<Self> {
+        Self::chain(load_plugin_dir(dir)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Upper;
+
+    impl InternalPlugin for Upper {
+        fn run_internal(self: &mut Self, io: String) -> FutureIO<'static, String> {
+            Box::new(futures::future::ok(io.to_uppercase()))
+        }
+    }
+
+    export_plugins!(|registrar| registrar.register("upper", Box::new(Upper)));
+
+    unsafe extern "C" fn register_nothing(_: *mut PluginRegistrar) {}
+
+    #[test]
+    fn register_exported_plugins() {
+        let mut registrar = PluginRegistrar::default();
+        check_declaration(Path::new("test"), &mutating_futures_plugin_declaration).unwrap();
+        unsafe { (mutating_futures_plugin_declaration.register)(&mut registrar) };
+
+        assert_eq!(registrar.plugins.len(), 1);
+        assert_eq!(registrar.plugins[0].0, "upper");
+    }
+
+    /// Builds the plugin library in `test_plugin` with the cargo running the tests and
+    /// the Cargo.lock of this crate.
+    fn build_test_plugin() -> PathBuf {
+        let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let target_dir = manifest_dir.join("target").join("test_plugin");
+        let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+
+        let workspace_manifest = std::process::Command::new(&cargo)
+            .current_dir(manifest_dir)
+            .args(["locate-project", "--workspace", "--message-format", "plain"])
+            .output()
+            .expect("could not run cargo");
+        let workspace_manifest =
+            PathBuf::from(String::from_utf8(workspace_manifest.stdout).unwrap().trim());
+        std::fs::copy(
+            workspace_manifest.with_file_name("Cargo.lock"),
+            manifest_dir.join("test_plugin").join("Cargo.lock"),
+        )
+        .expect("could not copy the Cargo.lock");
+
+        let status = std::process::Command::new(cargo)
+            .arg("build")
+            .arg("--manifest-path")
+            .arg(manifest_dir.join("test_plugin").join("Cargo.toml"))
+            .arg("--target-dir")
+            .arg(&target_dir)
+            .status()
+            .expect("could not run cargo");
+        assert!(status.success(), "could not build the test plugin");
+
+        target_dir.join("debug").join(format!(
+            "{}mutating_futures_test_plugin.{}",
+            std::env::consts::DLL_PREFIX,
+            std::env::consts::DLL_EXTENSION
+        ))
+    }
+
+    #[test]
+    fn load_plugin_library_from_cdylib() -> Fallible<()> {
+        let mut plugins = load_plugin_library(&build_test_plugin())?;
+        assert_eq!(plugins.len(), 1);
+        let (name, plugin) = plugins.remove(0);
+        assert_eq!(name, "suffix");
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(plugin.init(&serde_json::json!({ "suffix": "!" })))?;
+        assert_eq!(runtime.block_on(plugin.run("a".to_string()))?, "a!");
+        let error = runtime
+            .block_on(plugin.run("fail".to_string()))
+            .expect_err("plugin should fail on \"fail\"");
+
+        // the failure still works once the plugin and its library handle are gone
+        drop(plugin);
+        assert_eq!(error.to_string(), "suffix plugin failed on fail");
+        assert!(error.downcast_ref::<LibraryError>().is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn refuse_mismatched_plugin_libraries() {
+        let path = Path::new("libmismatched.so");
+        let declaration = PluginDeclaration {
+            abi_version: PLUGIN_ABI_VERSION + 1,
+            rustc_version: version_field(RUSTC_VERSION),
+            crate_version: version_field(CRATE_VERSION),
+            register: register_nothing,
+        };
+
+        match check_declaration(path, &declaration).map_err(|e| e.downcast::<PluginLoadError>()) {
+            Err(Ok(PluginLoadError::AbiMismatch(_, found, required))) => {
+                assert_eq!(found, PLUGIN_ABI_VERSION + 1);
+                assert_eq!(required, PLUGIN_ABI_VERSION);
+            }
+            other => panic!("expected an ABI mismatch, got {:?}", other.map(|_| ())),
+        }
+
+        // the same ABI version from another compiler is refused as well
+        let declaration = PluginDeclaration {
+            abi_version: PLUGIN_ABI_VERSION,
+            rustc_version: version_field("rustc 1.0.0"),
+            ..declaration
+        };
+        match check_declaration(path, &declaration).map_err(|e| e.downcast::<PluginLoadError>()) {
+            Err(Ok(PluginLoadError::BuildMismatch {
+                build,
+                found,
+                required,
+                ..
+            })) => {
+                assert_eq!((build, found.as_str()), ("rustc", "rustc 1.0.0"));
+                assert_eq!(required, RUSTC_VERSION);
+            }
+            other => panic!("expected a build mismatch, got {:?}", other.map(|_| ())),
+        }
+
+        let dir =
+            std::env::temp_dir().join(format!("mutating_futures_plugins_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join(format!("libbroken.{}", std::env::consts::DLL_EXTENSION)),
+            "not a library",
+        )
+        .unwrap();
+        std::fs::write(dir.join("README"), "ignored").unwrap();
+
+        let result = PluginProcessor::from_plugin_dir(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        match result.map_err(|e| e.downcast::<PluginLoadError>()) {
+            Err(Ok(PluginLoadError::Library(path, _))) => {
+                assert!(path.ends_with(format!("libbroken.{}", std::env::consts::DLL_EXTENSION)))
+            }
+            other => panic!("expected a library error, got {:?}", other.map(|_| ())),
+        }
+    }
+}
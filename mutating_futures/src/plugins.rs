@@ -2,11 +2,17 @@ use failure::Fallible;
 use std::collections::HashMap;
 use futures::stream::Stream;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use core::fmt::Debug;
 use futures::Future;
-use futures::future::IntoFuture;
+use futures::future::{IntoFuture, Shared};
 use failure::Error;
 use futures_locks::Mutex as FuturesMutex;
+use crate::introspection::StageKind;
+use crate::lock_monitor::locking;
+use core::fmt::{Display, Formatter};
+use failure::Fail;
 
 /// Convenience type to wrap other types in a Future
 pub type FutureIO<'a, T> = Box<dyn Future<Item = T, Error = Error> + Send + 'a>;
@@ -14,6 +20,44 @@ pub type FutureIO<'a, T> = Box<dyn Future<Item = T, Error = Error> + Send + 'a>;
 // /// Convenience type for the thread-safe storage of plugins
 pub type PluginReference = Box<dyn Plugin<String> + Sync + Send>;
 
+/// Configuration handed to a plugin's `init` hook, `Null` if none is given
+pub type PluginConfig = serde_json::Value;
+
+/// Failure of a future whose result is shared by several consumers.
+///
+/// It displays like the failure, which is its cause, so the failure can still be
+/// found with `iter_causes` and downcast.
+#[derive(Debug, Clone)]
+pub struct SharedError(Arc<Error>);
+
+impl SharedError {
+    pub fn error(self: &Self) -> &Error {
+        &self.0
+    }
+}
+
+impl From<Error> for SharedError {
+    /// Wraps `error`, unless it already is a SharedError.
+    fn from(error: Error) -> Self {
+        match error.downcast::<SharedError>() {
+            Ok(shared) => shared,
+            Err(error) => SharedError(Arc::new(error)),
+        }
+    }
+}
+
+impl Display for SharedError {
+    fn fmt(self: &Self, formatter: &mut Formatter) -> Result<(), std::fmt::Error> {
+        write!(formatter, "{}", self.0)
+    }
+}
+
+impl Fail for SharedError {
+    fn cause(&self) -> Option<&dyn Fail> {
+        Some(self.0.as_fail())
+    }
+}
+
 /// Trait which fronts InternalPlugin and ExternalPlugin, allowing their trait objects to live in the same collection
 pub trait Plugin<T>
 where
@@ -21,6 +65,49 @@ where
     T: Sync + Send,
 {
     fn run(self: &Self, t: T) -> FutureIO<'static, T>;
+
+    /// Whether the plugin runs in this process or in a child process.
+    fn kind(self: &Self) -> StageKind {
+        StageKind::Internal
+    }
+
+    /// Sets the plugin up, e.g. opens its connections and files.
+    fn init(self: &Self, _config: &PluginConfig) -> FutureIO<'static, ()> {
+        Box::new(futures::future::ok(()))
+    }
+
+    /// Prepares the plugin for its first run once every plugin is initialized.
+    fn warmup(self: &Self) -> FutureIO<'static, ()> {
+        Box::new(futures::future::ok(()))
+    }
+
+    /// Fails if the plugin is currently unable to run.
+    fn health(self: &Self) -> FutureIO<'static, ()> {
+        Box::new(futures::future::ok(()))
+    }
+
+    /// Tears the plugin down gracefully after its last run.
+    fn shutdown(self: &Self) -> FutureIO<'static, ()> {
+        Box::new(futures::future::ok(()))
+    }
+
+    /// Serializes the plugin's state, or returns None if it has no state worth keeping.
+    fn snapshot(self: &Self) -> FutureIO<'static, Option<Vec<u8>>> {
+        Box::new(futures::future::ok(None))
+    }
+
+    /// Replaces the plugin's state with a snapshot taken earlier.
+    fn restore(self: &Self, _snapshot: Vec<u8>) -> FutureIO<'static, ()> {
+        Box::new(futures::future::err(failure::err_msg(
+            "plugin does not support checkpoints",
+        )))
+    }
+}
+
+/// Opt-in trait for plugins whose state should survive a restart
+pub trait Checkpoint {
+    fn snapshot(self: &mut Self) -> Fallible<Vec<u8>>;
+    fn restore(self: &mut Self, snapshot: &[u8]) -> Fallible<()>;
 }
 
 /// Trait to be implemented by internal plugins with their native IO type
@@ -28,78 +115,557 @@ pub trait InternalPlugin
 where
     Self: Debug,
 {
-    fn run_internal(self: &mut Self, input: String) -> FutureIO<String>;
+    fn run_internal(self: &mut Self, input: String) -> FutureIO<'static, String>;
+
+    /// See `Plugin::init`
+    fn init(self: &mut Self, _config: &PluginConfig) -> FutureIO<'static, ()> {
+        Box::new(futures::future::ok(()))
+    }
+
+    /// See `Plugin::warmup`
+    fn warmup(self: &mut Self) -> FutureIO<'static, ()> {
+        Box::new(futures::future::ok(()))
+    }
+
+    /// See `Plugin::health`
+    fn health(self: &mut Self) -> FutureIO<'static, ()> {
+        Box::new(futures::future::ok(()))
+    }
+
+    /// See `Plugin::shutdown`
+    fn shutdown(self: &mut Self) -> FutureIO<'static, ()> {
+        Box::new(futures::future::ok(()))
+    }
+
+    /// Returns the plugin as a Checkpoint if its state should be checkpointed.
+    fn as_checkpoint(self: &mut Self) -> Option<&mut dyn Checkpoint> {
+        None
+    }
 }
 
 /// Wrapper struct for a universal implementation of Plugin<PluginIO> for all InternalPlugin implementors
 #[derive(Debug, Clone)]
 pub struct InternalPluginWrapper<T>(Arc<FuturesMutex<T>>);
 
+impl<T> InternalPluginWrapper<T> {
+    pub fn new(plugin: T) -> Self {
+        InternalPluginWrapper(Arc::new(FuturesMutex::new(plugin)))
+    }
+
+    /// Calls `f` with the locked plugin.
+    fn with_plugin<F, R>(self: &Self, f: F) -> FutureIO<'static, R>
+    where
+        T: Send + Sync + 'static,
+        F: FnOnce(&mut T) -> FutureIO<'static, R> + Send + 'static,
+        R: Send + 'static,
+    {
+        Box::new(
+            locking(&self.0)
+                .map_err(|_| failure::err_msg("could not acquire the plugin mutex"))
+                .and_then(move |mut guard| f(&mut *guard)),
+        )
+    }
+}
+
 /// This implementation allows the process function to run ipmlementors of
 /// InternalPlugin
-impl<T> Plugin<String> for Arc<InternalPluginWrapper<T>>
+impl<T> Plugin<String> for InternalPluginWrapper<T>
 where
-    Self: Sync + Send + Clone + 'static,
     T: InternalPlugin,
-    T: Sync + Send + Clone + 'static,
+    T: Sync + Send + 'static,
 {
     fn run(self: &Self, plugin_io: String) -> FutureIO<'static, String> {
-        // Box::new(futures::future::ok(plugin_io))
+        self.with_plugin(|plugin| plugin.run_internal(plugin_io))
+    }
 
+    fn init(self: &Self, config: &PluginConfig) -> FutureIO<'static, ()> {
+        let config = config.clone();
+        self.with_plugin(move |plugin| plugin.init(&config))
+    }
+
+    fn warmup(self: &Self) -> FutureIO<'static, ()> {
+        self.with_plugin(|plugin| plugin.warmup())
+    }
+
+    fn health(self: &Self) -> FutureIO<'static, ()> {
+        self.with_plugin(|plugin| plugin.health())
+    }
+
+    fn shutdown(self: &Self) -> FutureIO<'static, ()> {
+        self.with_plugin(|plugin| plugin.shutdown())
+    }
+
+    fn snapshot(self: &Self) -> FutureIO<'static, Option<Vec<u8>>> {
         Box::new(
-            self.0.clone().lock()
+            locking(&self.0)
                 .map_err(|_| failure::err_msg("could not acquire the plugin mutex"))
-                .map(|mut guard| {
-                    futures::future::ok::<String, Error>(plugin_io)
-                        .and_then(|internal_io| guard.run_internal(internal_io))
-                        .and_then(|internal_io| {
-                            let plugin_io: Fallible<String> = Ok(internal_io.to_owned());
-                            plugin_io
-                        })
-                })
-                .flatten(),
+                .and_then(|mut guard| {
+                    guard
+                        .as_checkpoint()
+                        .map(|checkpoint| checkpoint.snapshot())
+                        .transpose()
+                }),
+        )
+    }
+
+    fn restore(self: &Self, snapshot: Vec<u8>) -> FutureIO<'static, ()> {
+        Box::new(
+            locking(&self.0)
+                .map_err(|_| failure::err_msg("could not acquire the plugin mutex"))
+                .and_then(move |mut guard| match guard.as_checkpoint() {
+                    Some(checkpoint) => checkpoint.restore(&snapshot),
+                    None => Err(failure::err_msg("plugin does not support checkpoints")),
+                }),
         )
     }
 }
 
+/// A named plugin together with the names of the plugins whose output it consumes.
+///
+/// Nodes without dependencies receive the initial IO of the processor.
 #[derive(Debug, Clone)]
-pub struct PluginProcessor
-{
-    plugins: Arc<Vec<Arc<PluginReference>>>,
+pub struct PluginNode {
+    pub name: String,
+    pub plugin: Arc<PluginReference>,
+    pub dependencies: Vec<String>,
+    /// Passed to the plugin's `init` hook by `PluginProcessor::start`
+    pub config: PluginConfig,
+}
+
+impl PluginNode {
+    pub fn new(name: &str, plugin: PluginReference, dependencies: &[&str]) -> Self {
+        PluginNode {
+            name: name.to_string(),
+            plugin: Arc::new(plugin),
+            dependencies: dependencies.iter().map(|d| d.to_string()).collect(),
+            config: PluginConfig::Null,
+        }
+    }
+
+    pub fn with_config(mut self: Self, config: PluginConfig) -> Self {
+        self.config = config;
+        self
+    }
+}
+
+#[derive(Debug, Fail)]
+pub enum PluginGraphError {
+    #[fail(display = "plugin name '{}' is used more than once", _0)]
+    DuplicateName(String),
+    #[fail(display = "plugin '{}' depends on unknown plugin '{}'", _0, _1)]
+    UnknownDependency(String, String),
+    #[fail(display = "plugin dependencies form a cycle between {:?}", _0)]
+    Cycle(Vec<String>),
+}
+
+#[derive(Debug, Fail)]
+pub enum LifecycleError {
+    #[fail(display = "plugin '{}' failed to {}", plugin, hook)]
+    HookFailed {
+        plugin: String,
+        hook: &'static str,
+        #[cause]
+        cause: Error,
+    },
+    #[fail(display = "plugin '{}' is unhealthy", plugin)]
+    Unhealthy {
+        plugin: String,
+        #[cause]
+        cause: Error,
+    },
+    #[fail(display = "plugin processor is not ready, it is {:?}", _0)]
+    NotReady(LifecycleState),
+    #[fail(display = "plugin processor was shut down and can't be started again")]
+    ShutDown,
+}
+
+/// Where a PluginProcessor is in the lifecycle of its plugins
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LifecycleState {
+    /// `start` has not succeeded yet
+    Created,
+    /// All plugins are healthy and runs are accepted
+    Ready,
+    /// The last health check since the start failed
+    Unhealthy,
+    /// `shutdown` was called
+    Stopped,
+}
+
+/// Combines the outputs of several plugins into the input of a join node
+pub type MergeFn = fn(Vec<String>) -> String;
+
+type SharedIO = Shared<Box<dyn Future<Item = String, Error = SharedError> + Send>>;
+
+#[derive(Debug, Clone)]
+pub struct PluginProcessor {
+    nodes: Arc<Vec<PluginNode>>,
+    /// Dependency indices for every node in `nodes`
+    dependencies: Arc<Vec<Vec<usize>>>,
+    /// Node indices in topological order
+    order: Arc<Vec<usize>>,
+    merge: MergeFn,
+    state: Arc<Mutex<LifecycleState>>,
+    /// Whether the `init` hook of every node in `nodes` has succeeded
+    initialized: Arc<Vec<AtomicBool>>,
 }
 
 impl PluginProcessor {
-    /// Processes all given Plugins sequentially.
-    ///
-    /// This function automatically converts between the different IO representations
-    /// if necessary.
-    pub fn process<'a: 'b, 'b>(
-        self: &'a Self,
-        initial_io: String,
-    ) -> FutureIO<'b, String> {
-        let future_result = futures::stream::iter_ok(0..self.plugins.len())
-            .fold(
-                String::new(),
-                move |last_io, next_plugin_index| match self.plugins.clone().get(next_plugin_index).clone() {
-                    Some(next_plugin) => next_plugin.clone().run(last_io),
-                    None => Box::new(futures::future::err(failure::err_msg(format!(
-                        "could not find plugin at index {}",
-                        next_plugin_index
-                    )))),
+    /// Builds the plugin DAG, rejecting unknown dependencies and cycles.
+    pub fn new(nodes: Vec<PluginNode>) -> Fallible<Self> {
+        let mut indices: HashMap<&str, usize> = HashMap::with_capacity(nodes.len());
+        for (index, node) in nodes.iter().enumerate() {
+            if indices.insert(&node.name, index).is_some() {
+                return Err(PluginGraphError::DuplicateName(node.name.clone()).into());
+            }
+        }
+
+        let mut dependencies = Vec::with_capacity(nodes.len());
+        for node in &nodes {
+            let mut node_dependencies = Vec::with_capacity(node.dependencies.len());
+            for dependency in &node.dependencies {
+                match indices.get(dependency.as_str()) {
+                    Some(index) => node_dependencies.push(*index),
+                    None => {
+                        return Err(PluginGraphError::UnknownDependency(
+                            node.name.clone(),
+                            dependency.clone(),
+                        )
+                        .into())
+                    }
+                }
+            }
+            dependencies.push(node_dependencies);
+        }
+
+        // Kahn's algorithm; whatever is left unsorted is part of a cycle
+        let mut in_degree: Vec<usize> = dependencies.iter().map(Vec::len).collect();
+        let mut ready: Vec<usize> = (0..nodes.len()).filter(|i| in_degree[*i] == 0).collect();
+        let mut order = Vec::with_capacity(nodes.len());
+        while let Some(index) = ready.pop() {
+            order.push(index);
+            for (dependent, node_dependencies) in dependencies.iter().enumerate() {
+                for _ in node_dependencies.iter().filter(|d| **d == index) {
+                    in_degree[dependent] -= 1;
+                    if in_degree[dependent] == 0 {
+                        ready.push(dependent);
+                    }
                 }
+            }
+        }
+
+        if order.len() < nodes.len() {
+            return Err(PluginGraphError::Cycle(
+                (0..nodes.len())
+                    .filter(|i| in_degree[*i] > 0)
+                    .map(|i| nodes[i].name.clone())
+                    .collect(),
             )
-            .into_future()
-            .and_then(|final_io| {
-                Ok(final_io)
-            });
+            .into());
+        }
+
+        let initialized = nodes.iter().map(|_| AtomicBool::new(false)).collect();
+        Ok(PluginProcessor {
+            nodes: Arc::new(nodes),
+            dependencies: Arc::new(dependencies),
+            order: Arc::new(order),
+            merge: |outputs| outputs.concat(),
+            state: Arc::new(Mutex::new(LifecycleState::Created)),
+            initialized: Arc::new(initialized),
+        })
+    }
+
+    /// Builds a processor which runs the given plugins one after another.
+    pub fn chain(plugins: Vec<(String, PluginReference)>) -> Fallible<Self> {
+        let mut previous: Option<String> = None;
+        let nodes = plugins
+            .into_iter()
+            .map(|(name, plugin)| {
+                let dependencies = previous.replace(name.clone()).into_iter().collect();
+                PluginNode {
+                    name,
+                    plugin: Arc::new(plugin),
+                    dependencies,
+                    config: PluginConfig::Null,
+                }
+            })
+            .collect();
+
+        Self::new(nodes)
+    }
+
+    pub fn nodes(self: &Self) -> &[PluginNode] {
+        &self.nodes
+    }
+
+    /// Replaces the function which combines the outputs at join nodes.
+    ///
+    /// The default concatenates the outputs in the order the dependencies are declared.
+    pub fn with_merge(mut self: Self, merge: MergeFn) -> Self {
+        self.merge = merge;
+        self
+    }
+
+    pub fn state(self: &Self) -> LifecycleState {
+        *self.state.lock().unwrap()
+    }
+
+    /// Calls `hook` on all plugins concurrently and returns the first failure once
+    /// every hook has finished.
+    fn run_hook<F, E>(self: &Self, hook: F, error: E) -> FutureIO<'static, ()>
+    where
+        F: Fn(usize, &PluginNode) -> FutureIO<'static, ()>,
+        E: Fn(String, Error) -> LifecycleError + Send + Sync + 'static,
+    {
+        let error = Arc::new(error);
+        let hooks: Vec<_> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(index, node)| {
+                let name = node.name.clone();
+                let error = error.clone();
+                hook(index, node)
+                    .then(move |result| Ok::<_, Error>(result.map_err(|e| error(name, e))))
+            })
+            .collect();
+
+        Box::new(futures::future::join_all(hooks).and_then(|results| {
+            match results.into_iter().find_map(Result::err) {
+                Some(e) => Err(e.into()),
+                None => Ok(()),
+            }
+        }))
+    }
+
+    /// Initializes, warms up and health checks all plugins, in that order, and
+    /// accepts runs once every plugin is healthy.
+    ///
+    /// Starting again after a failed start or health check only initializes the
+    /// plugins whose `init` hasn't succeeded yet. A processor which was shut down
+    /// can't be started again.
+    pub fn start(self: &Self) -> FutureIO<'static, ()> {
+        self.start_with(|| Box::new(futures::future::ok(())))
+    }
+
+    /// Like `start`, but runs `restore` once the plugins are initialized and before
+    /// they are warmed up.
+    pub(crate) fn start_with<R>(self: &Self, restore: R) -> FutureIO<'static, ()>
+    where
+        R: FnOnce() -> FutureIO<'static, ()> + Send + 'static,
+    {
+        if self.state() == LifecycleState::Stopped {
+            return Box::new(futures::future::err(LifecycleError::ShutDown.into()));
+        }
+
+        let hook_failed = |hook| {
+            move |plugin, cause: Error| LifecycleError::HookFailed {
+                plugin,
+                hook,
+                cause,
+            }
+        };
+
+        let processor = self.clone();
+        let warmup = self.clone();
+        let init = |index: usize, node: &PluginNode| -> FutureIO<'static, ()> {
+            if self.initialized[index].load(Ordering::SeqCst) {
+                return Box::new(futures::future::ok(()));
+            }
+            let initialized = self.initialized.clone();
+            Box::new(node.plugin.init(&node.config).map(move |_| {
+                initialized[index].store(true, Ordering::SeqCst);
+            }))
+        };
+        Box::new(
+            self.run_hook(init, hook_failed("init"))
+                .and_then(move |_| {
+                    println!("[] plugins initialized");
+                    restore()
+                })
+                .and_then(move |_| {
+                    warmup.run_hook(|_, node| node.plugin.warmup(), hook_failed("warm up"))
+                })
+                .and_then(move |_| {
+                    println!("[] plugins warmed up");
+                    processor.health_hook().and_then(move |_| {
+                        let mut state = processor.state.lock().unwrap();
+                        // a shutdown while starting keeps the processor stopped
+                        match *state {
+                            LifecycleState::Stopped => Err(LifecycleError::ShutDown.into()),
+                            _ => {
+                                *state = LifecycleState::Ready;
+                                Ok(())
+                            }
+                        }
+                    })
+                }),
+        )
+    }
+
+    fn health_hook(self: &Self) -> FutureIO<'static, ()> {
+        self.run_hook(
+            |_, node| node.plugin.health(),
+            |plugin, cause| LifecycleError::Unhealthy { plugin, cause },
+        )
+    }
+
+    /// Health checks all plugins and accepts or refuses runs accordingly.
+    ///
+    /// This only moves a started processor between Ready and Unhealthy; one which
+    /// isn't started or is stopped keeps its state.
+    pub fn check_health(self: &Self) -> FutureIO<'static, ()> {
+        let state = self.state.clone();
+
+        Box::new(self.health_hook().then(move |result| {
+            let mut state = state.lock().unwrap();
+            if let LifecycleState::Ready | LifecycleState::Unhealthy = *state {
+                *state = match result {
+                    Ok(_) => LifecycleState::Ready,
+                    Err(_) => LifecycleState::Unhealthy,
+                };
+            }
+            result
+        }))
+    }
+
+    /// Health checks all plugins every `period` until the processor is stopped.
+    pub fn monitor_health(self: &Self, period: Duration) -> FutureIO<'static, ()> {
+        let processor = self.clone();
 
-        Box::new(future_result)
+        Box::new(
+            tokio::timer::Interval::new(Instant::now() + period, period)
+                .map_err(Error::from)
+                .take_while(move |_| Ok(processor.state() != LifecycleState::Stopped))
+                .for_each({
+                    let processor = self.clone();
+                    move |_| {
+                        processor.check_health().then(|result| {
+                            if let Err(e) = result {
+                                println!("[] health check failed: {}", e);
+                            }
+                            Ok(())
+                        })
+                    }
+                }),
+        )
+    }
+
+    /// Refuses further runs and shuts all plugins down, dependents before their
+    /// dependencies.
+    ///
+    /// Every plugin is shut down even if an earlier one fails; the first failure is
+    /// returned.
+    pub fn shutdown(self: &Self) -> FutureIO<'static, ()> {
+        *self.state.lock().unwrap() = LifecycleState::Stopped;
+
+        let shutdowns: Vec<(String, Arc<PluginReference>)> = self
+            .order
+            .iter()
+            .rev()
+            .map(|index| {
+                (
+                    self.nodes[*index].name.clone(),
+                    self.nodes[*index].plugin.clone(),
+                )
+            })
+            .collect();
+
+        Box::new(
+            futures::stream::iter_ok::<_, Error>(shutdowns)
+                .fold(None, |first_error: Option<Error>, (name, plugin)| {
+                    plugin.shutdown().then(move |result| {
+                        Ok::<_, Error>(first_error.or_else(|| {
+                            result.err().map(|cause| {
+                                LifecycleError::HookFailed {
+                                    plugin: name,
+                                    hook: "shut down",
+                                    cause,
+                                }
+                                .into()
+                            })
+                        }))
+                    })
+                })
+                .and_then(|first_error| match first_error {
+                    Some(e) => Err(e),
+                    None => {
+                        println!("[] plugins shut down");
+                        Ok(())
+                    }
+                }),
+        )
+    }
+
+    /// Processes all given Plugins along their dependency graph.
+    ///
+    /// Plugins which don't depend on each other run concurrently. Join nodes and the
+    /// final output receive the merged outputs of their inputs. Runs are refused unless
+    /// the processor was started and its plugins are healthy.
+    pub fn process(self: &Self, initial_io: String) -> FutureIO<'static, String> {
+        let state = self.state();
+        if state != LifecycleState::Ready {
+            return Box::new(futures::future::err(LifecycleError::NotReady(state).into()));
+        }
+
+        let initial: SharedIO = (Box::new(futures::future::ok(initial_io))
+            as Box<dyn Future<Item = String, Error = SharedError> + Send>)
+            .shared();
+        let merge = self.merge;
+
+        let mut outputs: Vec<Option<SharedIO>> = vec![None; self.nodes.len()];
+        for &index in self.order.iter() {
+            let inputs: Vec<SharedIO> = if self.dependencies[index].is_empty() {
+                vec![initial.clone()]
+            } else {
+                self.dependencies[index]
+                    .iter()
+                    .map(|dependency| {
+                        outputs[*dependency]
+                            .clone()
+                            .expect("dependencies are processed first")
+                    })
+                    .collect()
+            };
+
+            let plugin = self.nodes[index].plugin.clone();
+            let output: Box<dyn Future<Item = String, Error = SharedError> + Send> = Box::new(
+                join_shared(inputs, merge)
+                    .and_then(move |io| plugin.run(io))
+                    .map_err(SharedError::from),
+            );
+            outputs[index] = Some(output.shared());
+        }
+
+        let sinks: Vec<SharedIO> = (0..self.nodes.len())
+            .filter(|index| !self.dependencies.iter().any(|d| d.contains(index)))
+            .filter_map(|index| outputs[index].clone())
+            .collect();
+
+        if sinks.is_empty() {
+            return Box::new(join_shared(vec![initial], merge));
+        }
+
+        Box::new(join_shared(sinks, merge))
     }
 }
 
+fn join_shared(inputs: Vec<SharedIO>, merge: MergeFn) -> impl Future<Item = String, Error = Error> {
+    futures::future::join_all(inputs)
+        .map_err(|e| (*e).clone().into())
+        .map(move |mut outputs| {
+            if outputs.len() == 1 {
+                (*outputs.remove(0)).clone()
+            } else {
+                merge(outputs.iter().map(|output| (**output).clone()).collect())
+            }
+        })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fixtures::AppendingPlugin;
 
     #[derive(Debug, Clone)]
     struct TestInternalPlugin {
@@ -108,7 +674,7 @@ mod tests {
     }
 
     impl InternalPlugin for TestInternalPlugin {
-        fn run_internal(self: &mut Self, io: String) -> FutureIO<String> {
+        fn run_internal(self: &mut Self, io: String) -> FutureIO<'static, String> {
             self.counter += 1;
             self.dict.insert(self.counter, true);
 
@@ -117,40 +683,50 @@ mod tests {
     }
 
     impl Plugin<String> for TestInternalPlugin {
-        fn run(self: Box<Self>, io: String) -> FutureIO<'static, String> {
+        fn run(self: &Self, io: String) -> FutureIO<'static, String> {
             Box::new(futures::future::ok(io))
         }
     }
 
+    fn appending(name: &'static str, dependencies: &[&str]) -> PluginNode {
+        PluginNode::new(
+            name,
+            Box::new(InternalPluginWrapper::new(AppendingPlugin::new(name))),
+            dependencies,
+        )
+    }
+
     #[test]
     fn process_plugins_with_state() {
         let initial_io = String::new();
 
-        let plugins: Arc<Vec<PluginReference>> = Arc::new(vec![
-            Arc::new(InternalPluginWrapper(
-                Arc::new(FuturesMutex::new(
-                    TestInternalPlugin {
-                        counter: Default::default(),
-                        dict: Default::default(),
-                    },
-                ))
-            )),
+        let plugins: Vec<(String, PluginReference)> = vec![
+            (
+                "test".to_string(),
+                Box::new(InternalPluginWrapper::new(TestInternalPlugin {
+                    counter: Default::default(),
+                    dict: Default::default(),
+                })),
+            ),
             // Arc::new(FuturesMutex::new(Box::new(InternalPluginWrapper(
             //     TestInternalPlugin {
             //         counter: Default::default(),
             //         dict: Default::default(),
             //     },
             // )))),
-        ]);
+        ];
 
-        let plugin_processor = PluginProcessor { plugins };
+        let plugin_processor = PluginProcessor::chain(plugins).expect("invalid plugin chain");
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(plugin_processor.start())
+            .expect("plugins failed to start");
 
         let runs: usize = 10;
         for _ in 0..runs {
             let initial_io = initial_io.clone();
 
-            let plugins_future: FutureIO<String> =
-                plugin_processor.clone().process(initial_io.clone());
+            let plugins_future: FutureIO<String> = plugin_processor.process(initial_io.clone());
 
             let _ = tokio::runtime::Runtime::new()
                 .unwrap()
@@ -161,4 +737,209 @@ mod tests {
         // assert_eq!(runs, counter.load(Ordering::SeqCst));
         // assert!(dict.read().unwrap().get(&runs).unwrap());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn process_plugin_dag() {
+        let plugin_processor = PluginProcessor::new(vec![
+            appending("d", &["b", "c"]),
+            appending("b", &["a"]),
+            appending("c", &["a"]),
+            appending("a", &[]),
+            appending("e", &["a"]),
+        ])
+        .expect("invalid plugin graph");
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime
+            .block_on(plugin_processor.start())
+            .expect("plugins failed to start");
+        let result = runtime
+            .block_on(plugin_processor.process("_".to_string()))
+            .expect("plugin processing failed");
+
+        // sinks "d" and "e" are merged in declaration order
+        assert_eq!(result, "_ab_acd_ae");
+    }
+
+    #[derive(Debug, Fail)]
+    #[fail(display = "plugin is out of order")]
+    struct OutOfOrder;
+
+    #[derive(Debug)]
+    struct FailingPlugin;
+
+    impl InternalPlugin for FailingPlugin {
+        fn run_internal(self: &mut Self, _: String) -> FutureIO<'static, String> {
+            Box::new(futures::future::err(OutOfOrder.into()))
+        }
+    }
+
+    #[test]
+    fn keep_errors_of_joined_plugins() {
+        let plugin_processor = PluginProcessor::new(vec![
+            PluginNode::new(
+                "a",
+                Box::new(InternalPluginWrapper::new(FailingPlugin)),
+                &[],
+            ),
+            appending("b", &["a"]),
+            appending("c", &["a"]),
+        ])
+        .expect("invalid plugin graph");
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime
+            .block_on(plugin_processor.start())
+            .expect("plugins failed to start");
+        let error = runtime
+            .block_on(plugin_processor.process("_".to_string()))
+            .expect_err("failing plugin was joined");
+
+        assert_eq!(error.to_string(), "plugin is out of order");
+        assert!(error
+            .find_root_cause()
+            .downcast_ref::<OutOfOrder>()
+            .is_some());
+        assert_eq!(error.iter_chain().count(), 2);
+    }
+
+    #[test]
+    fn reject_plugin_cycles() {
+        let result = PluginProcessor::new(vec![
+            appending("a", &[]),
+            appending("b", &["a", "c"]),
+            appending("c", &["b"]),
+        ]);
+
+        match result.map_err(|e| e.downcast::<PluginGraphError>()) {
+            Err(Ok(PluginGraphError::Cycle(names))) => assert_eq!(names, vec!["b", "c"]),
+            other => panic!("expected a cycle error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    /// Logs its lifecycle hooks and reports healthy once it has been warmed up
+    #[derive(Debug)]
+    struct LifecyclePlugin {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+        warm: bool,
+    }
+
+    impl InternalPlugin for LifecyclePlugin {
+        fn run_internal(self: &mut Self, io: String) -> FutureIO<'static, String> {
+            Box::new(futures::future::ok(format!("{}{}", io, self.name)))
+        }
+
+        fn init(self: &mut Self, config: &PluginConfig) -> FutureIO<'static, ()> {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("init {} {}", self.name, config));
+            Box::new(futures::future::ok(()))
+        }
+
+        fn warmup(self: &mut Self) -> FutureIO<'static, ()> {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("warmup {}", self.name));
+            Box::new(futures::future::ok(()))
+        }
+
+        fn health(self: &mut Self) -> FutureIO<'static, ()> {
+            if self.warm {
+                return Box::new(futures::future::ok(()));
+            }
+            self.warm = true;
+            Box::new(futures::future::err(failure::err_msg("still cold")))
+        }
+
+        fn shutdown(self: &mut Self) -> FutureIO<'static, ()> {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("shutdown {}", self.name));
+            Box::new(futures::future::ok(()))
+        }
+    }
+
+    #[test]
+    fn run_plugin_lifecycle() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let lifecycle = |name, dependencies| {
+            PluginNode::new(
+                name,
+                Box::new(InternalPluginWrapper::new(LifecyclePlugin {
+                    name,
+                    log: log.clone(),
+                    warm: false,
+                })),
+                dependencies,
+            )
+        };
+        let plugin_processor = PluginProcessor::new(vec![
+            lifecycle("b", &["a"]).with_config(serde_json::json!({ "limit": 3 })),
+            lifecycle("a", &[]),
+        ])
+        .expect("invalid plugin graph");
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        match runtime
+            .block_on(plugin_processor.process("_".to_string()))
+            .map_err(|e| e.downcast::<LifecycleError>())
+        {
+            Err(Ok(LifecycleError::NotReady(LifecycleState::Created))) => (),
+            other => panic!("expected a refused run, got {:?}", other.map(|_| ())),
+        }
+
+        // the plugins are cold on their first health check, so the first start fails
+        let error = runtime
+            .block_on(plugin_processor.start())
+            .expect_err("cold plugins reported healthy");
+        let chain: Vec<String> = error.iter_chain().map(|e| e.to_string()).collect();
+        assert_eq!(chain[1..], ["still cold"]);
+        assert_eq!(plugin_processor.state(), LifecycleState::Created);
+        // only a start makes the processor ready
+        runtime
+            .block_on(plugin_processor.check_health())
+            .expect("warm plugins reported unhealthy");
+        assert_eq!(plugin_processor.state(), LifecycleState::Created);
+        runtime
+            .block_on(plugin_processor.start())
+            .expect("plugins failed to start");
+        assert_eq!(
+            runtime
+                .block_on(plugin_processor.process("_".to_string()))
+                .unwrap(),
+            "_ab"
+        );
+
+        runtime
+            .block_on(plugin_processor.shutdown())
+            .expect("plugins failed to shut down");
+        assert_eq!(plugin_processor.state(), LifecycleState::Stopped);
+        match runtime
+            .block_on(plugin_processor.start())
+            .map_err(|e| e.downcast::<LifecycleError>())
+        {
+            Err(Ok(LifecycleError::ShutDown)) => (),
+            other => panic!("expected a refused start, got {:?}", other.map(|_| ())),
+        }
+
+        // the second start only warmed the plugins up again
+        let log = log.lock().unwrap();
+        assert_eq!(
+            &log[..6],
+            &[
+                "init b {\"limit\":3}",
+                "init a null",
+                "warmup b",
+                "warmup a",
+                "warmup b",
+                "warmup a",
+            ]
+        );
+        assert_eq!(log.len(), 8);
+        assert_eq!(&log[log.len() - 2..], &["shutdown b", "shutdown a"]);
+    }
+}
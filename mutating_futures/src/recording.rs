@@ -0,0 +1,461 @@
+use crate::wrapped::{
+    with_locked, AsyncResult, AsyncWorker, Compensation, Work, WorkCollection, WorkIO,
+};
+use failure::{Fallible, ResultExt};
+use futures::Future;
+use futures_locks::Mutex as FuturesMutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Fail)]
+pub enum ReplayError {
+    #[fail(display = "stage {} has no recorded runs left", _0)]
+    Exhausted(usize),
+    #[fail(
+        display = "stage {} got input {:?}, but the recording has {:?}",
+        stage, actual, expected
+    )]
+    UnexpectedInput {
+        stage: usize,
+        expected: WorkIO,
+        actual: WorkIO,
+    },
+    #[fail(display = "pipeline disagrees with the recording:\n{}", _0)]
+    Mismatch(String),
+}
+
+/// One run of one stage, a line of a recording file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StageRecord {
+    pub stage: usize,
+    /// Position of the run among the runs of its stage, in the order they started
+    pub sequence: u64,
+    pub input: WorkIO,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<WorkIO>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Time the stage took once it had its lock, not compared in checks
+    pub elapsed: Duration,
+}
+
+impl StageRecord {
+    /// Whether both records saw the same input and produced the same result.
+    fn same_run(self: &Self, other: &StageRecord) -> bool {
+        self.stage == other.stage
+            && self.input == other.input
+            && self.output == other.output
+            && self.error == other.error
+    }
+
+    /// Identifies the run, records with the same key are compared with each other
+    fn key(self: &Self) -> (u64, usize) {
+        (self.sequence, self.stage)
+    }
+
+    fn summary(self: &Self) -> String {
+        match (&self.output, &self.error) {
+            (_, Some(error)) => {
+                format!("stage {}: {:?} -> error: {}", self.stage, self.input, error)
+            }
+            (Some(output), None) => {
+                format!("stage {}: {:?} -> {:?}", self.stage, self.input, output)
+            }
+            (None, None) => format!("stage {}: {:?} -> nothing", self.stage, self.input),
+        }
+    }
+}
+
+/// Stage runs in the order they started, the runs of all stages of a pipeline run
+/// before those of the next one
+#[derive(Debug, Clone, Default)]
+pub struct Recording {
+    pub records: Vec<StageRecord>,
+}
+
+impl Recording {
+    /// Reads a recording with one JSON record per line.
+    pub fn load(path: &Path) -> Fallible<Self> {
+        let file = std::fs::File::open(path)
+            .with_context(|_| format!("could not open recording {}", path.display()))?;
+
+        let mut records = Vec::new();
+        for (index, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            records.push(
+                serde_json::from_str(&line).with_context(|_| {
+                    format!("invalid record in {}:{}", path.display(), index + 1)
+                })?,
+            );
+        }
+
+        records.sort_by_key(StageRecord::key);
+        Ok(Recording { records })
+    }
+
+    pub fn save(self: &Self, path: &Path) -> Fallible<()> {
+        let mut file = BufWriter::new(std::fs::File::create(path)?);
+        for record in &self.records {
+            serde_json::to_writer(&mut file, record)?;
+            file.write_all(b"\n")?;
+        }
+        file.flush()?;
+        Ok(())
+    }
+
+    /// Returns the number of stages, as far as the recording saw them.
+    pub fn stages(self: &Self) -> usize {
+        self.records
+            .iter()
+            .map(|record| record.stage + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Returns a worker which answers like `stage` did in the recording.
+    pub fn replay_stage(self: &Self, stage: usize) -> ReplayWorker {
+        let mut records: Vec<StageRecord> = self
+            .records
+            .iter()
+            .filter(|record| record.stage == stage)
+            .cloned()
+            .collect();
+        records.sort_by_key(|record| record.sequence);
+
+        ReplayWorker {
+            stage,
+            records: records.into(),
+        }
+    }
+
+    /// Returns stand-ins for all recorded stages.
+    pub fn replay(self: &Self) -> WorkCollection {
+        (0..self.stages())
+            .map(|stage| -> Work {
+                Arc::new(FuturesMutex::new(Box::new(self.replay_stage(stage))))
+            })
+            .collect()
+    }
+
+    /// Lists the records which differ from `actual`, or returns None if there are none.
+    ///
+    /// Records are matched by their stage and sequence number, so runs which finished
+    /// in a different order still match.
+    pub fn diff(self: &Self, actual: &Recording) -> Option<String> {
+        let by_key = |recording: &Recording| -> BTreeMap<(u64, usize), StageRecord> {
+            recording
+                .records
+                .iter()
+                .map(|record| (record.key(), record.clone()))
+                .collect()
+        };
+        let (expected, actual) = (by_key(self), by_key(actual));
+        let keys: BTreeSet<_> = expected.keys().chain(actual.keys()).collect();
+
+        let mut diff = String::new();
+        for key in keys {
+            match (expected.get(key), actual.get(key)) {
+                (Some(expected), Some(actual)) if expected.same_run(actual) => continue,
+                (expected, actual) => {
+                    diff.push_str(&format!("stage {} run {}:\n", key.1, key.0));
+                    if let Some(expected) = expected {
+                        diff.push_str(&format!("- {}\n", expected.summary()));
+                    }
+                    if let Some(actual) = actual {
+                        diff.push_str(&format!("+ {}\n", actual.summary()));
+                    }
+                }
+            }
+        }
+
+        if diff.is_empty() {
+            None
+        } else {
+            Some(diff)
+        }
+    }
+
+    /// Fails with the diff if `actual` disagrees with this recording.
+    pub fn check(self: &Self, actual: &Recording) -> Fallible<()> {
+        match self.diff(actual) {
+            Some(diff) => Err(ReplayError::Mismatch(diff).into()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Collects the runs of the stages it wraps. Clones share their records.
+#[derive(Clone, Default)]
+pub struct Recorder {
+    records: Arc<Mutex<Vec<StageRecord>>>,
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wraps `work` so its runs are recorded as runs of `stage`.
+    pub fn wrap(self: &Self, stage: usize, work: Work) -> Work {
+        Arc::new(FuturesMutex::new(Box::new(RecordingWorker {
+            stage,
+            worker: work,
+            recorder: self.clone(),
+            runs: 0,
+        })))
+    }
+
+    pub fn wrap_all(self: &Self, work_collection: &[Work]) -> WorkCollection {
+        work_collection
+            .iter()
+            .enumerate()
+            .map(|(stage, work)| self.wrap(stage, work.clone()))
+            .collect()
+    }
+
+    pub fn recording(self: &Self) -> Recording {
+        let mut records = self.records.lock().unwrap().clone();
+        records.sort_by_key(StageRecord::key);
+        Recording { records }
+    }
+}
+
+/// AsyncWorker which records the runs of the wrapped worker
+struct RecordingWorker {
+    stage: usize,
+    worker: Work,
+    recorder: Recorder,
+    /// Number of runs started so far, the sequence number of the next run
+    runs: u64,
+}
+
+impl AsyncWorker<WorkIO> for RecordingWorker {
+    fn init(self: &mut Self, config: &serde_json::Value) -> AsyncResult<()> {
+        let config = config.clone();
+        with_locked(&self.worker, move |worker| worker.init(&config))
+    }
+
+    fn warmup(self: &mut Self) -> AsyncResult<()> {
+        with_locked(&self.worker, |worker| worker.warmup())
+    }
+
+    fn health(self: &mut Self) -> AsyncResult<()> {
+        with_locked(&self.worker, |worker| worker.health())
+    }
+
+    fn shutdown(self: &mut Self) -> AsyncResult<()> {
+        with_locked(&self.worker, |worker| worker.shutdown())
+    }
+
+    fn compensation(
+        self: &mut Self,
+        input: &WorkIO,
+        output: &WorkIO,
+    ) -> AsyncResult<Option<Compensation>> {
+        let (input, output) = (input.clone(), output.clone());
+        with_locked(&self.worker, move |worker| {
+            worker.compensation(&input, &output)
+        })
+    }
+
+    fn run(self: &mut Self, input: WorkIO) -> AsyncResult<WorkIO> {
+        let stage = self.stage;
+        let sequence = self.runs;
+        self.runs += 1;
+        let records = self.recorder.records.clone();
+
+        with_locked(&self.worker, move |worker| {
+            let started = Instant::now();
+            Box::new(worker.run(input.clone()).then(move |result| {
+                let (output, error) = match result {
+                    Ok(ref output) => (Some(output.clone()), None),
+                    Err(ref error) => (None, Some(error.to_string())),
+                };
+                records.lock().unwrap().push(StageRecord {
+                    stage,
+                    sequence,
+                    input,
+                    output,
+                    error,
+                    elapsed: started.elapsed(),
+                });
+                result
+            }))
+        })
+    }
+}
+
+/// AsyncWorker which answers with the recorded runs of a stage, in the order they
+/// started.
+///
+/// Fails if it gets an input other than the recorded one, so a replayed stage also
+/// checks the stages before it.
+pub struct ReplayWorker {
+    stage: usize,
+    records: VecDeque<StageRecord>,
+}
+
+impl AsyncWorker<WorkIO> for ReplayWorker {
+    fn run(self: &mut Self, input: WorkIO) -> AsyncResult<WorkIO> {
+        let record = match self.records.pop_front() {
+            Some(record) => record,
+            None => {
+                return Box::new(futures::future::err(
+                    ReplayError::Exhausted(self.stage).into(),
+                ))
+            }
+        };
+
+        Box::new(futures::future::result(
+            match (record.output, record.error) {
+                _ if record.input != input => Err(ReplayError::UnexpectedInput {
+                    stage: self.stage,
+                    expected: record.input,
+                    actual: input,
+                }
+                .into()),
+                (_, Some(error)) => Err(failure::err_msg(error)),
+                (Some(output), None) => Ok(output),
+                (None, None) => Err(failure::err_msg("recorded run has no output")),
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::InternalCountingForwarder;
+    use crate::wrapped::{process, AsyncWorkerInternal, InternalWorkWrapper};
+
+    /// Counts its runs like InternalCountingForwarder, but takes its time on the first
+    #[derive(Clone)]
+    struct InternalSlowFirstForwarder(pub usize);
+    impl AsyncWorkerInternal<WorkIO> for InternalSlowFirstForwarder {
+        fn run_internal(self: &mut Self, input: WorkIO) -> AsyncResult<WorkIO> {
+            self.0 += 1;
+            let delay = if self.0 == 1 { 50 } else { 0 };
+            let output = format!("{}{}", input, self.0 % 10);
+
+            Box::new(
+                tokio::timer::Delay::new(Instant::now() + Duration::from_millis(delay))
+                    .map_err(failure::Error::from)
+                    .map(move |_| output),
+            )
+        }
+    }
+
+    fn counters(starts: &[usize]) -> WorkCollection {
+        starts
+            .iter()
+            .map(|start| -> Work {
+                Arc::new(FuturesMutex::new(Box::new(InternalWorkWrapper(
+                    InternalCountingForwarder(*start),
+                ))))
+            })
+            .collect()
+    }
+
+    fn record_runs(
+        runtime: &mut tokio::runtime::Runtime,
+        work_collection: &[Work],
+        runs: usize,
+    ) -> Fallible<Recording> {
+        let recorder = Recorder::new();
+        let recorded = recorder.wrap_all(work_collection);
+        for _ in 0..runs {
+            runtime.block_on(process(recorded.clone().into_iter()))?;
+        }
+        Ok(recorder.recording())
+    }
+
+    #[test]
+    fn test_record_and_replay() -> Fallible<()> {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let path = std::env::temp_dir().join(format!(
+            "mutating_futures_recording_{}.jsonl",
+            std::process::id()
+        ));
+
+        let golden = record_runs(&mut runtime, &counters(&[0, 5]), 2)?;
+        golden.save(&path)?;
+        let golden = Recording::load(&path)?;
+        std::fs::remove_file(&path)?;
+        let summaries: Vec<String> = golden.records.iter().map(StageRecord::summary).collect();
+        assert_eq!(
+            summaries,
+            vec![
+                "stage 0: \"\" -> \"1\"",
+                "stage 1: \"1\" -> \"16\"",
+                "stage 0: \"\" -> \"2\"",
+                "stage 1: \"2\" -> \"27\"",
+            ]
+        );
+
+        // replayed stages stand in for the recorded workers
+        let replayed = golden.replay();
+        assert_eq!(
+            runtime.block_on(process(replayed.clone().into_iter()))?,
+            "16"
+        );
+        assert_eq!(
+            runtime.block_on(process(replayed.clone().into_iter()))?,
+            "27"
+        );
+        match runtime
+            .block_on(process(replayed.into_iter()))
+            .map_err(|e| e.downcast::<ReplayError>())
+        {
+            Err(Ok(ReplayError::Exhausted(0))) => (),
+            other => panic!("expected an exhausted replay, got {:?}", other.map(|_| ())),
+        }
+
+        // a live pipeline which agrees passes, one which doesn't gets a diff
+        golden.check(&record_runs(&mut runtime, &counters(&[0, 5]), 2)?)?;
+        let error = golden
+            .check(&record_runs(&mut runtime, &counters(&[0, 6]), 1)?)
+            .expect_err("second stage should disagree");
+        assert_eq!(
+            error.to_string(),
+            "pipeline disagrees with the recording:
+stage 1 run 0:
+- stage 1: \"1\" -> \"16\"
++ stage 1: \"1\" -> \"17\"
+stage 0 run 1:
+- stage 0: \"\" -> \"2\"
+stage 1 run 1:
+- stage 1: \"2\" -> \"27\"
+"
+        );
+
+        // runs are matched by the order they started in, not the order they finished in
+        let slow_first = || -> WorkCollection {
+            vec![Arc::new(FuturesMutex::new(Box::new(InternalWorkWrapper(
+                InternalSlowFirstForwarder(0),
+            ))))]
+        };
+        let recorder = Recorder::new();
+        let recorded = recorder.wrap_all(&slow_first());
+        let outputs = runtime
+            .block_on(process(recorded.clone().into_iter()).join(process(recorded.into_iter())))?;
+        assert_eq!(outputs, ("1".to_string(), "2".to_string()));
+        let finished: Vec<u64> = recorder
+            .records
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|record| record.sequence)
+            .collect();
+        assert_eq!(finished, vec![1, 0]);
+        record_runs(&mut runtime, &slow_first(), 2)?.check(&recorder.recording())?;
+
+        Ok(())
+    }
+}
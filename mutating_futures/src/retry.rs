@@ -0,0 +1,309 @@
+use crate::wrapped::{with_locked, AsyncResult, AsyncWorker, Compensation, Work, WorkIO};
+use core::fmt::Display;
+use core::fmt::Formatter;
+use failure::{Error, Fail};
+use futures::future::{Either, Loop};
+use futures::Future;
+use futures_locks::Mutex as FuturesMutex;
+use rand::Rng;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Decides whether a failed attempt may be retried
+pub type RetryPredicate = Arc<dyn Fn(&Error) -> bool + Send + Sync>;
+
+/// How often and how fast a failing worker is retried.
+///
+/// The delay before attempt `n + 1` is `initial_backoff * multiplier^(n - 1)`, capped at
+/// `max_backoff` and reduced by a random share of up to `jitter` of itself.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_attempts: usize,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    multiplier: u32,
+    jitter: f64,
+    retryable: RetryPredicate,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            multiplier: 2,
+            jitter: 0.5,
+            retryable: Arc::new(|_| true),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Sets the number of attempts including the first one.
+    pub fn with_max_attempts(mut self: Self, max_attempts: usize) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    pub fn with_backoff(mut self: Self, initial: Duration, max: Duration, multiplier: u32) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self.multiplier = multiplier;
+        self
+    }
+
+    /// Sets the share of each delay, between 0 and 1, which is randomly left out.
+    pub fn with_jitter(mut self: Self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn with_retryable<F>(mut self: Self, retryable: F) -> Self
+    where
+        F: Fn(&Error) -> bool + Send + Sync + 'static,
+    {
+        self.retryable = Arc::new(retryable);
+        self
+    }
+
+    /// Returns the delay after the given failed attempt, counting from 1.
+    pub fn backoff(self: &Self, attempt: usize) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(u32::MAX as usize) as u32;
+        let backoff = self
+            .multiplier
+            .checked_pow(exponent)
+            .and_then(|factor| self.initial_backoff.checked_mul(factor))
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff));
+
+        let jitter = rand::thread_rng().gen_range(0.0, 1.0) * self.jitter;
+        backoff.mul_f64(1.0 - jitter)
+    }
+}
+
+/// Failure of the last attempt.
+///
+/// Each attempt is caused by the attempt before it, and the first attempt by its
+/// error, so the error chain lists every attempt down to the root error. The failures
+/// of the attempts are also returned by `attempts`.
+#[derive(Debug)]
+pub struct AttemptError {
+    pub attempt: usize,
+    pub error: Error,
+    previous: Option<Box<AttemptError>>,
+}
+
+impl AttemptError {
+    /// Returns the failed attempts, the last one first.
+    pub fn attempts(self: &Self) -> impl Iterator<Item = &AttemptError> {
+        std::iter::successors(Some(self), |attempt| attempt.previous.as_deref())
+    }
+}
+
+impl Display for AttemptError {
+    fn fmt(self: &Self, formatter: &mut Formatter) -> Result<(), std::fmt::Error> {
+        write!(formatter, "attempt {} failed: {}", self.attempt, self.error)
+    }
+}
+
+impl Fail for AttemptError {
+    fn cause(&self) -> Option<&dyn Fail> {
+        match self.previous {
+            Some(ref previous) => Some(previous.as_ref()),
+            None => Some(self.error.as_fail()),
+        }
+    }
+}
+
+/// AsyncWorker which retries the wrapped worker according to its RetryPolicy.
+///
+/// The error of the last attempt is an AttemptError, which is caused by the earlier
+/// attempts.
+pub struct RetryingWorker {
+    worker: Work,
+    policy: RetryPolicy,
+}
+
+impl RetryingWorker {
+    pub fn new(worker: Box<dyn AsyncWorker<WorkIO>>, policy: RetryPolicy) -> Self {
+        RetryingWorker {
+            worker: Arc::new(FuturesMutex::new(worker)),
+            policy,
+        }
+    }
+}
+
+impl AsyncWorker<WorkIO> for RetryingWorker {
+    fn init(self: &mut Self, config: &serde_json::Value) -> AsyncResult<()> {
+        let config = config.clone();
+        with_locked(&self.worker, move |worker| worker.init(&config))
+    }
+
+    fn warmup(self: &mut Self) -> AsyncResult<()> {
+        with_locked(&self.worker, |worker| worker.warmup())
+    }
+
+    fn health(self: &mut Self) -> AsyncResult<()> {
+        with_locked(&self.worker, |worker| worker.health())
+    }
+
+    fn shutdown(self: &mut Self) -> AsyncResult<()> {
+        with_locked(&self.worker, |worker| worker.shutdown())
+    }
+
+    fn compensation(
+        self: &mut Self,
+        input: &WorkIO,
+        output: &WorkIO,
+    ) -> AsyncResult<Option<Compensation>> {
+        let (input, output) = (input.clone(), output.clone());
+        with_locked(&self.worker, move |worker| {
+            worker.compensation(&input, &output)
+        })
+    }
+
+    fn run(self: &mut Self, input: WorkIO) -> AsyncResult<WorkIO> {
+        let worker = self.worker.clone();
+        let policy = self.policy.clone();
+
+        Box::new(futures::future::loop_fn(
+            (1, None),
+            move |(attempt, previous): (usize, Option<AttemptError>)| {
+                let policy = policy.clone();
+                let input = input.clone();
+
+                with_locked(&worker, move |worker| worker.run(input)).then(move |result| {
+                    match result {
+                        Ok(output) => Either::A(futures::future::ok(Loop::Break(output))),
+                        Err(error) => {
+                            let retryable = (policy.retryable)(&error);
+                            let failed = AttemptError {
+                                attempt,
+                                error,
+                                previous: previous.map(Box::new),
+                            };
+
+                            if attempt >= policy.max_attempts || !retryable {
+                                return Either::A(futures::future::err(failed.into()));
+                            }
+
+                            println!("[] {}, retrying", failed);
+                            Either::B(
+                                tokio::timer::Delay::new(Instant::now() + policy.backoff(attempt))
+                                    .map_err(Error::from)
+                                    .map(move |_| Loop::Continue((attempt + 1, Some(failed)))),
+                            )
+                        }
+                    }
+                })
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wrapped::{process, AsyncWorkerInternal, InternalWorkWrapper, WorkCollection};
+    use failure::Fallible;
+
+    #[derive(Debug, Fail)]
+    #[fail(display = "transient failure #{}", _0)]
+    struct TransientError(usize);
+
+    /// Fails with a TransientError on its first `failures` runs
+    #[derive(Clone)]
+    struct InternalFlakyForwarder {
+        failures: usize,
+        runs: usize,
+    }
+    impl AsyncWorkerInternal<WorkIO> for InternalFlakyForwarder {
+        fn run_internal(self: &mut Self, input: WorkIO) -> AsyncResult<WorkIO> {
+            self.runs += 1;
+            if self.runs <= self.failures {
+                return Box::new(futures::future::err(TransientError(self.runs).into()));
+            }
+
+            Box::new(futures::future::ok(format!("{}{}", input, self.runs % 10)))
+        }
+    }
+
+    fn flaky(failures: usize, policy: RetryPolicy) -> Work {
+        Arc::new(FuturesMutex::new(Box::new(RetryingWorker::new(
+            Box::new(InternalWorkWrapper(InternalFlakyForwarder {
+                failures,
+                runs: 0,
+            })),
+            policy,
+        ))))
+    }
+
+    #[test]
+    fn test_process_with_retries() -> Fallible<()> {
+        let policy = RetryPolicy::default().with_max_attempts(3).with_backoff(
+            Duration::from_millis(1),
+            Duration::from_millis(5),
+            2,
+        );
+
+        let work_collection: WorkCollection = vec![
+            flaky(2, policy.clone()),
+            flaky(
+                5,
+                policy
+                    .clone()
+                    .with_retryable(|e| e.downcast_ref::<TransientError>().is_some()),
+            ),
+        ];
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        // the first stage recovers on its third attempt, the second one gives up
+        let error = runtime
+            .block_on(process(work_collection.into_iter()))
+            .expect_err("second stage should exhaust its attempts");
+        let chain: Vec<String> = error.iter_chain().map(|e| e.to_string()).collect();
+        assert_eq!(
+            chain,
+            vec![
+                "attempt 3 failed: transient failure #3",
+                "attempt 2 failed: transient failure #2",
+                "attempt 1 failed: transient failure #1",
+                "transient failure #1",
+            ]
+        );
+        assert!(error
+            .find_root_cause()
+            .downcast_ref::<TransientError>()
+            .is_some());
+        let error = error
+            .downcast::<AttemptError>()
+            .expect("error is not an AttemptError");
+        let attempts: Vec<String> = error.attempts().map(|e| e.to_string()).collect();
+        assert_eq!(
+            attempts,
+            vec![
+                "attempt 3 failed: transient failure #3",
+                "attempt 2 failed: transient failure #2",
+                "attempt 1 failed: transient failure #1",
+            ]
+        );
+
+        // non-retryable errors are not retried
+        let mut not_retried = RetryingWorker::new(
+            Box::new(InternalWorkWrapper(InternalFlakyForwarder {
+                failures: 1,
+                runs: 0,
+            })),
+            policy.clone().with_retryable(|_| false),
+        );
+        let error = runtime
+            .block_on(not_retried.run("".to_string()))
+            .expect_err("error should not be retried");
+        assert_eq!(error.iter_chain().count(), 2);
+
+        assert!(policy.backoff(10) <= Duration::from_millis(5));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,265 @@
+use crate::stage_control::{ProcessControl, StagePhase};
+use crate::wrapped::{AsyncResult, Compensation, Work, WorkIO};
+use core::fmt::Display;
+use core::fmt::Formatter;
+use failure::{Error, Fail};
+use futures::{Future, Stream};
+use std::borrow::Borrow;
+use std::sync::{Arc, Mutex};
+
+/// Result of undoing one stage of a failed run
+#[derive(Debug, Clone)]
+pub struct CompensationOutcome {
+    pub stage: usize,
+    /// Why the compensation failed, if it did
+    pub error: Option<String>,
+}
+
+/// Failure of a run whose completed stages were compensated.
+///
+/// Its cause is the failure of the run.
+#[derive(Debug)]
+pub struct CompensatedError {
+    /// In the order the compensations ran, the last completed stage first
+    pub outcomes: Vec<CompensationOutcome>,
+    cause: Error,
+}
+
+impl CompensatedError {
+    /// Whether every compensation succeeded.
+    pub fn is_consistent(self: &Self) -> bool {
+        self.outcomes.iter().all(|outcome| outcome.error.is_none())
+    }
+}
+
+impl Display for CompensatedError {
+    fn fmt(self: &Self, formatter: &mut Formatter) -> Result<(), std::fmt::Error> {
+        write!(formatter, "run failed, compensated stages:")?;
+        if self.outcomes.is_empty() {
+            write!(formatter, " none")?;
+        }
+        for outcome in &self.outcomes {
+            match outcome.error {
+                None => write!(formatter, " {} (undone)", outcome.stage)?,
+                Some(ref error) => write!(formatter, " {} (failed: {})", outcome.stage, error)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Fail for CompensatedError {
+    fn cause(&self) -> Option<&dyn Fail> {
+        Some(self.cause.as_fail())
+    }
+}
+
+/// Runs the compensations in reverse order and fails with their outcomes and `error`.
+fn compensate(
+    completed: Vec<(usize, Compensation)>,
+    error: Error,
+) -> impl Future<Item = WorkIO, Error = Error> {
+    println!(
+        "[] run failed: {}, compensating {} stages",
+        error,
+        completed.len()
+    );
+
+    futures::stream::iter_ok::<_, Error>(completed.into_iter().rev())
+        .fold(Vec::new(), |mut outcomes, (stage, compensation)| {
+            compensation().then(move |result| {
+                outcomes.push(CompensationOutcome {
+                    stage,
+                    error: result.err().map(|e| e.to_string()),
+                });
+                Ok::<_, Error>(outcomes)
+            })
+        })
+        .and_then(move |outcomes| {
+            Err(CompensatedError {
+                outcomes,
+                cause: error,
+            }
+            .into())
+        })
+}
+
+/// Like `wrapped::process_controlled`, but if the run fails, the compensations of the
+/// stages which completed are run, the last completed stage first.
+///
+/// The error of a failed run is a CompensatedError, whose cause is the failure. A stage
+/// which can't tell how to undo its run fails the run like a failed run of it would.
+pub fn process_with_compensation<T>(
+    work_collection: T,
+    control: ProcessControl,
+) -> AsyncResult<WorkIO>
+where
+    T: Iterator + Sync + Send + 'static,
+    T::Item: Borrow<Work> + Send,
+{
+    let control = control.for_run();
+    let stage_control = control.clone();
+    let completed: Arc<Mutex<Vec<(usize, Compensation)>>> = Default::default();
+    let registered = completed.clone();
+
+    let future_work = futures::stream::iter_ok::<_, Error>(work_collection.enumerate()).fold(
+        "".to_string(),
+        move |input, (stage, next_item_mutex)| {
+            let run_control = stage_control.clone();
+            let registered = registered.clone();
+            stage_control
+                .lock(stage, next_item_mutex.borrow())
+                .and_then(move |mut next_item| {
+                    let run = (*next_item).run(input.clone());
+                    // the lock is kept until the compensation is registered
+                    run_control
+                        .stage(stage, StagePhase::Run, run)
+                        .and_then(move |output| {
+                            next_item
+                                .compensation(&input, &output)
+                                .map(move |compensation| {
+                                    if let Some(compensation) = compensation {
+                                        registered.lock().unwrap().push((stage, compensation));
+                                    }
+                                    drop(next_item);
+                                    output
+                                })
+                        })
+                })
+        },
+    );
+
+    Box::new(control.pipeline(future_work).or_else(move |error| {
+        let completed = std::mem::take(&mut *completed.lock().unwrap());
+        compensate(completed, error)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fan_out::{FanOutWorker, Reducer};
+    use crate::retry::{RetryPolicy, RetryingWorker};
+    use crate::wrapped::{AsyncWorker, AsyncWorkerInternal, InternalWorkWrapper, WorkCollection};
+    use failure::Fallible;
+    use futures_locks::Mutex as FuturesMutex;
+
+    type Store = Arc<Mutex<Vec<String>>>;
+
+    /// Writes its input to a store and undoes that if asked to, unless the store is
+    /// `broken`
+    #[derive(Clone)]
+    struct InternalStoreWriter {
+        store: Store,
+        suffix: &'static str,
+        broken: bool,
+    }
+    impl AsyncWorkerInternal<WorkIO> for InternalStoreWriter {
+        fn run_internal(self: &mut Self, input: WorkIO) -> AsyncResult<WorkIO> {
+            let output = format!("{}{}", input, self.suffix);
+            self.store.lock().unwrap().push(output.clone());
+            Box::new(futures::future::ok(output))
+        }
+
+        fn compensation(
+            self: &mut Self,
+            _input: &WorkIO,
+            output: &WorkIO,
+        ) -> AsyncResult<Option<Compensation>> {
+            let store = self.store.clone();
+            let output = output.clone();
+            let broken = self.broken;
+
+            let compensation: Compensation = Box::new(move || -> AsyncResult<()> {
+                if broken {
+                    return Box::new(futures::future::err(failure::err_msg("store is offline")));
+                }
+                store.lock().unwrap().retain(|stored| *stored != output);
+                Box::new(futures::future::ok(()))
+            });
+            Box::new(futures::future::ok(Some(compensation)))
+        }
+    }
+
+    #[derive(Clone)]
+    struct InternalFailingWorker;
+    impl AsyncWorkerInternal<WorkIO> for InternalFailingWorker {
+        fn run_internal(self: &mut Self, _: WorkIO) -> AsyncResult<WorkIO> {
+            Box::new(futures::future::err(failure::err_msg("stage failed")))
+        }
+    }
+
+    fn store_writer(
+        store: &Store,
+        suffix: &'static str,
+        broken: bool,
+    ) -> Box<dyn AsyncWorker<WorkIO>> {
+        Box::new(InternalWorkWrapper(InternalStoreWriter {
+            store: store.clone(),
+            suffix,
+            broken,
+        }))
+    }
+
+    fn writer(store: &Store, suffix: &'static str, broken: bool) -> Work {
+        Arc::new(FuturesMutex::new(store_writer(store, suffix, broken)))
+    }
+
+    #[test]
+    fn test_compensate_failed_runs() -> Fallible<()> {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let failing: Work = Arc::new(FuturesMutex::new(Box::new(InternalWorkWrapper(
+            InternalFailingWorker,
+        ))));
+        let run = |work_collection: WorkCollection, runtime: &mut tokio::runtime::Runtime| {
+            runtime.block_on(process_with_compensation(
+                work_collection.into_iter(),
+                ProcessControl::default(),
+            ))
+        };
+
+        // successful runs keep their effects
+        let store = Store::default();
+        let stages = vec![writer(&store, "a", false), writer(&store, "b", false)];
+        assert_eq!(run(stages.clone(), &mut runtime)?, "ab");
+        assert_eq!(*store.lock().unwrap(), vec!["a", "ab"]);
+
+        // failed runs undo them in reverse order, also behind composite workers
+        let store = Store::default();
+        let composite = FanOutWorker::new(
+            vec![
+                Box::new(RetryingWorker::new(
+                    store_writer(&store, "b", false),
+                    RetryPolicy::default(),
+                )),
+                // undone with its own output, not the concatenated one
+                store_writer(&store, "x", false),
+            ],
+            Reducer::concatenate(","),
+        );
+        let stages = vec![
+            writer(&store, "a", true),
+            Arc::new(FuturesMutex::new(
+                Box::new(composite) as Box<dyn AsyncWorker<WorkIO>>
+            )),
+            failing.clone(),
+            writer(&store, "c", false),
+        ];
+        let error = run(stages, &mut runtime).expect_err("third stage should fail");
+        let chain: Vec<String> = error.iter_chain().map(|e| e.to_string()).collect();
+        assert_eq!(
+            chain,
+            vec![
+                "run failed, compensated stages: 1 (undone) 0 (failed: store is offline)",
+                "stage failed",
+            ]
+        );
+        let error = error
+            .downcast::<CompensatedError>()
+            .expect("error is not a CompensatedError");
+        assert!(!error.is_consistent());
+        assert_eq!(*store.lock().unwrap(), vec!["a"]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,451 @@
+use crate::actor::panic_message;
+use crate::wrapped::{with_locked, AsyncResult, AsyncWorker, Compensation};
+use core::fmt::Debug;
+use failure::Error;
+use futures::sync::oneshot;
+use futures::Future;
+use futures_locks::Mutex as FuturesMutex;
+use rand::Rng;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct LatencySummary {
+    pub mean: Duration,
+    pub max: Duration,
+    #[serde(skip)]
+    total: Duration,
+}
+
+impl LatencySummary {
+    fn add(self: &mut Self, elapsed: Duration, runs: u32) {
+        self.total += elapsed;
+        self.max = self.max.max(elapsed);
+        self.mean = self.total / runs;
+    }
+}
+
+/// Shadowed run whose candidate disagreed with the primary
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ShadowDifference {
+    pub input: String,
+    pub primary: String,
+    pub candidate: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ShadowReport {
+    pub runs: u64,
+    /// Runs which were also given to the candidate and compared
+    pub compared: u64,
+    /// Sampled runs which were not shadowed because the candidate was still busy
+    pub skipped: u64,
+    pub matches: u64,
+    pub mismatches: u64,
+    /// Compared runs in which the candidate took longer than the primary
+    pub candidate_slower: u64,
+    /// Latency of the primary in the compared runs
+    pub primary_latency: LatencySummary,
+    pub candidate_latency: LatencySummary,
+    /// The most recent mismatches, the oldest first
+    pub differences: VecDeque<ShadowDifference>,
+    /// Why the candidate failed in init, warmup or shutdown, after which it is no
+    /// longer shadowed
+    pub candidate_failure: Option<String>,
+}
+
+/// Result of a run, with errors rendered as text so they can be compared and kept
+type Outcome<T> = (Result<T, String>, Duration);
+
+/// Copy of a run's `result` which can be compared and kept, leaving the result alone
+fn outcome<T: Clone>(result: &Result<T, Error>, elapsed: Duration) -> Outcome<T> {
+    match result {
+        Ok(output) => (Ok(output.clone()), elapsed),
+        Err(error) => (Err(error.to_string()), elapsed),
+    }
+}
+
+/// Calls `f` and runs the future it returns, failing if either panics.
+fn guarded<R, F>(f: F) -> AsyncResult<R>
+where
+    F: FnOnce() -> AsyncResult<R>,
+    R: Send + 'static,
+{
+    let panicked = |panic: Box<dyn std::any::Any + Send>| {
+        format_err!("candidate panicked: {}", panic_message(&*panic))
+    };
+
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(run) => Box::new(
+            AssertUnwindSafe(run)
+                .catch_unwind()
+                .map_err(panicked)
+                .and_then(|result| result),
+        ),
+        Err(panic) => Box::new(futures::future::err(panicked(panic))),
+    }
+}
+
+fn describe<T: Debug>(result: &Result<T, String>) -> String {
+    match result {
+        Ok(output) => format!("{:?}", output),
+        Err(error) => format!("error: {}", error),
+    }
+}
+
+/// AsyncWorker which runs a candidate next to the primary on a sample of the runs, to
+/// check the candidate against live inputs before it replaces the primary.
+///
+/// Only the output of the primary is passed on. The candidate runs as a task of its
+/// own and is compared once both have finished, so it doesn't hold back the stage, and
+/// its panics are reported as errors. A candidate failing in init, warmup or shutdown
+/// doesn't fail the stage either, but is no longer shadowed until the report is reset.
+/// Sampled runs are skipped while the candidate is busy with an earlier one, so a slow
+/// candidate doesn't pile up work. Clones share their workers and report.
+pub struct ShadowWorker<T> {
+    primary: Arc<FuturesMutex<Box<dyn AsyncWorker<T>>>>,
+    candidate: Arc<FuturesMutex<Box<dyn AsyncWorker<T>>>>,
+    report: Arc<Mutex<ShadowReport>>,
+    fraction: f64,
+    max_differences: usize,
+}
+
+impl<T> Clone for ShadowWorker<T> {
+    fn clone(&self) -> Self {
+        ShadowWorker {
+            primary: self.primary.clone(),
+            candidate: self.candidate.clone(),
+            report: self.report.clone(),
+            fraction: self.fraction,
+            max_differences: self.max_differences,
+        }
+    }
+}
+
+impl<T> ShadowWorker<T>
+where
+    T: Debug + PartialEq + Sync + Send + Clone + 'static,
+{
+    pub fn new(primary: Box<dyn AsyncWorker<T>>, candidate: Box<dyn AsyncWorker<T>>) -> Self {
+        ShadowWorker {
+            primary: Arc::new(FuturesMutex::new(primary)),
+            candidate: Arc::new(FuturesMutex::new(candidate)),
+            report: Default::default(),
+            fraction: 1.0,
+            max_differences: 100,
+        }
+    }
+
+    /// Sets the share of runs, between 0 and 1, which are also given to the candidate.
+    pub fn with_fraction(mut self: Self, fraction: f64) -> Self {
+        self.fraction = fraction.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Sets how many of the most recent differences are kept in the report.
+    pub fn with_max_differences(mut self: Self, max_differences: usize) -> Self {
+        self.max_differences = max_differences;
+        self
+    }
+
+    pub fn report(self: &Self) -> ShadowReport {
+        self.report.lock().unwrap().clone()
+    }
+
+    /// Starts a new report, e.g. after the candidate was fixed.
+    pub fn reset(self: &Self) {
+        *self.report.lock().unwrap() = ShadowReport::default();
+    }
+
+    /// Runs `f` with both workers and fails if the primary fails. A failure of the
+    /// candidate is kept in the report and stops the shadowing.
+    fn with_both<F>(self: &Self, f: F) -> AsyncResult<()>
+    where
+        F: Fn(&mut Box<dyn AsyncWorker<T>>) -> AsyncResult<()> + Send + Sync + 'static,
+    {
+        let f = Arc::new(f);
+        let candidate_f = f.clone();
+        let report = self.report.clone();
+        let candidate = with_locked(&self.candidate, move |worker| {
+            guarded(|| candidate_f(worker))
+        })
+        .or_else(move |e| {
+            println!("[] candidate failed, no longer shadowing: {}", e);
+            report.lock().unwrap().candidate_failure = Some(e.to_string());
+            Ok(())
+        });
+
+        Box::new(
+            with_locked(&self.primary, move |worker| f(worker))
+                .join(candidate)
+                .map(|_| ()),
+        )
+    }
+
+    /// Runs `run` to its end and returns its result with how long it took.
+    fn time(
+        run: AsyncResult<T>,
+    ) -> impl Future<Item = (Result<T, Error>, Duration), Error = Error> {
+        let started = Instant::now();
+        run.then(move |result| Ok((result, started.elapsed())))
+    }
+
+    fn compare(self: &Self, input: T, primary: Outcome<T>, candidate: Outcome<T>) {
+        let mut report = self.report.lock().unwrap();
+        report.compared += 1;
+        let compared = report.compared as u32;
+        report.primary_latency.add(primary.1, compared);
+        report.candidate_latency.add(candidate.1, compared);
+        if candidate.1 > primary.1 {
+            report.candidate_slower += 1;
+        }
+
+        if primary.0 == candidate.0 {
+            report.matches += 1;
+            return;
+        }
+        report.mismatches += 1;
+        report.differences.push_back(ShadowDifference {
+            input: format!("{:?}", input),
+            primary: describe(&primary.0),
+            candidate: describe(&candidate.0),
+        });
+        while report.differences.len() > self.max_differences {
+            report.differences.pop_front();
+        }
+    }
+}
+
+impl<T> AsyncWorker<T> for ShadowWorker<T>
+where
+    T: Debug + PartialEq + Sync + Send + Clone + 'static,
+{
+    fn init(self: &mut Self, config: &serde_json::Value) -> AsyncResult<()> {
+        let config = config.clone();
+        self.with_both(move |worker| worker.init(&config))
+    }
+
+    fn warmup(self: &mut Self) -> AsyncResult<()> {
+        self.with_both(|worker| worker.warmup())
+    }
+
+    /// Only the primary's health counts, a broken candidate is seen in the report.
+    fn health(self: &mut Self) -> AsyncResult<()> {
+        with_locked(&self.primary, |worker| worker.health())
+    }
+
+    fn shutdown(self: &mut Self) -> AsyncResult<()> {
+        self.with_both(|worker| worker.shutdown())
+    }
+
+    /// Only the primary's runs take effect, so only they are undone.
+    fn compensation(self: &mut Self, input: &T, output: &T) -> AsyncResult<Option<Compensation>> {
+        let (input, output) = (input.clone(), output.clone());
+        with_locked(&self.primary, move |worker| {
+            worker.compensation(&input, &output)
+        })
+    }
+
+    fn run(self: &mut Self, input: T) -> AsyncResult<T> {
+        let sampled = {
+            let mut report = self.report.lock().unwrap();
+            report.runs += 1;
+            report.candidate_failure.is_none()
+                && rand::thread_rng().gen_range(0.0, 1.0) < self.fraction
+        };
+        let candidate = if sampled {
+            let candidate = self.candidate.try_lock().ok();
+            if candidate.is_none() {
+                self.report.lock().unwrap().skipped += 1;
+            }
+            candidate
+        } else {
+            None
+        };
+        let primary = with_locked(&self.primary, {
+            let input = input.clone();
+            move |worker| worker.run(input)
+        });
+
+        let mut candidate = match candidate {
+            Some(candidate) => candidate,
+            None => return primary,
+        };
+
+        let shadow = self.clone();
+        let (primary_done, primary_outcome) = oneshot::channel::<Outcome<T>>();
+
+        Box::new(
+            futures::future::lazy(move || {
+                tokio::spawn(futures::future::lazy(move || {
+                    let run = guarded(|| candidate.run(input.clone()));
+                    Self::time(run).then(move |timed| {
+                        // the candidate stays locked until it has finished
+                        drop(candidate);
+                        let (result, elapsed) = timed.expect("timed runs don't fail");
+                        primary_outcome
+                            .map(move |primary_outcome| {
+                                shadow.compare(input, primary_outcome, outcome(&result, elapsed));
+                            })
+                            .map_err(|_| ())
+                    })
+                }));
+                Ok::<_, Error>(())
+            })
+            .and_then(move |_| Self::time(primary))
+            .and_then(move |(result, elapsed)| {
+                let _ = primary_done.send(outcome(&result, elapsed));
+                result
+            }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wrapped::{AsyncWorkerInternal, InternalWorkWrapper, WorkIO};
+    use failure::Fallible;
+
+    /// Uppercases its input, the candidate also trims it and takes its time
+    #[derive(Clone)]
+    struct InternalUppercase {
+        candidate: bool,
+    }
+    impl AsyncWorkerInternal<WorkIO> for InternalUppercase {
+        fn run_internal(self: &mut Self, input: WorkIO) -> AsyncResult<WorkIO> {
+            if !self.candidate {
+                return Box::new(futures::future::ok(input.to_uppercase()));
+            }
+
+            let output = input.trim().to_uppercase();
+            Box::new(
+                tokio::timer::Delay::new(Instant::now() + Duration::from_millis(10))
+                    .map_err(Error::from)
+                    .map(move |_| output),
+            )
+        }
+    }
+
+    #[derive(Debug, Fail)]
+    #[fail(display = "refused {}", _0)]
+    struct Refused(String);
+
+    /// Refuses to start, panics on "panic" and refuses every other input
+    #[derive(Clone)]
+    struct InternalRefusing;
+    impl AsyncWorkerInternal<WorkIO> for InternalRefusing {
+        fn run_internal(self: &mut Self, input: WorkIO) -> AsyncResult<WorkIO> {
+            if input == "panic" {
+                panic!("refusing got {}", input);
+            }
+            Box::new(futures::future::err(Refused(input).into()))
+        }
+
+        fn init(self: &mut Self, _config: &serde_json::Value) -> AsyncResult<()> {
+            Box::new(futures::future::err(failure::err_msg("can't start")))
+        }
+    }
+
+    fn uppercase() -> ShadowWorker<WorkIO> {
+        ShadowWorker::new(
+            Box::new(InternalWorkWrapper(InternalUppercase { candidate: false })),
+            Box::new(InternalWorkWrapper(InternalUppercase { candidate: true })),
+        )
+    }
+
+    /// Waits until the candidate runs of the report have been compared.
+    fn compared(shadow: &ShadowWorker<WorkIO>, runs: u64) -> ShadowReport {
+        for _ in 0..100 {
+            let report = shadow.report();
+            if report.compared >= runs {
+                return report;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("candidate runs were not compared: {:?}", shadow.report());
+    }
+
+    #[test]
+    fn test_shadow_candidate() -> Fallible<()> {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let shadow = uppercase().with_max_differences(1);
+        let mut worker = shadow.clone();
+
+        // only the primary's output is passed on
+        for (run, input) in ["a", " b", " c "].iter().enumerate() {
+            assert_eq!(
+                runtime.block_on(worker.run(input.to_string()))?,
+                input.to_uppercase()
+            );
+            compared(&shadow, run as u64 + 1);
+        }
+        let report = compared(&shadow, 3);
+        assert_eq!((report.runs, report.skipped), (3, 0));
+        assert_eq!((report.matches, report.mismatches), (1, 2));
+        assert_eq!(report.candidate_slower, 3);
+        assert!(report.candidate_latency.mean >= Duration::from_millis(10));
+        assert_eq!(
+            report.differences,
+            vec![ShadowDifference {
+                input: "\" c \"".to_string(),
+                primary: "\" C \"".to_string(),
+                candidate: "\"C\"".to_string(),
+            }]
+        );
+
+        // runs outside the sample and runs while the candidate is busy go unshadowed
+        let mut unsampled = uppercase().with_fraction(0.0);
+        runtime.block_on(unsampled.run("a".to_string()))?;
+        let busy = uppercase();
+        let mut worker = busy.clone();
+        runtime.block_on(
+            worker
+                .run("a".to_string())
+                .join(worker.run("b".to_string())),
+        )?;
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(unsampled.report().compared, 0);
+        let report = busy.report();
+        assert_eq!((report.runs, report.compared, report.skipped), (2, 1, 1));
+
+        // the primary's errors are passed on as they are
+        let mut refusing = ShadowWorker::new(
+            Box::new(InternalWorkWrapper(InternalRefusing)),
+            Box::new(InternalWorkWrapper(InternalUppercase { candidate: true })),
+        );
+        let error = runtime
+            .block_on(refusing.run("a".to_string()))
+            .expect_err("primary should refuse");
+        assert!(error.downcast_ref::<Refused>().is_some());
+        assert_eq!(
+            compared(&refusing, 1).differences[0].primary,
+            "error: refused a"
+        );
+
+        // a broken candidate neither fails nor holds back the stage
+        let mut broken = ShadowWorker::new(
+            Box::new(InternalWorkWrapper(InternalUppercase { candidate: false })),
+            Box::new(InternalWorkWrapper(InternalRefusing)),
+        );
+        assert_eq!(runtime.block_on(broken.run("panic".to_string()))?, "PANIC");
+        assert_eq!(
+            compared(&broken, 1).differences[0].candidate,
+            "error: candidate panicked: refusing got panic"
+        );
+        runtime.block_on(broken.init(&serde_json::json!({})))?;
+        assert_eq!(
+            broken.report().candidate_failure.as_deref(),
+            Some("can't start")
+        );
+        assert_eq!(runtime.block_on(broken.run("b".to_string()))?, "B");
+        std::thread::sleep(Duration::from_millis(50));
+        let report = broken.report();
+        assert_eq!((report.runs, report.compared), (2, 1));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,235 @@
+use crate::lock_monitor::{locking, LockMonitor, MonitoredGuard, RunId};
+use failure::Error;
+use futures::future::{Either, Shared};
+use futures::sync::oneshot;
+use futures::Future;
+use futures_locks::Mutex as FuturesMutex;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::timer::Timeout;
+
+type ControlledFuture<T> = Box<dyn Future<Item = T, Error = Error> + Send>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StagePhase {
+    /// Waiting for the stage's mutex
+    Lock,
+    /// Waiting for the future returned by the stage's worker
+    Run,
+}
+
+#[derive(Debug, Fail)]
+pub enum StageError {
+    #[fail(
+        display = "stage {} timed out after {:?} ({:?})",
+        stage, timeout, phase
+    )]
+    Timeout {
+        stage: usize,
+        phase: StagePhase,
+        timeout: Duration,
+    },
+    #[fail(display = "stage {} was cancelled ({:?})", stage, phase)]
+    Cancelled { stage: usize, phase: StagePhase },
+    #[fail(display = "pipeline timed out after {:?}", _0)]
+    PipelineTimeout(Duration),
+}
+
+/// Stops in-flight runs which were started with a `ProcessControl` holding this handle.
+///
+/// Cancelling drops the pending lock and worker futures, which releases their mutexes.
+/// A lock wait which was given up releases its mutex as soon as it gets it.
+#[derive(Clone)]
+pub struct CancellationHandle {
+    sender: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+    cancelled: Shared<oneshot::Receiver<()>>,
+}
+
+impl CancellationHandle {
+    pub fn new() -> Self {
+        let (sender, receiver) = oneshot::channel();
+
+        CancellationHandle {
+            sender: Arc::new(Mutex::new(Some(sender))),
+            cancelled: receiver.shared(),
+        }
+    }
+
+    pub fn cancel(self: &Self) {
+        if let Some(sender) = self.sender.lock().unwrap().take() {
+            let _ = sender.send(());
+        }
+    }
+
+    /// Resolves once `cancel` has been called.
+    fn cancelled(self: &Self) -> impl Future<Item = (), Error = ()> {
+        self.cancelled.clone().then(|result| match result {
+            Ok(_) => Either::A(futures::future::ok(())),
+            // all handles are gone, so this can never be cancelled anymore
+            Err(_) => Either::B(futures::future::empty()),
+        })
+    }
+}
+
+impl Default for CancellationHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Timeouts, cancellation and lock monitoring for the stages of a `process` run.
+///
+/// The default waits forever, just like the plain `process` functions.
+#[derive(Clone, Default)]
+pub struct ProcessControl {
+    stage_timeout: Option<Duration>,
+    pipeline_timeout: Option<Duration>,
+    cancellation: Option<CancellationHandle>,
+    lock_monitor: Option<LockMonitor>,
+    name: Option<String>,
+    run: RunId,
+}
+
+impl ProcessControl {
+    /// Limits both the wait for each stage's lock and the run of its worker.
+    pub fn with_stage_timeout(mut self: Self, timeout: Duration) -> Self {
+        self.stage_timeout = Some(timeout);
+        self
+    }
+
+    /// Limits the whole run across all stages.
+    pub fn with_pipeline_timeout(mut self: Self, timeout: Duration) -> Self {
+        self.pipeline_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_cancellation(mut self: Self, cancellation: CancellationHandle) -> Self {
+        self.cancellation = Some(cancellation);
+        self
+    }
+
+    /// Names the pipeline in the lock labels of the lock monitor, e.g. `orders stage 0`.
+    pub fn with_name(mut self: Self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Records the waits for and holds of stage locks in `lock_monitor`.
+    pub fn with_lock_monitor(mut self: Self, lock_monitor: LockMonitor) -> Self {
+        self.lock_monitor = Some(lock_monitor);
+        self
+    }
+
+    /// Returns a copy for a new run, which the lock monitor tells apart from others.
+    pub fn for_run(self: &Self) -> Self {
+        let mut control = self.clone();
+        if let Some(ref lock_monitor) = self.lock_monitor {
+            control.run = lock_monitor.next_run();
+        }
+        control
+    }
+
+    /// Locks the mutex of a stage with the stage timeout, the cancellation and the
+    /// lock monitor applied.
+    pub fn lock<T>(
+        self: &Self,
+        stage: usize,
+        mutex: &Arc<FuturesMutex<T>>,
+    ) -> ControlledFuture<MonitoredGuard<T>>
+    where
+        T: Send + 'static,
+    {
+        match self.lock_monitor {
+            Some(ref lock_monitor) => self.stage(
+                stage,
+                StagePhase::Lock,
+                lock_monitor.lock(self.run, &self.label(stage), mutex),
+            ),
+            None => self.stage(
+                stage,
+                StagePhase::Lock,
+                locking(mutex)
+                    .map(MonitoredGuard::unmonitored)
+                    .map_err(|_| failure::err_msg("could not acquire the mutex lock")),
+            ),
+        }
+    }
+
+    /// Label of a stage's lock in the lock monitor
+    fn label(self: &Self, stage: usize) -> String {
+        match self.name {
+            Some(ref name) => format!("{} stage {}", name, stage),
+            None => format!("stage {}", stage),
+        }
+    }
+
+    /// Applies the stage timeout and the cancellation to one phase of a stage, and has
+    /// the lock monitor record the nested locks taken in it.
+    pub fn stage<F>(
+        self: &Self,
+        stage: usize,
+        phase: StagePhase,
+        future: F,
+    ) -> ControlledFuture<F::Item>
+    where
+        F: Future<Error = Error> + Send + 'static,
+        F::Item: Send + 'static,
+    {
+        let future: ControlledFuture<F::Item> = match self.lock_monitor {
+            Some(ref lock_monitor) => {
+                Box::new(lock_monitor.in_stage(self.run, &self.label(stage), future))
+            }
+            None => Box::new(future),
+        };
+        let future: ControlledFuture<F::Item> = match self.stage_timeout {
+            Some(timeout) => Box::new(Timeout::new(future, timeout).map_err(move |e| {
+                if e.is_elapsed() {
+                    StageError::Timeout {
+                        stage,
+                        phase,
+                        timeout,
+                    }
+                    .into()
+                } else if e.is_inner() {
+                    e.into_inner().expect("checked by is_inner")
+                } else {
+                    e.into_timer().expect("neither elapsed nor inner").into()
+                }
+            })),
+            None => Box::new(future),
+        };
+
+        match self.cancellation {
+            Some(ref cancellation) => Box::new(future.select2(cancellation.cancelled()).then(
+                move |result| match result {
+                    Ok(Either::A((item, _))) => Ok(item),
+                    Err(Either::A((e, _))) => Err(e),
+                    Ok(Either::B(_)) | Err(Either::B(_)) => {
+                        Err(StageError::Cancelled { stage, phase }.into())
+                    }
+                },
+            )),
+            None => future,
+        }
+    }
+
+    /// Applies the pipeline timeout to a whole run.
+    pub fn pipeline<F>(self: &Self, future: F) -> ControlledFuture<F::Item>
+    where
+        F: Future<Error = Error> + Send + 'static,
+        F::Item: Send + 'static,
+    {
+        match self.pipeline_timeout {
+            Some(timeout) => Box::new(Timeout::new(future, timeout).map_err(move |e| {
+                if e.is_elapsed() {
+                    StageError::PipelineTimeout(timeout).into()
+                } else if e.is_inner() {
+                    e.into_inner().expect("checked by is_inner")
+                } else {
+                    e.into_timer().expect("neither elapsed nor inner").into()
+                }
+            })),
+            None => Box::new(future),
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use crate::lock_monitor::locking;
+use crate::wrapped::{Work, WorkIO};
+use failure::{Error, Fallible};
+use futures::future::Either;
+use futures::sync::{mpsc, oneshot};
+use futures::{Future, Sink, Stream};
+
+#[derive(Debug, Fail)]
+pub enum StreamError {
+    /// The stage's task ended before its inputs did, e.g. because its worker panicked
+    #[fail(display = "stage {} stopped before its inputs ended", stage)]
+    StageStopped { stage: usize },
+}
+
+/// Runs every item of `inputs` through the stages of `work_collection`.
+///
+/// Each stage runs as its own task and hands its outputs to the next stage through a
+/// bounded queue of `capacity` items, so stage N can work on item k+1 while stage N+1
+/// works on item k. A full queue holds back the stage in front of it. Outputs are
+/// yielded in the order of their inputs, and a failed item skips all later stages.
+///
+/// The stage tasks are spawned onto the tokio executor once the stream is first polled.
+/// If one of them stops early, the stream fails with `StreamError::StageStopped` after
+/// the outputs which made it through.
+pub fn process_stream<T, S>(
+    work_collection: T,
+    inputs: S,
+    capacity: usize,
+) -> impl Stream<Item = WorkIO, Error = Error>
+where
+    T: IntoIterator<Item = Work>,
+    T::IntoIter: Send + 'static,
+    S: Stream<Item = WorkIO, Error = Error> + Send + 'static,
+{
+    let work_collection = work_collection.into_iter();
+
+    futures::future::lazy(move || {
+        let (input_sender, mut receiver) = mpsc::channel::<Fallible<WorkIO>>(capacity);
+        tokio::spawn(
+            inputs
+                .then(Ok::<_, ()>)
+                .forward(input_sender.sink_map_err(|_| ()))
+                .map(|_| ()),
+        );
+
+        let mut finished = Vec::new();
+        for (stage, work) in work_collection.enumerate() {
+            let (sender, next_receiver) = mpsc::channel::<Fallible<WorkIO>>(capacity);
+            // dropped without a message if the task ends early
+            let (done, stage_finished) = oneshot::channel::<()>();
+            finished.push(stage_finished);
+
+            tokio::spawn(
+                receiver
+                    .and_then(move |item| match item {
+                        Ok(input) => {
+                            println!("[] stage {} getting work lock...", stage);
+                            Either::A(
+                                locking(&work)
+                                    .map_err(|_| {
+                                        failure::err_msg("could not acquire the mutex lock")
+                                    })
+                                    .and_then(move |mut next_item| {
+                                        println!("[] stage {} input: {}", stage, input);
+                                        (*next_item).run(input)
+                                    })
+                                    .then(Ok),
+                            )
+                        }
+                        Err(e) => Either::B(futures::future::ok(Err(e))),
+                    })
+                    .forward(sender.sink_map_err(|_| ()))
+                    .map(move |_| {
+                        let _ = done.send(());
+                    }),
+            );
+
+            receiver = next_receiver;
+        }
+
+        // the stages in front of a stopped one stop as well once it drops their outputs
+        let stopped = futures::future::join_all(finished.into_iter().map(|f| f.then(Ok::<_, ()>)))
+            .then(|results| {
+                let results = results.expect("results of the stages don't fail");
+                match results.iter().rposition(|result| result.is_err()) {
+                    Some(stage) => Err(StreamError::StageStopped { stage }.into()),
+                    None => Ok(None),
+                }
+            })
+            .into_stream()
+            .filter_map(|item| item);
+
+        Ok::<_, Error>(
+            receiver
+                .then(|item| item.expect("mpsc receivers don't fail"))
+                .chain(stopped),
+        )
+    })
+    .flatten_stream()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wrapped::{AsyncResult, AsyncWorkerInternal, InternalWorkWrapper};
+    use futures_locks::Mutex as FuturesMutex;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    /// Appends its stage number after a delay and logs when it starts and ends an item
+    #[derive(Clone)]
+    struct InternalDelayingForwarder {
+        stage: usize,
+        delay: Duration,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+    impl AsyncWorkerInternal<WorkIO> for InternalDelayingForwarder {
+        fn run_internal(self: &mut Self, input: WorkIO) -> AsyncResult<WorkIO> {
+            let stage = self.stage;
+            let log = self.log.clone();
+            log.lock()
+                .unwrap()
+                .push(format!("{} start {}", stage, input));
+
+            Box::new(
+                tokio::timer::Delay::new(Instant::now() + self.delay)
+                    .map_err(Error::from)
+                    .map(move |_| {
+                        log.lock().unwrap().push(format!("{} end {}", stage, input));
+                        format!("{}{}", input, stage)
+                    }),
+            )
+        }
+    }
+
+    #[test]
+    fn test_process_stream() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let work_collection: Vec<Work> = vec![(0, 10), (1, 50)]
+            .into_iter()
+            .map(|(stage, delay)| -> Work {
+                Arc::new(FuturesMutex::new(Box::new(InternalWorkWrapper(
+                    InternalDelayingForwarder {
+                        stage,
+                        delay: Duration::from_millis(delay),
+                        log: log.clone(),
+                    },
+                ))))
+            })
+            .collect();
+
+        let inputs = futures::stream::iter_ok(vec!["a", "b", "c"].into_iter().map(String::from));
+
+        let outputs = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(process_stream(work_collection, inputs, 1).collect())
+            .expect("stream failed");
+        assert_eq!(outputs, vec!["a01", "b01", "c01"]);
+
+        // stage 0 moved on to the next item while stage 1 was still busy
+        let log = log.lock().unwrap();
+        let position = |event: &str| log.iter().position(|e| e == event).unwrap();
+        assert!(position("0 start b") < position("1 end a0"));
+    }
+
+    #[test]
+    fn test_process_stream_stopped_stage() {
+        use crate::fixtures::InternalSlowCounter;
+
+        let work_collection: Vec<Work> = vec![
+            Arc::new(FuturesMutex::new(Box::new(InternalWorkWrapper(
+                InternalSlowCounter::new(Duration::from_millis(10)),
+            )))),
+            Arc::new(FuturesMutex::new(Box::new(InternalWorkWrapper(
+                InternalDelayingForwarder {
+                    stage: 1,
+                    delay: Duration::from_millis(10),
+                    log: Default::default(),
+                },
+            )))),
+        ];
+        let inputs =
+            futures::stream::iter_ok(vec!["a", "panic", "c"].into_iter().map(String::from));
+
+        let mut items: Vec<Fallible<WorkIO>> = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(
+                process_stream(work_collection, inputs, 1)
+                    .then(Ok::<_, ()>)
+                    .collect(),
+            )
+            .unwrap();
+
+        // the stream fails after the outputs which made it through
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].as_ref().ok().map(String::as_str), Some("a11"));
+        match items
+            .pop()
+            .unwrap()
+            .map_err(|e| e.downcast::<StreamError>())
+        {
+            Err(Ok(StreamError::StageStopped { stage: 0 })) => (),
+            other => panic!("expected stage 0 to stop, got {:?}", other),
+        }
+    }
+}
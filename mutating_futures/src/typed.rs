@@ -0,0 +1,350 @@
+use crate::introspection::short_type_name;
+use crate::wrapped::{with_locked, AsyncResult, AsyncWorker};
+use failure::{Error, Fail, Fallible};
+use futures::{Future, IntoFuture};
+use futures_locks::Mutex as FuturesMutex;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+/// Worker whose input and output may be of any type, and of different types.
+///
+/// Every `AsyncWorker<T>` is a TypedWorker from T to T once boxed.
+///
+/// TypedWorkers have no lifecycle hooks, and a TypedPipeline only runs its workers. So
+/// workers which need to be initialized, warmed up, checked or shut down are handled by
+/// their owner before they are added and after the pipeline is dropped; the hooks of a
+/// boxed AsyncWorker are not called either.
+pub trait TypedWorker
+where
+    Self: Send + 'static,
+{
+    type Input: Send + 'static;
+    type Output: Send + 'static;
+
+    fn run(self: &mut Self, input: Self::Input) -> AsyncResult<Self::Output>;
+}
+
+impl<T> TypedWorker for Box<dyn AsyncWorker<T>>
+where
+    T: Sync + Send + Clone + 'static,
+{
+    type Input = T;
+    type Output = T;
+
+    fn run(self: &mut Self, input: T) -> AsyncResult<T> {
+        (**self).run(input)
+    }
+}
+
+/// Caused by the error of the failed `TryFrom` conversion
+#[derive(Debug, Fail)]
+#[fail(display = "could not convert {} into {} for stage {}", from, to, stage)]
+pub struct ConversionError {
+    pub stage: usize,
+    pub from: String,
+    pub to: String,
+    #[cause]
+    pub cause: Error,
+}
+
+type StageFn<I, O> = Arc<dyn Fn(I) -> AsyncResult<O> + Send + Sync>;
+
+/// Runs `worker` behind a mutex, so the stage can be shared by clones of a pipeline.
+fn stage<W: TypedWorker>(worker: W) -> StageFn<W::Input, W::Output> {
+    let worker = Arc::new(FuturesMutex::new(worker));
+
+    Arc::new(move |input| with_locked(&worker, move |worker| worker.run(input)))
+}
+
+/// Chain of TypedWorkers from `I` to `O`.
+///
+/// Each stage converts the output of the stage before it into its own input, through
+/// `From` with `then` or through `TryFrom` with `then_try`. Stages which don't fit
+/// together don't compile. Clones share their stages.
+pub struct TypedPipeline<I, O> {
+    run: StageFn<I, O>,
+    stages: usize,
+}
+
+impl<I, O> Clone for TypedPipeline<I, O> {
+    fn clone(&self) -> Self {
+        TypedPipeline {
+            run: self.run.clone(),
+            stages: self.stages,
+        }
+    }
+}
+
+impl<I, O> TypedPipeline<I, O>
+where
+    I: Send + 'static,
+    O: Send + 'static,
+{
+    pub fn new<W>(worker: W) -> Self
+    where
+        W: TypedWorker<Input = I, Output = O>,
+    {
+        TypedPipeline {
+            run: stage(worker),
+            stages: 1,
+        }
+    }
+
+    pub fn len(self: &Self) -> usize {
+        self.stages
+    }
+
+    pub fn is_empty(self: &Self) -> bool {
+        self.stages == 0
+    }
+
+    /// Appends `worker`, whose input can always be made from the current output.
+    pub fn then<W>(self: Self, worker: W) -> TypedPipeline<I, W::Output>
+    where
+        W: TypedWorker,
+        W::Input: From<O>,
+    {
+        self.then_with(worker, |output| Ok(W::Input::from(output)))
+    }
+
+    /// Appends `worker`, failing runs whose output can't be converted into its input
+    /// with a ConversionError.
+    pub fn then_try<W>(self: Self, worker: W) -> TypedPipeline<I, W::Output>
+    where
+        W: TypedWorker,
+        W::Input: TryFrom<O>,
+        <W::Input as TryFrom<O>>::Error: Fail,
+    {
+        let stage = self.stages;
+        self.then_with(worker, move |output| {
+            W::Input::try_from(output).map_err(|e| {
+                ConversionError {
+                    stage,
+                    from: short_type_name::<O>(),
+                    to: short_type_name::<W::Input>(),
+                    cause: e.into(),
+                }
+                .into()
+            })
+        })
+    }
+
+    fn then_with<W, C>(self: Self, worker: W, convert: C) -> TypedPipeline<I, W::Output>
+    where
+        W: TypedWorker,
+        C: Fn(O) -> Fallible<W::Input> + Send + Sync + 'static,
+    {
+        let previous = self.run;
+        let next = stage(worker);
+        let convert = Arc::new(convert);
+
+        TypedPipeline {
+            run: Arc::new(move |input| {
+                let next = next.clone();
+                let convert = convert.clone();
+                Box::new(previous(input).and_then(move |output| {
+                    convert(output)
+                        .into_future()
+                        .and_then(move |input| next(input))
+                }))
+            }),
+            stages: self.stages + 1,
+        }
+    }
+
+    pub fn process(self: &Self, input: I) -> AsyncResult<O> {
+        (self.run)(input)
+    }
+}
+
+// The conversions between payloads only exist for `String` and the `Text`, `Bytes` and
+// `Json` newtypes. Other payloads, e.g. `Vec<u8>` or `serde_json::Value` themselves,
+// need conversions of their own, which the orphan rule only allows for a type of the
+// crate which defines them, so they are wrapped in a newtype of that crate first.
+
+/// UTF-8 text payload
+#[derive(Debug, Clone, PartialEq)]
+pub struct Text(pub String);
+
+/// Binary payload
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bytes(pub Vec<u8>);
+
+/// Structured payload
+#[derive(Debug, Clone, PartialEq)]
+pub struct Json(pub serde_json::Value);
+
+impl From<String> for Text {
+    fn from(text: String) -> Self {
+        Text(text)
+    }
+}
+
+impl From<Text> for String {
+    fn from(text: Text) -> Self {
+        text.0
+    }
+}
+
+impl From<String> for Bytes {
+    fn from(text: String) -> Self {
+        Bytes(text.into_bytes())
+    }
+}
+
+impl From<Text> for Bytes {
+    fn from(text: Text) -> Self {
+        Bytes(text.0.into_bytes())
+    }
+}
+
+impl TryFrom<Bytes> for Text {
+    type Error = std::string::FromUtf8Error;
+
+    fn try_from(bytes: Bytes) -> Result<Self, Self::Error> {
+        String::from_utf8(bytes.0).map(Text)
+    }
+}
+
+impl From<Json> for Text {
+    fn from(json: Json) -> Self {
+        Text(json.0.to_string())
+    }
+}
+
+impl From<Json> for String {
+    fn from(json: Json) -> Self {
+        json.0.to_string()
+    }
+}
+
+impl From<Json> for Bytes {
+    fn from(json: Json) -> Self {
+        Bytes(json.0.to_string().into_bytes())
+    }
+}
+
+impl TryFrom<Text> for Json {
+    type Error = serde_json::Error;
+
+    fn try_from(text: Text) -> Result<Self, Self::Error> {
+        serde_json::from_str(&text.0).map(Json)
+    }
+}
+
+impl TryFrom<String> for Json {
+    type Error = serde_json::Error;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        serde_json::from_str(&text).map(Json)
+    }
+}
+
+impl TryFrom<Bytes> for Json {
+    type Error = serde_json::Error;
+
+    fn try_from(bytes: Bytes) -> Result<Self, Self::Error> {
+        serde_json::from_slice(&bytes.0).map(Json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wrapped::{AsyncWorkerInternal, InternalWorkWrapper, WorkIO};
+    use serde::{Deserialize, Serialize};
+
+    /// Turns a number into a JSON object, without checking it is a number
+    #[derive(Clone)]
+    struct InternalTemplate;
+    impl AsyncWorkerInternal<WorkIO> for InternalTemplate {
+        fn run_internal(self: &mut Self, input: WorkIO) -> AsyncResult<WorkIO> {
+            Box::new(futures::future::ok(format!("{{\"count\": {}}}", input)))
+        }
+    }
+
+    struct Stamp;
+    impl TypedWorker for Stamp {
+        type Input = Json;
+        type Output = Json;
+
+        fn run(self: &mut Self, mut input: Json) -> AsyncResult<Json> {
+            input.0["stamped"] = serde_json::Value::Bool(true);
+            Box::new(futures::future::ok(input))
+        }
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Tally {
+        count: u64,
+    }
+
+    impl TryFrom<Json> for Tally {
+        type Error = serde_json::Error;
+
+        fn try_from(json: Json) -> Result<Self, Self::Error> {
+            serde_json::from_value(json.0)
+        }
+    }
+
+    impl From<Tally> for Bytes {
+        fn from(tally: Tally) -> Self {
+            Bytes(serde_json::to_vec(&tally).expect("tally serializes"))
+        }
+    }
+
+    struct Increment;
+    impl TypedWorker for Increment {
+        type Input = Tally;
+        type Output = Tally;
+
+        fn run(self: &mut Self, input: Tally) -> AsyncResult<Tally> {
+            Box::new(futures::future::ok(Tally {
+                count: input.count + 1,
+            }))
+        }
+    }
+
+    struct Size;
+    impl TypedWorker for Size {
+        type Input = Bytes;
+        type Output = usize;
+
+        fn run(self: &mut Self, input: Bytes) -> AsyncResult<usize> {
+            Box::new(futures::future::ok(input.0.len()))
+        }
+    }
+
+    #[test]
+    fn test_process_typed_pipeline() -> Fallible<()> {
+        let template: Box<dyn AsyncWorker<WorkIO>> =
+            Box::new(InternalWorkWrapper(InternalTemplate));
+        let pipeline = TypedPipeline::new(template)
+            .then_try(Stamp)
+            .then_try(Increment)
+            .then(Size);
+        assert_eq!(pipeline.len(), 4);
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        // {"count":42}
+        assert_eq!(runtime.block_on(pipeline.process("41".to_string()))?, 12);
+
+        let error = runtime
+            .block_on(pipeline.clone().process("many".to_string()))
+            .expect_err("template output should not parse");
+        let error = error
+            .downcast::<ConversionError>()
+            .expect("error is not a ConversionError");
+        assert_eq!((error.stage, error.from.as_str()), (1, "String"));
+        assert_eq!(error.to, "Json");
+        assert!(error.cause.downcast_ref::<serde_json::Error>().is_some());
+
+        assert_eq!(
+            Text::try_from(Bytes::from(Text("ü".to_string()))),
+            Ok(Text("ü".to_string()))
+        );
+        assert!(Text::try_from(Bytes(vec![0xff])).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,318 @@
+use crate::lock_monitor::locking;
+use crate::wrapped::{
+    with_each_locked, with_locked, AsyncResult, AsyncWorker, Compensation, ServedRuns, WorkIO,
+};
+use futures::Future;
+use futures_locks::Mutex as FuturesMutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// How a WorkerPool picks the instance for the next run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SelectionPolicy {
+    RoundRobin,
+    /// The instance with the fewest unfinished runs, round robin among equals
+    LeastLoaded,
+}
+
+/// Lets the state of several pool instances be combined into one, see
+/// `WorkerPool::merged_snapshot`
+pub trait MergeState {
+    fn merge_state(self: &mut Self, other: &Self);
+}
+
+struct PoolInstance<W> {
+    worker: Arc<FuturesMutex<W>>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+/// Decrements an instance's in-flight counter when its run finishes or is dropped
+struct InFlight(Arc<AtomicUsize>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// AsyncWorker made of several instances of a worker, each behind its own lock.
+///
+/// Concurrent runs are spread over the instances instead of queueing on a single
+/// mutex. Clones share the same instances.
+pub struct WorkerPool<W> {
+    instances: Arc<Vec<PoolInstance<W>>>,
+    policy: SelectionPolicy,
+    next: Arc<AtomicUsize>,
+    /// The instance which served each run, for the compensation
+    served: ServedRuns<usize>,
+}
+
+impl<W> Clone for WorkerPool<W> {
+    fn clone(&self) -> Self {
+        WorkerPool {
+            instances: self.instances.clone(),
+            policy: self.policy,
+            next: self.next.clone(),
+            served: self.served.clone(),
+        }
+    }
+}
+
+impl<W> WorkerPool<W>
+where
+    W: AsyncWorker<WorkIO> + 'static,
+{
+    /// Creates `size` instances by calling `factory` with each instance's index.
+    pub fn new<F>(size: usize, mut factory: F, policy: SelectionPolicy) -> Self
+    where
+        F: FnMut(usize) -> W,
+    {
+        assert!(size > 0, "a worker pool needs at least one instance");
+
+        WorkerPool {
+            instances: Arc::new(
+                (0..size)
+                    .map(|index| PoolInstance {
+                        worker: Arc::new(FuturesMutex::new(factory(index))),
+                        in_flight: Arc::new(AtomicUsize::new(0)),
+                    })
+                    .collect(),
+            ),
+            policy,
+            next: Arc::new(AtomicUsize::new(0)),
+            served: ServedRuns::default(),
+        }
+    }
+
+    /// Returns the number of unfinished runs of every instance.
+    pub fn in_flight(self: &Self) -> Vec<usize> {
+        self.instances
+            .iter()
+            .map(|instance| instance.in_flight.load(Ordering::SeqCst))
+            .collect()
+    }
+
+    fn select(self: &Self) -> usize {
+        let start = self.next.fetch_add(1, Ordering::SeqCst) % self.instances.len();
+
+        match self.policy {
+            SelectionPolicy::RoundRobin => start,
+            SelectionPolicy::LeastLoaded => (0..self.instances.len())
+                .map(|offset| (start + offset) % self.instances.len())
+                .min_by_key(|index| self.instances[*index].in_flight.load(Ordering::SeqCst))
+                .unwrap_or(start),
+        }
+    }
+
+    /// Returns a copy of the first instance's state with the state of all other
+    /// instances merged into it.
+    ///
+    /// The snapshot is read-only: the instances keep their own state, and changes to
+    /// the returned copy are not written back to them.
+    pub fn merged_snapshot(self: &Self) -> AsyncResult<W>
+    where
+        W: MergeState + Clone,
+    {
+        let locks: Vec<_> = self
+            .instances
+            .iter()
+            .map(|instance| locking(&instance.worker))
+            .collect();
+
+        Box::new(
+            futures::future::join_all(locks)
+                .map_err(|_| failure::err_msg("could not acquire the mutex lock"))
+                .map(|guards| {
+                    let mut merged = (*guards[0]).clone();
+                    for guard in &guards[1..] {
+                        merged.merge_state(guard);
+                    }
+                    merged
+                }),
+        )
+    }
+
+    /// Calls `f` on every instance and fails if it fails for any of them.
+    fn on_every_instance<F>(self: &Self, f: F) -> AsyncResult<()>
+    where
+        F: Fn(&mut W) -> AsyncResult<()> + Send + Sync + 'static,
+    {
+        with_each_locked(self.instances.iter().map(|instance| &instance.worker), f)
+    }
+}
+
+impl<W> AsyncWorker<WorkIO> for WorkerPool<W>
+where
+    W: AsyncWorker<WorkIO> + 'static,
+{
+    fn init(self: &mut Self, config: &serde_json::Value) -> AsyncResult<()> {
+        let config = config.clone();
+        self.on_every_instance(move |worker| worker.init(&config))
+    }
+
+    fn warmup(self: &mut Self) -> AsyncResult<()> {
+        self.on_every_instance(|worker| worker.warmup())
+    }
+
+    fn health(self: &mut Self) -> AsyncResult<()> {
+        self.on_every_instance(|worker| worker.health())
+    }
+
+    fn shutdown(self: &mut Self) -> AsyncResult<()> {
+        self.on_every_instance(|worker| worker.shutdown())
+    }
+
+    /// Asks the instance which served the run.
+    fn compensation(
+        self: &mut Self,
+        input: &WorkIO,
+        output: &WorkIO,
+    ) -> AsyncResult<Option<Compensation>> {
+        let instance = match self.served.take(input, output) {
+            Ok(index) => &self.instances[index],
+            Err(e) => return Box::new(futures::future::err(e.into())),
+        };
+        let (input, output) = (input.clone(), output.clone());
+        with_locked(&instance.worker, move |worker| {
+            worker.compensation(&input, &output)
+        })
+    }
+
+    fn run(self: &mut Self, input: WorkIO) -> AsyncResult<WorkIO> {
+        let index = self.select();
+        let instance = &self.instances[index];
+        instance.in_flight.fetch_add(1, Ordering::SeqCst);
+        let in_flight = InFlight(instance.in_flight.clone());
+        let served = self.served.clone();
+
+        Box::new(
+            with_locked(&instance.worker, {
+                let input = input.clone();
+                move |worker| worker.run(input)
+            })
+            .then(move |result| {
+                drop(in_flight);
+                if let Ok(ref output) = result {
+                    served.record(input, output.clone(), index);
+                }
+                result
+            }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wrapped::{process, Work, WorkCollection};
+    use failure::{Error, Fallible};
+    use std::time::{Duration, Instant};
+
+    /// Appends its instance index and counts its runs, finishing after a delay
+    #[derive(Clone)]
+    struct CountingWorker {
+        instance: usize,
+        runs: usize,
+        delay: Duration,
+    }
+
+    impl AsyncWorker<WorkIO> for CountingWorker {
+        fn run(self: &mut Self, input: WorkIO) -> AsyncResult<WorkIO> {
+            self.runs += 1;
+            let output = format!("{}{}", input, self.instance);
+
+            Box::new(
+                tokio::timer::Delay::new(Instant::now() + self.delay)
+                    .map_err(Error::from)
+                    .map(move |_| output),
+            )
+        }
+
+        fn compensation(
+            self: &mut Self,
+            input: &WorkIO,
+            output: &WorkIO,
+        ) -> AsyncResult<Option<Compensation>> {
+            if *output != format!("{}{}", input, self.instance) {
+                return Box::new(futures::future::err(failure::format_err!(
+                    "instance {} did not serve {}",
+                    self.instance,
+                    output
+                )));
+            }
+
+            let compensation: Compensation =
+                Box::new(|| -> AsyncResult<()> { Box::new(futures::future::ok(())) });
+            Box::new(futures::future::ok(Some(compensation)))
+        }
+    }
+
+    impl MergeState for CountingWorker {
+        fn merge_state(self: &mut Self, other: &Self) {
+            self.runs += other.runs;
+        }
+    }
+
+    fn pool(policy: SelectionPolicy) -> WorkerPool<CountingWorker> {
+        WorkerPool::new(
+            3,
+            |instance| CountingWorker {
+                instance,
+                runs: 0,
+                delay: Duration::from_millis(20),
+            },
+            policy,
+        )
+    }
+
+    #[test]
+    fn test_process_with_worker_pool() -> Fallible<()> {
+        lazy_static! {
+            static ref POOL: WorkerPool<CountingWorker> = pool(SelectionPolicy::RoundRobin);
+            static ref WORK_COLLECTION: WorkCollection = vec![{
+                let work: Work = Arc::new(FuturesMutex::new(Box::new(POOL.clone())));
+                work
+            }];
+        }
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let results = runtime.block_on(futures::future::join_all(
+            (0..6)
+                .map(|_| process(WORK_COLLECTION.iter()))
+                .collect::<Vec<_>>(),
+        ))?;
+        assert_eq!(results, vec!["0", "1", "2", "0", "1", "2"]);
+        assert_eq!(runtime.block_on(POOL.merged_snapshot())?.runs, 6);
+        assert_eq!(POOL.in_flight(), vec![0, 0, 0]);
+        // taking a snapshot leaves the instances alone
+        assert_eq!(runtime.block_on(POOL.merged_snapshot())?.runs, 6);
+
+        // busy instances are skipped until every instance has a run
+        let mut least_loaded = pool(SelectionPolicy::LeastLoaded);
+        let first = least_loaded.run("a".to_string());
+        let second = least_loaded.run("b".to_string());
+        assert_eq!(least_loaded.in_flight(), vec![1, 1, 0]);
+        let results = runtime.block_on(first.join3(second, least_loaded.run("c".to_string())))?;
+        assert_eq!(
+            results,
+            ("a0".to_string(), "b1".to_string(), "c2".to_string())
+        );
+        assert_eq!(least_loaded.in_flight(), vec![0, 0, 0]);
+
+        // the instance which served a run is asked for its compensation, without
+        // taking a turn from the next run
+        let mut round_robin = pool(SelectionPolicy::RoundRobin);
+        let served = runtime.block_on(round_robin.run("a".to_string()))?;
+        assert_eq!(runtime.block_on(round_robin.run("b".to_string()))?, "b1");
+        let compensation = runtime.block_on(round_robin.compensation(&"a".to_string(), &served))?;
+        assert!(compensation.is_some());
+        assert_eq!(runtime.block_on(round_robin.run("c".to_string()))?, "c2");
+        // each run is compensated once
+        assert!(runtime
+            .block_on(round_robin.compensation(&"a".to_string(), &served))
+            .is_err());
+
+        Ok(())
+    }
+}
@@ -1,37 +1,211 @@
 #![allow(dead_code)]
 
+use crate::lock_monitor::lock_nested;
+use crate::stage_control::{ProcessControl, StagePhase};
 use failure::Error;
 use futures::IntoFuture;
 use futures::{Future, Stream};
 use futures_locks::Mutex as FuturesMutex;
-use std::sync::Arc;
+use std::borrow::Borrow;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
 
-type AsyncResult<T> = Box<dyn Future<Item = T, Error = Error> + Send>;
+pub type AsyncResult<T> = Box<dyn Future<Item = T, Error = Error> + Send>;
 
-type WorkIO = String;
+pub type WorkIO = String;
 
-trait AsyncWorker<T>
+/// Undoes the effects of a successful run, see `AsyncWorker::compensation`
+pub type Compensation = Box<dyn FnOnce() -> AsyncResult<()> + Send>;
+
+/// How many runs a `ServedRuns` keeps
+const SERVED_RUNS: usize = 64;
+
+#[derive(Debug, Fail)]
+#[fail(display = "run from {:?} to {:?} is not known anymore", input, output)]
+pub struct UnknownRun {
+    pub input: WorkIO,
+    pub output: WorkIO,
+}
+
+/// The latest runs of a wrapping worker and what served each of them.
+///
+/// `AsyncWorker::compensation` only gets the input and output of a run, so wrappers
+/// which pass it on to the workers that served the run look those up here. Clones
+/// share the same runs.
+#[derive(Clone)]
+pub struct ServedRuns<S> {
+    runs: Arc<Mutex<VecDeque<(WorkIO, WorkIO, S)>>>,
+}
+
+impl<S> Default for ServedRuns<S> {
+    fn default() -> Self {
+        ServedRuns {
+            runs: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+}
+
+impl<S> ServedRuns<S> {
+    /// Records a successful run, forgetting the oldest one beyond the latest runs.
+    pub fn record(self: &Self, input: WorkIO, output: WorkIO, served: S) {
+        let mut runs = self.runs.lock().unwrap();
+        runs.push_back((input, output, served));
+        if runs.len() > SERVED_RUNS {
+            runs.pop_front();
+        }
+    }
+
+    /// Removes the latest run from `input` to `output` and returns what served it.
+    pub fn take(self: &Self, input: &WorkIO, output: &WorkIO) -> Result<S, UnknownRun> {
+        let mut runs = self.runs.lock().unwrap();
+        let position = runs
+            .iter()
+            .rposition(|(run_input, run_output, _)| run_input == input && run_output == output);
+
+        match position.and_then(|position| runs.remove(position)) {
+            Some((_, _, served)) => Ok(served),
+            None => Err(UnknownRun {
+                input: input.clone(),
+                output: output.clone(),
+            }),
+        }
+    }
+}
+
+pub trait AsyncWorker<T>
 where
     Self: Sync + Send,
     T: Sync + Send + Clone,
     T: 'static,
 {
     fn run(self: &mut Self, input: T) -> AsyncResult<T>;
+
+    /// Sets the worker up, e.g. opens its connections and files.
+    fn init(self: &mut Self, _config: &serde_json::Value) -> AsyncResult<()> {
+        Box::new(futures::future::ok(()))
+    }
+
+    /// Prepares the worker for its first run once every worker is initialized.
+    fn warmup(self: &mut Self) -> AsyncResult<()> {
+        Box::new(futures::future::ok(()))
+    }
+
+    /// Fails if the worker is currently unable to run.
+    fn health(self: &mut Self) -> AsyncResult<()> {
+        Box::new(futures::future::ok(()))
+    }
+
+    /// Tears the worker down gracefully after its last run.
+    fn shutdown(self: &mut Self) -> AsyncResult<()> {
+        Box::new(futures::future::ok(()))
+    }
+
+    /// Returns the action which undoes the effects of the run from `input` to
+    /// `output`, if the run had any, see `saga::process_with_compensation`.
+    ///
+    /// Workers which wrap other workers forward it to them.
+    fn compensation(self: &mut Self, _input: &T, _output: &T) -> AsyncResult<Option<Compensation>> {
+        Box::new(futures::future::ok(None))
+    }
 }
 
-trait AsyncWorkerInternal<T> {
+/// Worker run in-process; the lifecycle hooks are those of `AsyncWorker`
+pub trait AsyncWorkerInternal<T> {
     fn run_internal(self: &mut Self, input: WorkIO) -> AsyncResult<WorkIO>;
+
+    fn init(self: &mut Self, _config: &serde_json::Value) -> AsyncResult<()> {
+        Box::new(futures::future::ok(()))
+    }
+
+    fn warmup(self: &mut Self) -> AsyncResult<()> {
+        Box::new(futures::future::ok(()))
+    }
+
+    fn health(self: &mut Self) -> AsyncResult<()> {
+        Box::new(futures::future::ok(()))
+    }
+
+    fn shutdown(self: &mut Self) -> AsyncResult<()> {
+        Box::new(futures::future::ok(()))
+    }
+
+    fn compensation(
+        self: &mut Self,
+        _input: &WorkIO,
+        _output: &WorkIO,
+    ) -> AsyncResult<Option<Compensation>> {
+        Box::new(futures::future::ok(None))
+    }
 }
 
-trait AsyncWorkerExternal<T> {
+/// Worker backed by something outside the process; the lifecycle hooks are those of
+/// `AsyncWorker`
+pub trait AsyncWorkerExternal<T> {
     fn run_external(self: &mut Self, input: WorkIO) -> AsyncResult<WorkIO>;
+
+    fn init(self: &mut Self, _config: &serde_json::Value) -> AsyncResult<()> {
+        Box::new(futures::future::ok(()))
+    }
+
+    fn warmup(self: &mut Self) -> AsyncResult<()> {
+        Box::new(futures::future::ok(()))
+    }
+
+    fn health(self: &mut Self) -> AsyncResult<()> {
+        Box::new(futures::future::ok(()))
+    }
+
+    fn shutdown(self: &mut Self) -> AsyncResult<()> {
+        Box::new(futures::future::ok(()))
+    }
+
+    fn compensation(
+        self: &mut Self,
+        _input: &WorkIO,
+        _output: &WorkIO,
+    ) -> AsyncResult<Option<Compensation>> {
+        Box::new(futures::future::ok(None))
+    }
+}
+
+pub type Work = Arc<FuturesMutex<Box<dyn AsyncWorker<WorkIO>>>>;
+pub type WorkCollection = Vec<Work>;
+
+/// Calls `f` with the locked `worker`, for workers which forward to the workers they
+/// wrap.
+///
+/// The lock is recorded by the lock monitor of the stage the call runs in, if any.
+pub fn with_locked<W, F, R>(worker: &Arc<FuturesMutex<W>>, f: F) -> AsyncResult<R>
+where
+    W: Send + 'static,
+    F: FnOnce(&mut W) -> AsyncResult<R> + Send + 'static,
+    R: Send + 'static,
+{
+    Box::new(lock_nested(worker).and_then(move |mut worker| f(&mut *worker)))
 }
 
-type Work = Arc<FuturesMutex<Box<dyn AsyncWorker<WorkIO>>>>;
-type WorkCollection = Vec<Work>;
+/// Calls `f` with each of the locked `workers` concurrently and fails if it fails for
+/// any of them.
+pub fn with_each_locked<'a, W, I, F>(workers: I, f: F) -> AsyncResult<()>
+where
+    W: Send + 'static,
+    I: IntoIterator<Item = &'a Arc<FuturesMutex<W>>>,
+    F: Fn(&mut W) -> AsyncResult<()> + Send + Sync + 'static,
+{
+    let f = Arc::new(f);
+    let calls: Vec<_> = workers
+        .into_iter()
+        .map(|worker| {
+            let f = f.clone();
+            with_locked(worker, move |worker| f(worker))
+        })
+        .collect();
+
+    Box::new(futures::future::join_all(calls).map(|_| ()))
+}
 
-struct InternalWorkWrapper<T>(T);
-struct ExternalWorkWrapper<T>(T);
+pub struct InternalWorkWrapper<T>(pub T);
+pub struct ExternalWorkWrapper<T>(pub T);
 
 impl<T> AsyncWorker<WorkIO> for InternalWorkWrapper<T>
 where
@@ -41,6 +215,30 @@ where
     fn run(self: &mut Self, input: WorkIO) -> AsyncResult<WorkIO> {
         self.0.run_internal(input)
     }
+
+    fn init(self: &mut Self, config: &serde_json::Value) -> AsyncResult<()> {
+        self.0.init(config)
+    }
+
+    fn warmup(self: &mut Self) -> AsyncResult<()> {
+        self.0.warmup()
+    }
+
+    fn health(self: &mut Self) -> AsyncResult<()> {
+        self.0.health()
+    }
+
+    fn shutdown(self: &mut Self) -> AsyncResult<()> {
+        self.0.shutdown()
+    }
+
+    fn compensation(
+        self: &mut Self,
+        input: &WorkIO,
+        output: &WorkIO,
+    ) -> AsyncResult<Option<Compensation>> {
+        self.0.compensation(input, output)
+    }
 }
 
 impl<T> AsyncWorker<WorkIO> for ExternalWorkWrapper<T>
@@ -51,43 +249,81 @@ where
     fn run(self: &mut Self, input: WorkIO) -> AsyncResult<WorkIO> {
         self.0.run_external(input)
     }
+
+    fn init(self: &mut Self, config: &serde_json::Value) -> AsyncResult<()> {
+        self.0.init(config)
+    }
+
+    fn warmup(self: &mut Self) -> AsyncResult<()> {
+        self.0.warmup()
+    }
+
+    fn health(self: &mut Self) -> AsyncResult<()> {
+        self.0.health()
+    }
+
+    fn shutdown(self: &mut Self) -> AsyncResult<()> {
+        self.0.shutdown()
+    }
+
+    fn compensation(
+        self: &mut Self,
+        input: &WorkIO,
+        output: &WorkIO,
+    ) -> AsyncResult<Option<Compensation>> {
+        self.0.compensation(input, output)
+    }
 }
 
-fn process<T>(work_collection: T) -> AsyncResult<WorkIO>
+pub fn process<T>(work_collection: T) -> AsyncResult<WorkIO>
 where
-    T: Iterator<Item = &'static Work>,
-    T: Sync + Send,
-    T: 'static,
+    T: Iterator + Sync + Send + 'static,
+    T::Item: Borrow<Work> + Send,
+{
+    process_controlled(work_collection, ProcessControl::default())
+}
+
+/// Like `process`, but with the timeouts and cancellation of `control` applied.
+pub fn process_controlled<T>(work_collection: T, control: ProcessControl) -> AsyncResult<WorkIO>
+where
+    T: Iterator + Sync + Send + 'static,
+    T::Item: Borrow<Work> + Send,
 {
-    let future_work = futures::stream::iter_ok::<_, Error>(work_collection)
+    let control = control.for_run();
+    let stage_control = control.clone();
+    let future_work = futures::stream::iter_ok::<_, Error>(work_collection.enumerate())
         .fold(
             futures::future::Either::A(Box::new(futures::future::ok("".to_string()))),
-            |future_input, next_item_mutex| {
+            move |future_input, (stage, next_item_mutex)| {
                 println!("[] getting work lock...");
-                next_item_mutex
-                    .lock()
-                    .map_err(|_| failure::err_msg("could not acquire the mutex lock"))
+                let run_control = stage_control.clone();
+                stage_control
+                    .lock(stage, next_item_mutex.borrow())
                     .join(future_input)
-                    .map(|(mut next_item, input)| {
+                    .map(move |(mut next_item, input)| {
                         println!("[] got work lock!");
                         println!("[] input: {}", input);
-                        futures::future::Either::B((*next_item).run(input))
+                        futures::future::Either::B(run_control.stage(
+                            stage,
+                            StagePhase::Run,
+                            (*next_item).run(input),
+                        ))
                     })
             },
         )
         .into_future()
         .flatten();
 
-    Box::new(future_work)
+    control.pipeline(future_work)
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use failure::Fallible;
 
     #[derive(Clone)]
-    struct InternalCountingForwarder(pub usize);
+    pub(crate) struct InternalCountingForwarder(pub usize);
     impl AsyncWorkerInternal<WorkIO> for InternalCountingForwarder {
         fn run_internal(self: &mut Self, input: WorkIO) -> AsyncResult<WorkIO> {
             println!("Processing {}th run. Input {}", self.0, input);
@@ -98,7 +334,7 @@ mod tests {
     }
 
     #[derive(Clone)]
-    struct ExternalCountingForwarder(pub usize);
+    pub(crate) struct ExternalCountingForwarder(pub usize);
     impl AsyncWorkerExternal<WorkIO> for ExternalCountingForwarder {
         fn run_external(self: &mut Self, input: WorkIO) -> AsyncResult<WorkIO> {
             println!("Processing {}th run. Input {}", self.0, input);
@@ -106,6 +342,13 @@ mod tests {
 
             Box::new(futures::future::ok(format!("{}{}", input, self.0 % 10)))
         }
+
+        fn init(self: &mut Self, config: &serde_json::Value) -> AsyncResult<()> {
+            if let Some(start) = config["start"].as_u64() {
+                self.0 = start as usize;
+            }
+            Box::new(futures::future::ok(()))
+        }
     }
 
     #[test]
@@ -137,6 +380,92 @@ mod tests {
             assert_eq!(result.len(), WORK_COLLECTION.len());
         }
 
+        // the wrappers forward the lifecycle hooks
+        let mut worker = ExternalWorkWrapper(ExternalCountingForwarder(0));
+        runtime.block_on(worker.init(&serde_json::json!({ "start": 4 })))?;
+        assert_eq!(runtime.block_on(worker.run("_".to_string()))?, "_5");
+
+        Ok(())
+    }
+
+    /// Hangs on its first run and forwards the input on every later run
+    #[derive(Clone)]
+    struct InternalHangingOnceForwarder(pub bool);
+    impl AsyncWorkerInternal<WorkIO> for InternalHangingOnceForwarder {
+        fn run_internal(self: &mut Self, input: WorkIO) -> AsyncResult<WorkIO> {
+            if self.0 {
+                return Box::new(futures::future::ok(format!("{}h", input)));
+            }
+            self.0 = true;
+
+            Box::new(futures::future::empty())
+        }
+    }
+
+    #[test]
+    fn test_process_stage_timeout_and_cancellation() -> Fallible<()> {
+        use crate::stage_control::{CancellationHandle, StageError};
+        use std::time::{Duration, Instant};
+
+        lazy_static! {
+            static ref WORK_COLLECTION: WorkCollection = vec![
+                Arc::new(FuturesMutex::new(Box::new(InternalWorkWrapper(
+                    InternalCountingForwarder(0)
+                )))),
+                Arc::new(FuturesMutex::new(Box::new(InternalWorkWrapper(
+                    InternalHangingOnceForwarder(false)
+                )))),
+            ];
+        }
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let control = ProcessControl::default().with_stage_timeout(Duration::from_millis(50));
+        let error = runtime
+            .block_on(process_controlled(WORK_COLLECTION.iter(), control.clone()))
+            .expect_err("hanging stage did not time out");
+        match error.downcast::<StageError>()? {
+            StageError::Timeout { stage, phase, .. } => {
+                assert_eq!(stage, 1);
+                assert_eq!(phase, StagePhase::Run);
+            }
+            other => panic!("unexpected error: {}", other),
+        }
+
+        // the timed out run must not stall later callers
+        let result = runtime.block_on(process_controlled(WORK_COLLECTION.iter(), control))?;
+        assert_eq!(result.len(), WORK_COLLECTION.len());
+
+        // a cancelled run gives up the lock it is waiting for
+        let cancellation = CancellationHandle::new();
+        let lock = runtime
+            .block_on(WORK_COLLECTION[0].lock())
+            .map_err(|_| failure::err_msg("could not acquire the mutex lock"))?;
+        let canceller = cancellation.clone();
+        runtime.spawn(
+            tokio::timer::Delay::new(Instant::now() + Duration::from_millis(50)).then(move |_| {
+                canceller.cancel();
+                Ok(())
+            }),
+        );
+        let error = runtime
+            .block_on(process_controlled(
+                WORK_COLLECTION.iter(),
+                ProcessControl::default().with_cancellation(cancellation),
+            ))
+            .expect_err("run was not cancelled");
+        match error.downcast::<StageError>()? {
+            StageError::Cancelled { stage, phase } => {
+                assert_eq!(stage, 0);
+                assert_eq!(phase, StagePhase::Lock);
+            }
+            other => panic!("unexpected error: {}", other),
+        }
+        drop(lock);
+
+        let result = runtime.block_on(process(WORK_COLLECTION.iter()))?;
+        assert_eq!(result.len(), WORK_COLLECTION.len());
+
         Ok(())
     }
 }
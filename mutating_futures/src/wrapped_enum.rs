@@ -1,17 +1,25 @@
 #![allow(dead_code)]
 
+use crate::introspection::{short_type_name, PipelineDescription, StageDescription, StageKind};
+use crate::lock_monitor::locking;
+use crate::stage_control::{ProcessControl, StagePhase};
 use core::fmt::Display;
 use core::fmt::Formatter;
 use failure::Error;
 use futures::IntoFuture;
 use futures::{Future, Stream};
 use futures_locks::Mutex as FuturesMutex;
+use std::borrow::Borrow;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 type AsyncWorkIO<T> = Box<dyn Future<Item = T, Error = Error> + Send>;
 
+#[derive(Clone)]
 struct InternalWorkIO(pub String);
+#[derive(Clone)]
 struct ExternalWorkIO(pub String);
+#[derive(Clone)]
 enum WorkIO {
     InternalWorkIO(InternalWorkIO),
     ExternalWorkIO(ExternalWorkIO),
@@ -48,6 +56,17 @@ where
     T: Sync + Send,
 {
     fn run(self: &mut Self, input: T) -> AsyncWorkIO<T>;
+
+    /// Describes the stage for `describe`; by default as an internal stage which takes
+    /// and produces either variant of WorkIO.
+    fn describe(self: &Self) -> StageDescription {
+        StageDescription {
+            name: short_type_name::<Self>(),
+            kind: StageKind::Internal,
+            input: "WorkIO".to_string(),
+            output: "WorkIO".to_string(),
+        }
+    }
 }
 
 trait AsyncWorkerInternal {
@@ -79,6 +98,15 @@ where
                 .map(WorkIO::InternalWorkIO),
         )
     }
+
+    fn describe(self: &Self) -> StageDescription {
+        StageDescription {
+            name: short_type_name::<T>(),
+            kind: StageKind::Internal,
+            input: "InternalWorkIO".to_string(),
+            output: "InternalWorkIO".to_string(),
+        }
+    }
 }
 
 impl<T> AsyncWorker<WorkIO> for ExternalWorkWrapper<T>
@@ -96,27 +124,54 @@ where
                 .map(WorkIO::ExternalWorkIO),
         )
     }
+
+    fn describe(self: &Self) -> StageDescription {
+        StageDescription {
+            name: short_type_name::<T>(),
+            kind: StageKind::External,
+            input: "ExternalWorkIO".to_string(),
+            output: "ExternalWorkIO".to_string(),
+        }
+    }
 }
 
 fn process<T>(work_collection: T, initial_io: WorkIO) -> AsyncWorkIO<String>
 where
-    T: Iterator<Item = &'static Work>,
-    T: Sync + Send,
-    T: 'static,
+    T: Iterator + Sync + Send + 'static,
+    T::Item: Borrow<Work> + Send,
 {
-    let future_work = futures::stream::iter_ok::<_, Error>(work_collection)
+    process_controlled(work_collection, initial_io, ProcessControl::default())
+}
+
+/// Like `process`, but with the timeouts and cancellation of `control` applied.
+fn process_controlled<T>(
+    work_collection: T,
+    initial_io: WorkIO,
+    control: ProcessControl,
+) -> AsyncWorkIO<String>
+where
+    T: Iterator + Sync + Send + 'static,
+    T::Item: Borrow<Work> + Send,
+{
+    let control = control.for_run();
+    let stage_control = control.clone();
+    let future_work = futures::stream::iter_ok::<_, Error>(work_collection.enumerate())
         .fold(
             futures::future::Either::A(futures::future::ok(initial_io)),
-            |future_input, next_item_mutex| {
+            move |future_input, (stage, next_item_mutex)| {
                 println!("[] getting work lock...");
-                next_item_mutex
-                    .lock()
-                    .map_err(|_| failure::err_msg("could not acquire the mutex lock"))
+                let run_control = stage_control.clone();
+                stage_control
+                    .lock(stage, next_item_mutex.borrow())
                     .join(future_input)
-                    .map(|(mut next_item, input)| {
+                    .map(move |(mut next_item, input)| {
                         println!("[] got work lock!");
                         println!("[] input: {}", input);
-                        futures::future::Either::B((*next_item).run(input))
+                        futures::future::Either::B(run_control.stage(
+                            stage,
+                            StagePhase::Run,
+                            (*next_item).run(input),
+                        ))
                     })
             },
         )
@@ -124,12 +179,244 @@ where
         .flatten()
         .map(|io| io.get_string());
 
-    Box::new(future_work)
+    control.pipeline(future_work)
+}
+
+/// Describes the stages of `work_collection` in order, together with the conversions
+/// between the variants of WorkIO which the wrappers insert.
+fn describe<T>(work_collection: T) -> AsyncWorkIO<PipelineDescription>
+where
+    T: Iterator,
+    T::Item: Borrow<Work>,
+{
+    let stages: Vec<_> = work_collection
+        .enumerate()
+        .map(|(stage, work)| {
+            locking(work.borrow())
+                .map_err(|_| failure::err_msg("could not acquire the mutex lock"))
+                .map(move |worker| {
+                    let mut description = worker.describe();
+                    description.name = format!("stage {}: {}", stage, description.name);
+                    description
+                })
+        })
+        .collect();
+
+    Box::new(futures::future::join_all(stages).map(|stages| {
+        let mut description = PipelineDescription::chain(stages);
+        // routing stages take either variant as it is
+        let routing: Vec<String> = description
+            .stages
+            .iter()
+            .filter(|stage| stage.kind == StageKind::Routing)
+            .map(|stage| stage.name.clone())
+            .collect();
+        for edge in &mut description.edges {
+            if routing.contains(&edge.to) {
+                edge.conversion = None;
+            }
+        }
+        description
+    }))
+}
+
+/// Picks the route for an item, as an index into the routes of a RoutingWorker
+type Router = Box<dyn Fn(&WorkIO) -> usize + Send + Sync>;
+
+/// AsyncWorker which sends each item through one of several sub-pipelines.
+///
+/// The output of the chosen sub-pipeline becomes the output of the routing stage, so
+/// the item continues along the main pipeline afterwards. An empty route passes items
+/// through unchanged.
+struct RoutingWorker {
+    router: Router,
+    routes: Vec<WorkCollection>,
+}
+
+impl RoutingWorker {
+    fn new<F>(router: F, routes: Vec<WorkCollection>) -> Self
+    where
+        F: Fn(&WorkIO) -> usize + Send + Sync + 'static,
+    {
+        RoutingWorker {
+            router: Box::new(router),
+            routes,
+        }
+    }
+
+    /// Routes internal items to `internal` and external items to `external`.
+    fn by_variant(internal: WorkCollection, external: WorkCollection) -> Self {
+        Self::new(
+            |io| match io {
+                WorkIO::InternalWorkIO(_) => 0,
+                WorkIO::ExternalWorkIO(_) => 1,
+            },
+            vec![internal, external],
+        )
+    }
+
+    /// Routes items for which `predicate` holds to `matching`, all others to `other`.
+    fn by_predicate<F>(predicate: F, matching: WorkCollection, other: WorkCollection) -> Self
+    where
+        F: Fn(&WorkIO) -> bool + Send + Sync + 'static,
+    {
+        Self::new(
+            move |io| if predicate(io) { 0 } else { 1 },
+            vec![matching, other],
+        )
+    }
+}
+
+impl AsyncWorker<WorkIO> for RoutingWorker {
+    fn run(self: &mut Self, input: WorkIO) -> AsyncWorkIO<WorkIO> {
+        let route = (self.router)(&input);
+        let stages = match self.routes.get(route) {
+            Some(stages) => stages.clone(),
+            None => {
+                return Box::new(futures::future::err(format_err!(
+                    "no route with index {} among {} routes",
+                    route,
+                    self.routes.len()
+                )))
+            }
+        };
+
+        println!("[] routing input {} to route {}", input, route);
+        Box::new(
+            futures::stream::iter_ok::<_, Error>(stages).fold(input, |input, stage| {
+                locking(&stage)
+                    .map_err(|_| failure::err_msg("could not acquire the mutex lock"))
+                    .and_then(move |mut stage| (*stage).run(input))
+            }),
+        )
+    }
+
+    fn describe(self: &Self) -> StageDescription {
+        StageDescription {
+            name: format!("RoutingWorker ({} routes)", self.routes.len()),
+            kind: StageKind::Routing,
+            input: "WorkIO".to_string(),
+            output: "WorkIO".to_string(),
+        }
+    }
+}
+
+/// What `process_with_report` does with the output of a failed stage
+#[derive(Clone)]
+enum ErrorMode {
+    /// Pass the input of the failed stage on to the next stage
+    Skip,
+    /// Pass the given value on to the next stage
+    Fallback(WorkIO),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StageStatus {
+    Succeeded,
+    Skipped,
+    Fallback,
+}
+
+#[derive(Debug)]
+struct StageReport {
+    stage: usize,
+    status: StageStatus,
+    /// Time spent on the stage, including the wait for its lock
+    duration: Duration,
+    input_len: usize,
+    output_len: usize,
+    error: Option<Error>,
+}
+
+#[derive(Debug)]
+struct ExecutionReport {
+    output: String,
+    stages: Vec<StageReport>,
+}
+
+impl ExecutionReport {
+    fn failed_stages(self: &Self) -> impl Iterator<Item = &StageReport> {
+        self.stages.iter().filter(|report| report.error.is_some())
+    }
+}
+
+/// Like `process_controlled`, but keeps going when a stage fails and reports on every
+/// stage.
+///
+/// The output of a failed stage is replaced according to `error_mode`. A stage which
+/// times out is reported as failed like any other, while the pipeline timeout and the
+/// cancellation end the run.
+fn process_with_report<T>(
+    work_collection: T,
+    initial_io: WorkIO,
+    error_mode: ErrorMode,
+    control: ProcessControl,
+) -> AsyncWorkIO<ExecutionReport>
+where
+    T: Iterator + Sync + Send + 'static,
+    T::Item: Borrow<Work> + Send,
+{
+    let control = control.for_run();
+    let stage_control = control.clone();
+    let future_work = futures::stream::iter_ok::<_, Error>(work_collection.enumerate())
+        .fold(
+            (initial_io, Vec::new()),
+            move |(input, mut reports), (stage, next_item_mutex)| {
+                let error_mode = error_mode.clone();
+                let input_len = input.get_string().len();
+                let started = Instant::now();
+                let run_control = stage_control.clone();
+
+                println!("[] getting work lock...");
+                stage_control
+                    .lock(stage, next_item_mutex.borrow())
+                    .and_then({
+                        let input = input.clone();
+                        move |mut next_item| {
+                            println!("[] got work lock!");
+                            println!("[] input: {}", input);
+                            run_control.stage(stage, StagePhase::Run, (*next_item).run(input))
+                        }
+                    })
+                    .then(move |result| {
+                        let (output, status, error) = match result {
+                            Ok(output) => (output, StageStatus::Succeeded, None),
+                            Err(e) => {
+                                println!("[] stage {} failed: {}", stage, e);
+                                match error_mode {
+                                    ErrorMode::Skip => (input, StageStatus::Skipped, Some(e)),
+                                    ErrorMode::Fallback(fallback) => {
+                                        (fallback, StageStatus::Fallback, Some(e))
+                                    }
+                                }
+                            }
+                        };
+
+                        reports.push(StageReport {
+                            stage,
+                            status,
+                            duration: started.elapsed(),
+                            input_len,
+                            output_len: output.get_string().len(),
+                            error,
+                        });
+
+                        Ok::<_, Error>((output, reports))
+                    })
+            },
+        )
+        .map(|(output, stages)| ExecutionReport {
+            output: output.get_string(),
+            stages,
+        });
+
+    control.pipeline(future_work)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::stage_control::StageError;
     use failure::Fallible;
 
     struct InternalCountingForwarder(pub usize);
@@ -195,4 +482,251 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_process_owned_collection() -> Fallible<()> {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        // collections don't need to be 'static
+        let work_collection: WorkCollection = vec![
+            Arc::new(FuturesMutex::new(Box::new(InternalWorkWrapper(
+                InternalCountingForwarder(0),
+            )))),
+            Arc::new(FuturesMutex::new(Box::new(ExternalWorkWrapper(
+                ExternalCountingForwarder(0),
+            )))),
+        ];
+        let result = runtime.block_on(process(
+            work_collection.into_iter(),
+            WorkIO::InternalWorkIO(InternalWorkIO("".to_string())),
+        ))?;
+        assert_eq!(result, "11");
+
+        Ok(())
+    }
+
+    struct ExternalFailingWorker;
+    impl AsyncWorkerExternal for ExternalFailingWorker {
+        fn run_external(self: &mut Self, _: ExternalWorkIO) -> AsyncWorkIO<ExternalWorkIO> {
+            Box::new(futures::future::err(failure::err_msg(
+                "external worker failed",
+            )))
+        }
+    }
+
+    struct InternalAppender(&'static str);
+    impl AsyncWorkerInternal for InternalAppender {
+        fn run_internal(self: &mut Self, input: InternalWorkIO) -> AsyncWorkIO<InternalWorkIO> {
+            Box::new(futures::future::ok(InternalWorkIO(format!(
+                "{}{}",
+                input, self.0
+            ))))
+        }
+    }
+
+    struct ExternalAppender(&'static str);
+    impl AsyncWorkerExternal for ExternalAppender {
+        fn run_external(self: &mut Self, input: ExternalWorkIO) -> AsyncWorkIO<ExternalWorkIO> {
+            Box::new(futures::future::ok(ExternalWorkIO(format!(
+                "{}{}",
+                input, self.0
+            ))))
+        }
+    }
+
+    fn internal_appender(suffix: &'static str) -> Work {
+        Arc::new(FuturesMutex::new(Box::new(InternalWorkWrapper(
+            InternalAppender(suffix),
+        ))))
+    }
+
+    fn external_appender(suffix: &'static str) -> Work {
+        Arc::new(FuturesMutex::new(Box::new(ExternalWorkWrapper(
+            ExternalAppender(suffix),
+        ))))
+    }
+
+    #[test]
+    fn test_process_with_routing() -> Fallible<()> {
+        lazy_static! {
+            static ref WORK_COLLECTION: WorkCollection = vec![
+                Arc::new(FuturesMutex::new(Box::new(RoutingWorker::by_variant(
+                    vec![internal_appender("i")],
+                    vec![external_appender("e1"), external_appender("e2")],
+                )))),
+                Arc::new(FuturesMutex::new(Box::new(RoutingWorker::by_predicate(
+                    |io| io.get_string().starts_with('!'),
+                    vec![internal_appender("!")],
+                    vec![],
+                )))),
+                internal_appender("_"),
+            ];
+        }
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut run = |io| runtime.block_on(process(WORK_COLLECTION.iter(), io));
+
+        assert_eq!(
+            run(WorkIO::InternalWorkIO(InternalWorkIO("".to_string())))?,
+            "i_"
+        );
+        assert_eq!(
+            run(WorkIO::ExternalWorkIO(ExternalWorkIO("".to_string())))?,
+            "e1e2_"
+        );
+        assert_eq!(
+            run(WorkIO::ExternalWorkIO(ExternalWorkIO("!".to_string())))?,
+            "!e1e2!_"
+        );
+
+        Ok(())
+    }
+
+    /// Passes items on unchanged and doesn't describe itself
+    struct Passthrough;
+    impl AsyncWorker<WorkIO> for Passthrough {
+        fn run(self: &mut Self, input: WorkIO) -> AsyncWorkIO<WorkIO> {
+            Box::new(futures::future::ok(input))
+        }
+    }
+
+    #[test]
+    fn test_describe() -> Fallible<()> {
+        lazy_static! {
+            static ref WORK_COLLECTION: WorkCollection = vec![
+                internal_appender("a"),
+                internal_appender("b"),
+                external_appender("c"),
+                Arc::new(FuturesMutex::new(Box::new(RoutingWorker::by_variant(
+                    vec![],
+                    vec![],
+                )))),
+                internal_appender("d"),
+                Arc::new(FuturesMutex::new(Box::new(Passthrough))),
+            ];
+        }
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let description = runtime.block_on(describe(WORK_COLLECTION.iter()))?;
+
+        assert_eq!(description.stages[0].name, "stage 0: InternalAppender");
+        assert_eq!(description.stages[3].kind, StageKind::Routing);
+        let conversions: Vec<_> = description
+            .edges
+            .iter()
+            .map(|edge| edge.conversion.as_deref())
+            .collect();
+        assert_eq!(
+            conversions,
+            vec![
+                None,
+                Some("InternalWorkIO -> ExternalWorkIO"),
+                None,
+                Some("WorkIO -> InternalWorkIO"),
+                Some("InternalWorkIO -> WorkIO"),
+            ]
+        );
+        assert_eq!(description.stages[5].name, "stage 5: Passthrough");
+        assert!(description.to_dot().contains(
+            "\"stage 1: InternalAppender\" -> \"stage 2: ExternalAppender\" \
+             [label=\"InternalWorkIO -> ExternalWorkIO\"];"
+        ));
+        assert!(description
+            .to_mermaid()
+            .contains("s3{\"stage 3: RoutingWorker (2 routes)<br/>routing: WorkIO\"}"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_with_report() -> Fallible<()> {
+        lazy_static! {
+            static ref WORK_COLLECTION: WorkCollection = vec![
+                Arc::new(FuturesMutex::new(Box::new(InternalWorkWrapper(
+                    InternalCountingForwarder(0)
+                )))),
+                Arc::new(FuturesMutex::new(Box::new(ExternalWorkWrapper(
+                    ExternalFailingWorker
+                )))),
+                Arc::new(FuturesMutex::new(Box::new(ExternalWorkWrapper(
+                    ExternalCountingForwarder(0)
+                )))),
+            ];
+        }
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let report = runtime.block_on(process_with_report(
+            WORK_COLLECTION.iter(),
+            WorkIO::InternalWorkIO(InternalWorkIO("".to_string())),
+            ErrorMode::Skip,
+            ProcessControl::default(),
+        ))?;
+        assert_eq!(report.output, "11");
+        let statuses: Vec<_> = report.stages.iter().map(|stage| stage.status).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                StageStatus::Succeeded,
+                StageStatus::Skipped,
+                StageStatus::Succeeded
+            ]
+        );
+        let lengths: Vec<_> = report
+            .stages
+            .iter()
+            .map(|stage| (stage.input_len, stage.output_len))
+            .collect();
+        assert_eq!(lengths, vec![(0, 1), (1, 1), (1, 2)]);
+        assert_eq!(
+            report
+                .failed_stages()
+                .map(|stage| stage.stage)
+                .collect::<Vec<_>>(),
+            vec![1]
+        );
+
+        let report = runtime.block_on(process_with_report(
+            WORK_COLLECTION.iter(),
+            WorkIO::InternalWorkIO(InternalWorkIO("".to_string())),
+            ErrorMode::Fallback(WorkIO::ExternalWorkIO(ExternalWorkIO(
+                "fallback".to_string(),
+            ))),
+            ProcessControl::default(),
+        ))?;
+        assert_eq!(report.output, "fallback2");
+        assert_eq!(report.stages[1].status, StageStatus::Fallback);
+        assert_eq!(
+            report.stages[1].error.as_ref().map(|e| e.to_string()),
+            Some("external worker failed".to_string())
+        );
+
+        // a stage which times out is skipped, too
+        let held = runtime
+            .block_on(locking(&WORK_COLLECTION[2]))
+            .expect("could not lock the last stage");
+        let report = runtime.block_on(process_with_report(
+            WORK_COLLECTION.iter(),
+            WorkIO::InternalWorkIO(InternalWorkIO("".to_string())),
+            ErrorMode::Skip,
+            ProcessControl::default().with_stage_timeout(Duration::from_millis(50)),
+        ))?;
+        drop(held);
+        assert_eq!(report.output, "3");
+        assert_eq!(report.stages[2].status, StageStatus::Skipped);
+        match report.stages[2]
+            .error
+            .as_ref()
+            .and_then(|e| e.downcast_ref::<StageError>())
+        {
+            Some(StageError::Timeout {
+                stage: 2,
+                phase: StagePhase::Lock,
+                ..
+            }) => (),
+            other => panic!("expected a lock timeout, got {:?}", other),
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,32 @@
+use failure::format_err;
+use mutating_futures::export_plugins;
+use mutating_futures::plugin_loader::PluginRegistrar;
+use mutating_futures::plugins::{FutureIO, InternalPlugin, PluginConfig};
+
+/// Appends the suffix from its config, and fails on "fail"
+#[derive(Debug, Default)]
+struct Suffix(String);
+
+impl InternalPlugin for Suffix {
+    fn run_internal(self: &mut Self, input: String) -> FutureIO<'static, String> {
+        if input == "fail" {
+            return Box::new(futures::future::err(format_err!(
+                "suffix plugin failed on {}",
+                input
+            )));
+        }
+
+        Box::new(futures::future::ok(format!("{}{}", input, self.0)))
+    }
+
+    fn init(self: &mut Self, config: &PluginConfig) -> FutureIO<'static, ()> {
+        self.0 = config["suffix"].as_str().unwrap_or_default().to_string();
+        Box::new(futures::future::ok(()))
+    }
+}
+
+fn register(registrar: &mut PluginRegistrar) {
+    registrar.register("suffix", Box::new(Suffix::default()));
+}
+
+export_plugins!(register);